@@ -0,0 +1,480 @@
+use std::io::{self, BufRead, Write};
+
+use lib_dmg_01::cpu::instruction::{
+    disassemble_bus_with_log, disassemble_bus_with_symbols, DisassembledInstruction, Instruction,
+};
+use lib_dmg_01::cpu::CPU;
+use lib_dmg_01::debugger::{parse_number, Breakpoint, Register, Stop, WatchKind, Watchpoint};
+use lib_dmg_01::rewind::Rewind;
+use lib_dmg_01::Bus;
+
+const HELP: &str = "\
+step, s [N]                  run N instructions (1 by default)
+reverse-step, rs [N]         go back N instructions (1 by default)
+next, n                      run the next instruction, stepping over calls
+finish                       run until the current function returns
+continue, c                  run until a breakpoint or watchpoint (Ctrl-C quits)
+break, b [ADDRESS] [if COND] stop at ADDRESS (the PC by default), e.g. b $150 if a == 3
+watch [read|write|change|access] ADDRESS[-END]
+                             stop when memory is accessed (change by default)
+delete N, unwatch N          remove breakpoint or watchpoint N
+info                         list breakpoints and watchpoints
+registers, r                 show the CPU registers
+x[/N[xb]] ADDRESS            dump N bytes of memory (16 by default), e.g. x/16xb $C000
+disassemble, d [ADDRESS]     disassemble around ADDRESS (the PC by default)
+io                           show the I/O registers
+backtrace, bt                show the calls, RSTs and interrupts the CPU is inside of
+quit, q                      exit
+
+Addresses and numbers are decimal or hex ($C000 or 0xC000), a register (hl) or a
+label from the symbol file (main_loop).
+An empty line repeats the last command.";
+
+const IO_REGISTERS: [(u16, &str); 23] = [
+    (0xFF00, "P1"),
+    (0xFF01, "SB"),
+    (0xFF02, "SC"),
+    (0xFF04, "DIV"),
+    (0xFF05, "TIMA"),
+    (0xFF06, "TMA"),
+    (0xFF07, "TAC"),
+    (0xFF0F, "IF"),
+    (0xFF40, "LCDC"),
+    (0xFF41, "STAT"),
+    (0xFF42, "SCY"),
+    (0xFF43, "SCX"),
+    (0xFF44, "LY"),
+    (0xFF45, "LYC"),
+    (0xFF46, "DMA"),
+    (0xFF47, "BGP"),
+    (0xFF48, "OBP0"),
+    (0xFF49, "OBP1"),
+    (0xFF4A, "WY"),
+    (0xFF4B, "WX"),
+    (0xFF4D, "KEY1"),
+    (0xFF50, "BOOT"),
+    (0xFFFF, "IE"),
+];
+
+// A snapshot every frame for the last 10 seconds
+const REWIND_INTERVAL: usize = 1;
+const REWIND_SNAPSHOTS: usize = 600;
+
+enum Action {
+    Continue,
+    Quit,
+}
+
+/// Runs an interactive debugger reading commands from stdin until `quit` or the end of
+/// input. See `HELP` for the commands.
+pub fn run(cpu: &mut CPU) {
+    println!("Type help for a list of commands");
+    print_location(cpu);
+    let stdin = io::stdin();
+    let mut last_command = String::new();
+    let mut rewind = Rewind::new(REWIND_INTERVAL, REWIND_SNAPSHOTS);
+    loop {
+        print!("(dmg) ");
+        io::stdout().flush().expect("Could not write to stdout");
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = line.trim();
+        let command = if line.is_empty() {
+            last_command.clone()
+        } else {
+            line.to_string()
+        };
+        if command.is_empty() {
+            continue;
+        }
+        match execute(cpu, &mut rewind, &command) {
+            Ok(Action::Continue) => {}
+            Ok(Action::Quit) => break,
+            Err(e) => println!("{}", e),
+        }
+        last_command = command;
+    }
+}
+
+fn execute(cpu: &mut CPU, rewind: &mut Rewind, command: &str) -> Result<Action, String> {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let rest: Vec<&str> = words.collect();
+    let argument = rest.join(" ");
+    match name {
+        "help" | "h" => println!("{}", HELP),
+        "step" | "s" => {
+            let count = instruction_count(&argument)?;
+            let mut steps = 0;
+            let stop = run_until(cpu, rewind, |_| {
+                steps += 1;
+                steps > count
+            });
+            report(cpu, stop);
+        }
+        "reverse-step" | "rs" => {
+            for _ in 0..instruction_count(&argument)? {
+                if !rewind.step_back(cpu) {
+                    println!("Can't go back any further");
+                    break;
+                }
+            }
+            print_location(cpu);
+        }
+        "next" | "n" => {
+            let stop = step_over(cpu, rewind);
+            report(cpu, stop);
+        }
+        "finish" | "fin" => {
+            let stop = step_out(cpu, rewind);
+            report(cpu, stop);
+        }
+        "continue" | "c" => {
+            let stop = run_until(cpu, rewind, |_| false);
+            report(cpu, stop);
+        }
+        "break" | "b" => {
+            let (location, condition) = match rest.iter().position(|word| *word == "if") {
+                Some(index) => (rest[..index].join(" "), Some(rest[index + 1..].join(" "))),
+                None => (argument.clone(), None),
+            };
+            let address = if location.is_empty() {
+                cpu.pc
+            } else {
+                address(cpu, &location)?
+            };
+            let mut breakpoint = Breakpoint::new(address);
+            if let Some(condition) = condition {
+                breakpoint = breakpoint.when(condition.parse()?);
+            }
+            let id = cpu.breakpoints.add(breakpoint);
+            println!("Breakpoint {} at {}", id, describe(cpu, address));
+        }
+        "watch" => {
+            let (kind, range) = match rest.as_slice() {
+                [range] => (WatchKind::Change, *range),
+                ["read", range] => (WatchKind::Read, *range),
+                ["write", range] => (WatchKind::Write, *range),
+                ["change", range] => (WatchKind::Change, *range),
+                ["access", range] => (WatchKind::Access, *range),
+                _ => {
+                    return Err("Usage: watch [read|write|change|access] ADDRESS[-END]".to_string())
+                }
+            };
+            let mut bounds = range.splitn(2, '-');
+            let start = address(cpu, bounds.next().unwrap_or(""))?;
+            let end = match bounds.next() {
+                Some(end) => address(cpu, end)?,
+                None => start,
+            };
+            let addresses = start..=end;
+            let id = cpu.bus.watchpoints.add(Watchpoint { addresses, kind });
+            println!(
+                "Watchpoint {} on {}-{}",
+                id,
+                describe(cpu, start),
+                describe(cpu, end)
+            );
+        }
+        "delete" | "unwatch" => {
+            let id = argument
+                .parse()
+                .map_err(|_| format!("'{}' is not a breakpoint number", argument))?;
+            let removed = if name == "delete" {
+                cpu.breakpoints.remove(id)
+            } else {
+                cpu.bus.watchpoints.remove(id)
+            };
+            if !removed {
+                return Err(format!("There is nothing numbered {}", id));
+            }
+        }
+        "info" => {
+            for (id, breakpoint) in cpu.breakpoints.iter() {
+                match breakpoint.condition {
+                    Some(ref condition) => println!(
+                        "Breakpoint {} at {} if {}",
+                        id,
+                        describe(cpu, breakpoint.address),
+                        condition
+                    ),
+                    None => println!("Breakpoint {} at {}", id, describe(cpu, breakpoint.address)),
+                }
+            }
+            for (id, watchpoint) in cpu.bus.watchpoints.iter() {
+                println!(
+                    "Watchpoint {} on {}-{} ({:?})",
+                    id,
+                    describe(cpu, *watchpoint.addresses.start()),
+                    describe(cpu, *watchpoint.addresses.end()),
+                    watchpoint.kind
+                );
+            }
+        }
+        "registers" | "r" => print_registers(cpu),
+        "disassemble" | "d" => {
+            let around = if argument.is_empty() {
+                cpu.pc
+            } else {
+                address(cpu, &argument)?
+            };
+            print_disassembly(cpu, around);
+        }
+        "io" => {
+            for (address, name) in IO_REGISTERS.iter() {
+                match cpu.bus.try_peek_byte(*address) {
+                    Some(value) => println!("{:04X} {:<5} ${:02X}", address, name, value),
+                    None => println!("{:04X} {:<5} --", address, name),
+                }
+            }
+        }
+        "backtrace" | "bt" => print_backtrace(cpu),
+        "quit" | "q" => return Ok(Action::Quit),
+        _ if name == "x" || name.starts_with("x/") => {
+            let count = dump_count(&name[1..])?;
+            if argument.is_empty() {
+                return Err("Usage: x[/N[xb]] ADDRESS".to_string());
+            }
+            print_memory(cpu, address(cpu, &argument)?, count);
+        }
+        _ => return Err(format!("Unknown command '{}'. Try help", name)),
+    }
+    Ok(Action::Continue)
+}
+
+/// An address written as a number, the name of a register holding it or a label
+fn address(cpu: &CPU, s: &str) -> Result<u16, String> {
+    if let Ok(register) = s.parse::<Register>() {
+        return Ok(register.value(cpu));
+    }
+    match cpu.symbols.address(s) {
+        Some((_, address)) => Ok(address),
+        None => parse_number(s),
+    }
+}
+
+/// `address` in hex followed by the nearest label, if any, e.g. `$0153 <main+$3>`
+fn describe(cpu: &CPU, address: u16) -> String {
+    match cpu.symbols.nearest(cpu.bus.bank(address), address) {
+        Some(_) => format!(
+            "${:04X} <{}>",
+            address,
+            cpu.symbols.describe_on(&cpu.bus, address)
+        ),
+        None => format!("${:04X}", address),
+    }
+}
+
+// Data in the code/data log, if there is one, is shown as data
+fn disassemble(cpu: &CPU, start: u16, end: u16) -> Vec<DisassembledInstruction> {
+    match cpu.bus.code_data_log {
+        Some(ref log) => disassemble_bus_with_log(&cpu.bus, start, end, &cpu.symbols, log),
+        None => disassemble_bus_with_symbols(&cpu.bus, start, end, &cpu.symbols),
+    }
+}
+
+fn print_instruction(marker: &str, instruction: &DisassembledInstruction) {
+    if let Some(ref label) = instruction.label {
+        println!("{}:", label);
+    }
+    println!("{} {}", marker, instruction);
+}
+
+// Only hex bytes can be dumped so the format and unit letters are optional
+fn dump_count(format: &str) -> Result<u16, String> {
+    let format = format.trim_start_matches('/');
+    let digits = format.trim_end_matches(['x', 'b']);
+    if format.len() - digits.len() > 2 || digits.contains(|c: char| !c.is_ascii_digit()) {
+        return Err(format!(
+            "Only hex bytes can be dumped (x/16xb) not /{}",
+            format
+        ));
+    }
+    if digits.is_empty() {
+        Ok(16)
+    } else {
+        digits
+            .parse()
+            .map_err(|_| format!("'{}' is too many bytes", digits))
+    }
+}
+
+fn instruction_count(argument: &str) -> Result<usize, String> {
+    if argument.is_empty() {
+        return Ok(1);
+    }
+    argument
+        .parse()
+        .map_err(|_| format!("'{}' is not a number of instructions", argument))
+}
+
+// Runs like `CPU::run_until` while recording the frames that run for `reverse-step`
+fn run_until<F>(cpu: &mut CPU, rewind: &mut Rewind, mut done: F) -> Option<Stop>
+where
+    F: FnMut(&CPU) -> bool,
+{
+    cpu.run_until(|cpu| {
+        rewind.record(cpu);
+        done(cpu)
+    })
+}
+
+fn step_over(cpu: &mut CPU, rewind: &mut Rewind) -> Option<Stop> {
+    match Instruction::decode(cpu.bus.peek_byte(cpu.pc), false) {
+        Some(instruction @ Instruction::CALL(_)) | Some(instruction @ Instruction::RST(_)) => {
+            let return_address = cpu.pc.wrapping_add(instruction.length());
+            let sp = cpu.sp;
+            run_until(cpu, rewind, |cpu| cpu.pc == return_address && cpu.sp == sp)
+        }
+        _ => {
+            let mut stepped = false;
+            run_until(cpu, rewind, |_| std::mem::replace(&mut stepped, true))
+        }
+    }
+}
+
+// The current function has returned once a return instruction leaves the stack above
+// where it is now
+fn step_out(cpu: &mut CPU, rewind: &mut Rewind) -> Option<Stop> {
+    let sp = cpu.sp;
+    let mut returning = false;
+    run_until(cpu, rewind, |cpu| {
+        if returning && cpu.sp > sp {
+            return true;
+        }
+        returning = matches!(
+            Instruction::decode(cpu.bus.peek_byte(cpu.pc), false),
+            Some(Instruction::RET(_)) | Some(Instruction::RETI)
+        );
+        false
+    })
+}
+
+fn report(cpu: &CPU, stop: Option<Stop>) {
+    match stop {
+        Some(Stop::Breakpoint(id)) => println!("Breakpoint {}", id),
+        Some(Stop::Watchpoint(hit)) => match hit.kind {
+            WatchKind::Read => println!(
+                "Watchpoint {}: read ${:02X} from {}",
+                hit.id,
+                hit.new,
+                describe(cpu, hit.address)
+            ),
+            _ => println!(
+                "Watchpoint {}: wrote ${:02X} to {} (was ${:02X})",
+                hit.id,
+                hit.new,
+                describe(cpu, hit.address),
+                hit.old
+            ),
+        },
+        Some(Stop::SoftwareBreakpoint) => println!("Software breakpoint (ld b, b)"),
+        None => {}
+    }
+    print_location(cpu);
+}
+
+fn print_location(cpu: &CPU) {
+    if let Some(instruction) = disassemble(cpu, cpu.pc, cpu.pc.wrapping_add(1)).first() {
+        print_instruction("=>", instruction);
+    }
+}
+
+fn print_registers(cpu: &CPU) {
+    let r = &cpu.registers;
+    let flags = [
+        (r.f.zero, 'Z'),
+        (r.f.subtract, 'N'),
+        (r.f.half_carry, 'H'),
+        (r.f.carry, 'C'),
+    ];
+    let flags: String = flags
+        .iter()
+        .map(|&(set, name)| if set { name } else { '-' })
+        .collect();
+    println!(
+        "af ${:04X}  bc ${:04X}  de ${:04X}  hl ${:04X}  sp ${:04X}  pc ${:04X}",
+        r.get_af(),
+        r.get_bc(),
+        r.get_de(),
+        r.get_hl(),
+        cpu.sp,
+        cpu.pc
+    );
+    println!(
+        "flags {}  ime {}  halted {}  cycles {}",
+        flags,
+        cpu.interrupts_enabled() as u8,
+        cpu.is_halted() as u8,
+        cpu.bus.cycles()
+    );
+}
+
+fn print_memory(cpu: &CPU, start: u16, count: u16) {
+    for row in (0..count).step_by(16) {
+        let row_start = start.wrapping_add(row);
+        let bytes: Vec<String> = (row..count.min(row.saturating_add(16)))
+            .map(
+                |offset| match cpu.bus.try_peek_byte(start.wrapping_add(offset)) {
+                    Some(byte) => format!("{:02X}", byte),
+                    None => "??".to_string(),
+                },
+            )
+            .collect();
+        println!("{:04X}: {}", row_start, bytes.join(" "));
+    }
+}
+
+// Instructions are different lengths so there's no telling where the ones before
+// `around` start. The furthest start that lines up with `around` is the best guess.
+fn print_disassembly(cpu: &CPU, around: u16) {
+    let before = (1..=12)
+        .rev()
+        .filter_map(|back| {
+            let start = around.checked_sub(back)?;
+            let instructions = disassemble(cpu, start, around);
+            let last = instructions.last()?;
+            if last.address.wrapping_add(last.length()) == around {
+                Some(instructions)
+            } else {
+                None
+            }
+        })
+        .next()
+        .unwrap_or_default();
+    let after = disassemble(cpu, around, around.saturating_add(24));
+    let skipped = before.len().saturating_sub(4);
+    for instruction in before.iter().skip(skipped).chain(after.iter().take(6)) {
+        let marker = if instruction.address == cpu.pc {
+            "=>"
+        } else {
+            "  "
+        };
+        print_instruction(marker, instruction);
+    }
+}
+
+fn print_backtrace(cpu: &CPU) {
+    let backtrace = cpu.backtrace();
+    println!("#0  {}", describe(cpu, backtrace.pc));
+    for (index, frame) in backtrace.frames.iter().enumerate() {
+        println!(
+            "#{:<2} {}  {} {}",
+            index + 1,
+            describe(cpu, frame.call_site),
+            frame.kind,
+            describe(cpu, frame.target)
+        );
+    }
+    let anomalies: Vec<_> = cpu.stack_anomalies().collect();
+    if let Some(anomaly) = anomalies.last() {
+        println!(
+            "The stack has been moved around outside of calls and returns {} time(s), most recently {}",
+            anomalies.len(),
+            anomaly
+        );
+    }
+}
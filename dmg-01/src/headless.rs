@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io::Write;
+
+use lib_dmg_01::cpu::CPU;
+
+use super::ONE_FRAME_IN_CYCLES;
+
+pub const EXIT_PASSED: i32 = 0;
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_TIMED_OUT: i32 = 2;
+pub const EXIT_ERROR: i32 = 3;
+
+const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+
+/// How a headless run should be driven and when it should stop.
+pub struct Options {
+    /// The maximum number of frames to run for
+    pub frames: usize,
+    /// Stop successfully as soon as the serial output contains this text
+    pub until_serial: Option<String>,
+    /// Stop with a failure as soon as the serial output contains this text
+    pub fail_serial: Option<String>,
+    pub input: InputScript,
+    /// Where to write the final frame as a PPM image
+    pub screenshot: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Button {
+    A,
+    B,
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// Scripted joypad input.
+///
+/// Each non-empty line of a script has the form `FRAME [BUTTON...]` and sets which
+/// buttons are held from that frame on. A line with no buttons releases everything.
+/// Buttons are `a`, `b`, `start`, `select`, `up`, `down`, `left` and `right`, and
+/// anything after a `#` is a comment. For example:
+///
+/// ```text
+/// 60 start   # press start after one second
+/// 65         # and let go of it again
+/// 120 a right
+/// ```
+pub struct InputScript {
+    events: Vec<(usize, Vec<Button>)>,
+}
+
+impl InputScript {
+    pub fn empty() -> InputScript {
+        InputScript { events: Vec::new() }
+    }
+
+    pub fn parse(script: &str) -> Result<InputScript, String> {
+        let mut events = Vec::new();
+        for (line_index, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let frame = words
+                .next()
+                .and_then(|frame| frame.parse().ok())
+                .ok_or_else(|| format!("line {}: expected a frame number", line_index + 1))?;
+            let mut buttons = Vec::new();
+            for word in words {
+                let button = match word.to_lowercase().as_str() {
+                    "a" => Button::A,
+                    "b" => Button::B,
+                    "start" => Button::Start,
+                    "select" => Button::Select,
+                    "up" => Button::Up,
+                    "down" => Button::Down,
+                    "left" => Button::Left,
+                    "right" => Button::Right,
+                    _ => {
+                        return Err(format!(
+                            "line {}: unknown button '{}'",
+                            line_index + 1,
+                            word
+                        ))
+                    }
+                };
+                buttons.push(button);
+            }
+            events.push((frame, buttons));
+        }
+        // Later lines win when two lines name the same frame
+        events.sort_by_key(|&(frame, _)| frame);
+        Ok(InputScript { events })
+    }
+}
+
+/// Runs `cpu` without a window, prints a summary to stdout and returns the exit code
+/// the process should end with.
+pub fn run(cpu: &mut CPU, options: &Options) -> i32 {
+    let mut next_event = 0;
+    let mut frames = 0;
+    let mut cycles_elapsed_in_frame = 0usize;
+    let mut outcome = if options.until_serial.is_some() {
+        Outcome::TimedOut
+    } else {
+        Outcome::Passed
+    };
+
+    while frames < options.frames {
+        while next_event < options.input.events.len()
+            && options.input.events[next_event].0 <= frames
+        {
+            hold_buttons(cpu, &options.input.events[next_event].1);
+            next_event += 1;
+        }
+
+        while cycles_elapsed_in_frame < ONE_FRAME_IN_CYCLES {
+            cycles_elapsed_in_frame += cpu.step() as usize;
+        }
+        cycles_elapsed_in_frame -= ONE_FRAME_IN_CYCLES;
+        frames += 1;
+
+        let serial_output = String::from_utf8_lossy(&cpu.bus.serial_output);
+        if let Some(ref text) = options.fail_serial {
+            if serial_output.contains(text.as_str()) {
+                outcome = Outcome::Failed;
+                break;
+            }
+        }
+        if let Some(ref text) = options.until_serial {
+            if serial_output.contains(text.as_str()) {
+                outcome = Outcome::Passed;
+                break;
+            }
+        }
+    }
+
+    let canvas_buffer = &cpu.bus.gpu.canvas_buffer;
+    println!(
+        "result: {}",
+        match outcome {
+            Outcome::Passed => "passed",
+            Outcome::Failed => "failed",
+            Outcome::TimedOut => "timed out",
+        }
+    );
+    println!("frames: {}", frames);
+    println!("framebuffer hash: {:016x}", hash(canvas_buffer));
+    println!("serial output:");
+    println!("{}", String::from_utf8_lossy(&cpu.bus.serial_output));
+
+    if let Some(ref path) = options.screenshot {
+        if let Err(e) = write_ppm(path, canvas_buffer) {
+            eprintln!("Could not write screenshot to {}: {}", path, e);
+            return EXIT_ERROR;
+        }
+    }
+
+    match outcome {
+        Outcome::Passed => EXIT_PASSED,
+        Outcome::Failed => EXIT_FAILED,
+        Outcome::TimedOut => EXIT_TIMED_OUT,
+    }
+}
+
+fn hold_buttons(cpu: &mut CPU, buttons: &[Button]) {
+    let joypad = &mut cpu.bus.joypad;
+    joypad.a = buttons.contains(&Button::A);
+    joypad.b = buttons.contains(&Button::B);
+    joypad.start = buttons.contains(&Button::Start);
+    joypad.select = buttons.contains(&Button::Select);
+    joypad.up = buttons.contains(&Button::Up);
+    joypad.down = buttons.contains(&Button::Down);
+    joypad.left = buttons.contains(&Button::Left);
+    joypad.right = buttons.contains(&Button::Right);
+}
+
+// 64 bit FNV-1a. Unlike the std hashers this is guaranteed to stay the same across
+// Rust versions so hashes can be checked into CI configuration.
+fn hash(buffer: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in buffer {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn write_ppm(path: &str, canvas_buffer: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    write!(file, "P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    let pixels: Vec<u8> = canvas_buffer
+        .chunks(4)
+        .flat_map(|pixel| pixel[..3].iter().cloned())
+        .collect();
+    file.write_all(&pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_frames_and_buttons() {
+        let script = InputScript::parse("120 a Right\n60 start\n65\n").unwrap();
+        assert_eq!(
+            script.events,
+            vec![
+                (60, vec![Button::Start]),
+                (65, vec![]),
+                (120, vec![Button::A, Button::Right]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_blank_and_comment_lines() {
+        let script = InputScript::parse("# intro\n\n   \n60 start # skip the title\n").unwrap();
+        assert_eq!(script.events, vec![(60, vec![Button::Start])]);
+    }
+
+    #[test]
+    fn later_lines_win() {
+        let script = InputScript::parse("10 a\n5 up\n10 b\n").unwrap();
+        // Events are applied in order so the last one for a frame is what's held
+        assert_eq!(
+            script.events,
+            vec![
+                (5, vec![Button::Up]),
+                (10, vec![Button::A]),
+                (10, vec![Button::B]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            InputScript::parse("60 a\nstart\n").err(),
+            Some("line 2: expected a frame number".to_string())
+        );
+        assert_eq!(
+            InputScript::parse("-1 a\n").err(),
+            Some("line 1: expected a frame number".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_buttons() {
+        assert_eq!(
+            InputScript::parse("60 a\n\n90 turbo\n").err(),
+            Some("line 3: unknown button 'turbo'".to_string())
+        );
+    }
+}
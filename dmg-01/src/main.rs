@@ -2,14 +2,20 @@ extern crate clap;
 extern crate lib_dmg_01;
 extern crate minifb;
 
+mod debugger;
+mod headless;
+
 use clap::{App, Arg};
 use minifb::{Key, Window, WindowOptions};
 
-use std::io::Read;
+use std::fs::File;
+use std::io::{BufWriter, Read};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
-use lib_dmg_01::cpu::CPU;
+use lib_dmg_01::cpu::{Profiler, Tracer, CPU};
+use lib_dmg_01::rewind::Rewind;
+use lib_dmg_01::{CodeDataLog, FrameBlender, Model, Symbols};
 
 const ENLARGEMENT_FACTOR: usize = 1;
 const WINDOW_DIMENSIONS: [usize; 2] = [(160 * ENLARGEMENT_FACTOR), (144 * ENLARGEMENT_FACTOR)];
@@ -24,17 +30,223 @@ fn main() {
                 .required(true)
                 .value_name("FILE"),
         )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .value_name("MODEL")
+                .possible_values(&["dmg0", "dmg", "mgb"])
+                .default_value("dmg")
+                .help("The hardware to emulate when starting without a boot ROM"),
+        )
+        .arg(
+            Arg::with_name("frame blend")
+                .long("blend")
+                .value_name("RATIO")
+                .help("Mixes each frame with the previous one to imitate LCD ghosting (0.0 - 1.0)"),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help("Runs without opening a window and exits with a status code (e.g. for CI)"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .value_name("N")
+                // Not `requires("headless")`: clap counts the default as given, which
+                // would make `--headless` required for every run
+                .default_value("600")
+                .help("The number of frames to run for in headless mode"),
+        )
+        .arg(
+            Arg::with_name("until serial")
+                .long("until-serial")
+                .value_name("TEXT")
+                .requires("headless")
+                .help("Stops successfully once the serial output contains TEXT"),
+        )
+        .arg(
+            Arg::with_name("fail serial")
+                .long("fail-serial")
+                .value_name("TEXT")
+                .requires("headless")
+                .help("Stops with a failure once the serial output contains TEXT"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .value_name("FILE")
+                .requires("headless")
+                .help("A script of joypad input to feed in headless mode"),
+        )
+        .arg(
+            Arg::with_name("screenshot")
+                .long("screenshot")
+                .value_name("FILE")
+                .requires("headless")
+                .help("Writes the final frame as a PPM image in headless mode"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Runs without a window under a command line debugger"),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .value_name("PORT")
+                .help("Runs without a window until a GDB client connected to this port detaches"),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Loads labels from an RGBDS .sym file for the debugger and trace"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("Writes a trace of every instruction executed in Gameboy Doctor's format"),
+        )
+        .arg(
+            Arg::with_name("trace range")
+                .long("trace-range")
+                .value_name("START-END")
+                .requires("trace")
+                .help("Only traces instructions between two hex addresses (e.g. 0100-7FFF)"),
+        )
+        .arg(
+            Arg::with_name("trace start")
+                .long("trace-start")
+                .value_name("CYCLE")
+                .requires("trace")
+                .help("Only traces instructions from this clock cycle on"),
+        )
+        .arg(
+            Arg::with_name("trace stop")
+                .long("trace-stop")
+                .value_name("CYCLE")
+                .requires("trace")
+                .help("Stops tracing at this clock cycle"),
+        )
+        .arg(
+            Arg::with_name("trace labels")
+                .long("trace-labels")
+                .requires("trace")
+                .help("Writes a line for each label from --symbols into the trace"),
+        )
+        .arg(
+            Arg::with_name("cdl")
+                .long("cdl")
+                .value_name("FILE")
+                .help("Logs how each byte of ROM is used to a CDL file, adding to it if it exists"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("FILE")
+                .help("Writes a report of where the clock cycles were spent on exit"),
+        )
+        .arg(
+            Arg::with_name("profile folded")
+                .long("profile-folded")
+                .value_name("FILE")
+                .help("Writes the cycles spent in each stack of calls for flamegraph.pl on exit"),
+        )
         .get_matches();
 
-    let boot_buffer = matches
+    let boot_buffer = match matches
         .value_of("boot rom")
-        .map(|path| buffer_from_file(path));
-    let game_buffer = matches
-        .value_of("rom")
-        .map(|path| buffer_from_file(path))
-        .unwrap();
+        .map(buffer_from_file)
+        .transpose()
+    {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(headless::EXIT_ERROR);
+        }
+    };
+    let game_buffer = match buffer_from_file(matches.value_of("rom").unwrap()) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(headless::EXIT_ERROR);
+        }
+    };
+    let frame_blender = matches
+        .value_of("frame blend")
+        .map(|ratio| match ratio.parse() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => FrameBlender::new(ratio),
+            _ => {
+                eprintln!("The frame blend ratio must be a number from 0.0 to 1.0");
+                std::process::exit(headless::EXIT_ERROR);
+            }
+        });
+
+    let model: Model = matches.value_of("model").unwrap().parse().unwrap();
+    let rom_length = game_buffer.len();
+
+    let mut cpu = CPU::with_model(boot_buffer, game_buffer, model);
+    if let Some(path) = matches.value_of("symbols") {
+        match symbols_from_file(path) {
+            Ok(symbols) => cpu.symbols = symbols,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(headless::EXIT_ERROR);
+            }
+        }
+    }
+    match tracer_from_args(&matches) {
+        Ok(Some(tracer)) => cpu.set_tracer(tracer),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(headless::EXIT_ERROR);
+        }
+    }
+    if let Some(path) = matches.value_of("cdl") {
+        match code_data_log_from_file(path, rom_length) {
+            Ok(log) => cpu.bus.code_data_log = Some(log),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(headless::EXIT_ERROR);
+            }
+        }
+    }
+    if matches.is_present("profile") || matches.is_present("profile folded") {
+        cpu.set_profiler(Profiler::new());
+    }
+    if matches.is_present("debug") {
+        debugger::run(&mut cpu);
+        finish_trace(&mut cpu);
+        finish_profile(&mut cpu, &matches);
+        finish_code_data_log(&mut cpu, &matches);
+        return;
+    }
+    if let Some(port) = matches.value_of("gdb") {
+        let status = run_gdb(&mut cpu, port);
+        let finished = finish_trace(&mut cpu)
+            & finish_profile(&mut cpu, &matches)
+            & finish_code_data_log(&mut cpu, &matches);
+        std::process::exit(if finished {
+            status
+        } else {
+            headless::EXIT_ERROR
+        });
+    }
+    if matches.is_present("headless") {
+        let status = run_headless(&mut cpu, &matches);
+        let finished = finish_trace(&mut cpu)
+            & finish_profile(&mut cpu, &matches)
+            & finish_code_data_log(&mut cpu, &matches);
+        std::process::exit(if finished {
+            status
+        } else {
+            headless::EXIT_ERROR
+        });
+    }
 
-    let cpu = CPU::new(boot_buffer, game_buffer);
     let window = Window::new(
         "DMG-01",
         WINDOW_DIMENSIONS[0],
@@ -43,19 +255,68 @@ fn main() {
     )
     .unwrap();
 
-    run(cpu, window)
+    run(&mut cpu, window, frame_blender);
+    finish_trace(&mut cpu);
+    finish_profile(&mut cpu, &matches);
+    finish_code_data_log(&mut cpu, &matches);
+}
+
+#[cfg(feature = "gdb")]
+fn run_gdb(cpu: &mut CPU, port: &str) -> i32 {
+    let port: u16 = match port.parse() {
+        Ok(port) => port,
+        Err(_) => {
+            eprintln!("Invalid GDB port: {}", port);
+            return headless::EXIT_ERROR;
+        }
+    };
+    println!("Waiting for GDB to connect on port {}", port);
+    match lib_dmg_01::gdb::listen(cpu, ("127.0.0.1", port)) {
+        Ok(()) => headless::EXIT_PASSED,
+        Err(e) => {
+            eprintln!("GDB connection failed: {}", e);
+            headless::EXIT_ERROR
+        }
+    }
+}
+
+#[cfg(not(feature = "gdb"))]
+fn run_gdb(_cpu: &mut CPU, _port: &str) -> i32 {
+    eprintln!("dmg-01 was built without the gdb feature");
+    headless::EXIT_ERROR
 }
 
 const ONE_SECOND_IN_MICROS: usize = 1000000000;
 const ONE_SECOND_IN_CYCLES: usize = 4190000;
 const ONE_FRAME_IN_CYCLES: usize = 70224;
 const NUMBER_OF_PIXELS: usize = 23040;
+// A snapshot every 10 frames for 10 seconds
+const REWIND_INTERVAL: usize = 10;
+const REWIND_SNAPSHOTS: usize = 60;
+const REWIND_KEY: Key = Key::Backspace;
+// The number of addresses and functions listed in the profile report
+const PROFILE_REPORT_LIMIT: usize = 50;
 
-fn run(mut cpu: CPU, mut window: Window) {
+fn run(cpu: &mut CPU, mut window: Window, mut frame_blender: Option<FrameBlender>) {
     let mut buffer = [0; NUMBER_OF_PIXELS];
     let mut cycles_elapsed_in_frame = 0usize;
+    let mut rewind = Rewind::new(REWIND_INTERVAL, REWIND_SNAPSHOTS);
+    rewind.record_frame(cpu);
     let mut now = Instant::now();
     while window.is_open() && !window.is_key_down(Key::Escape) {
+        // Holding the rewind key plays the recorded frames backwards
+        if window.is_key_down(REWIND_KEY) {
+            if rewind.rewind_frames(cpu, 1) {
+                draw(cpu, &mut window, &mut buffer, &mut frame_blender);
+            } else {
+                window.update();
+            }
+            sleep(Duration::from_micros(16_742));
+            cycles_elapsed_in_frame = 0;
+            now = Instant::now();
+            continue;
+        }
+
         let time_delta = now.elapsed().subsec_nanos();
         now = Instant::now();
         let delta = time_delta as f64 / ONE_SECOND_IN_MICROS as f64;
@@ -69,13 +330,8 @@ fn run(mut cpu: CPU, mut window: Window) {
 
         // TODO: Consider updating buffer after every line is rendered.
         if cycles_elapsed_in_frame >= ONE_FRAME_IN_CYCLES {
-            for (i, pixel) in cpu.bus.gpu.canvas_buffer.chunks(4).enumerate() {
-                buffer[i] = (pixel[3] as u32) << 24
-                    | (pixel[2] as u32) << 16
-                    | (pixel[1] as u32) << 8
-                    | (pixel[0] as u32)
-            }
-            window.update_with_buffer(&buffer).unwrap();
+            draw(cpu, &mut window, &mut buffer, &mut frame_blender);
+            rewind.record_frame(cpu);
             cycles_elapsed_in_frame = 0;
         } else {
             sleep(Duration::from_nanos(2))
@@ -83,9 +339,182 @@ fn run(mut cpu: CPU, mut window: Window) {
     }
 }
 
-fn buffer_from_file(path: &str) -> Vec<u8> {
-    let mut file = std::fs::File::open(path).expect("File not there");
+fn draw(
+    cpu: &CPU,
+    window: &mut Window,
+    buffer: &mut [u32; NUMBER_OF_PIXELS],
+    frame_blender: &mut Option<FrameBlender>,
+) {
+    let canvas_buffer = match *frame_blender {
+        Some(ref mut frame_blender) => frame_blender.blend(&cpu.bus.gpu.canvas_buffer),
+        None => &cpu.bus.gpu.canvas_buffer,
+    };
+    for (i, pixel) in canvas_buffer.chunks(4).enumerate() {
+        buffer[i] = (pixel[3] as u32) << 24
+            | (pixel[2] as u32) << 16
+            | (pixel[1] as u32) << 8
+            | (pixel[0] as u32)
+    }
+    window.update_with_buffer(&buffer[..]).unwrap();
+}
+
+fn run_headless(cpu: &mut CPU, matches: &clap::ArgMatches) -> i32 {
+    let frames = match matches.value_of("frames").unwrap().parse() {
+        Ok(frames) => frames,
+        Err(_) => {
+            eprintln!("The number of frames must be a positive number");
+            return headless::EXIT_ERROR;
+        }
+    };
+    let input = match matches.value_of("input") {
+        Some(path) => {
+            let script = match buffer_from_file(path) {
+                Ok(buffer) => String::from_utf8_lossy(&buffer).into_owned(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return headless::EXIT_ERROR;
+                }
+            };
+            match headless::InputScript::parse(&script) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Could not parse input script {}: {}", path, e);
+                    return headless::EXIT_ERROR;
+                }
+            }
+        }
+        None => headless::InputScript::empty(),
+    };
+    let options = headless::Options {
+        frames,
+        until_serial: matches.value_of("until serial").map(String::from),
+        fail_serial: matches.value_of("fail serial").map(String::from),
+        input,
+        screenshot: matches.value_of("screenshot").map(String::from),
+    };
+
+    headless::run(cpu, &options)
+}
+
+fn tracer_from_args(matches: &clap::ArgMatches) -> Result<Option<Tracer>, String> {
+    let path = match matches.value_of("trace") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let file = File::create(path).map_err(|e| format!("Could not create trace {}: {}", path, e))?;
+    let mut tracer = Tracer::new(BufWriter::new(file));
+    if matches.is_present("trace labels") {
+        tracer = tracer.labels();
+    }
+    if let Some(range) = matches.value_of("trace range") {
+        let addresses: Vec<_> = range
+            .split('-')
+            .map(|address| u16::from_str_radix(address.trim_start_matches("0x"), 16))
+            .collect();
+        match addresses.as_slice() {
+            [Ok(start), Ok(end)] => tracer = tracer.addresses(*start..=*end),
+            _ => {
+                return Err(format!(
+                    "The trace range must look like 0100-7FFF not {}",
+                    range
+                ))
+            }
+        }
+    }
+    if let Some(cycle) = matches.value_of("trace start") {
+        let cycle = cycle
+            .parse()
+            .map_err(|_| "The trace start must be a number of cycles".to_string())?;
+        tracer = tracer.start_at_cycle(cycle);
+    }
+    if let Some(cycle) = matches.value_of("trace stop") {
+        let cycle = cycle
+            .parse()
+            .map_err(|_| "The trace stop must be a number of cycles".to_string())?;
+        tracer = tracer.stop_at_cycle(cycle);
+    }
+    Ok(Some(tracer))
+}
+
+fn symbols_from_file(path: &str) -> Result<Symbols, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read symbols {}: {}", path, e))?;
+    Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+// An existing log carries on being added to
+fn code_data_log_from_file(path: &str, rom_length: usize) -> Result<CodeDataLog, String> {
+    match std::fs::read(path) {
+        Ok(bytes) if bytes.len() == rom_length => Ok(CodeDataLog::from_bytes(bytes)),
+        Ok(_) => Err(format!("{} is not a CDL file for this ROM", path)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CodeDataLog::new(rom_length)),
+        Err(e) => Err(format!("Could not read CDL file {}: {}", path, e)),
+    }
+}
+
+/// Writes the code/data log if there is one, returning false if it could not be written
+fn finish_code_data_log(cpu: &mut CPU, matches: &clap::ArgMatches) -> bool {
+    let (log, path) = match (cpu.bus.code_data_log.take(), matches.value_of("cdl")) {
+        (Some(log), Some(path)) => (log, path),
+        _ => return true,
+    };
+    match std::fs::write(path, log.to_bytes()) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Could not write the CDL file {}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Flushes the trace if there is one, returning false if it could not be written
+fn finish_trace(cpu: &mut CPU) -> bool {
+    match cpu.take_tracer().map(Tracer::finish) {
+        Some(Err(e)) => {
+            eprintln!("Could not write the trace: {}", e);
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Writes the profile report and folded stacks asked for, returning false if they could
+/// not be written
+fn finish_profile(cpu: &mut CPU, matches: &clap::ArgMatches) -> bool {
+    let profiler = match cpu.take_profiler() {
+        Some(profiler) => profiler,
+        None => return true,
+    };
+    let reported = match matches.value_of("profile") {
+        Some(path) => write_profile(path, |file| {
+            profiler.write_report(cpu, PROFILE_REPORT_LIMIT, file)
+        }),
+        None => true,
+    };
+    let folded = match matches.value_of("profile folded") {
+        Some(path) => write_profile(path, |file| profiler.write_folded(cpu, file)),
+        None => true,
+    };
+    reported && folded
+}
+
+fn write_profile<F>(path: &str, write: F) -> bool
+where
+    F: FnOnce(BufWriter<File>) -> std::io::Result<()>,
+{
+    match File::create(path).and_then(|file| write(BufWriter::new(file))) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Could not write the profile {}: {}", path, e);
+            false
+        }
+    }
+}
+
+fn buffer_from_file(path: &str) -> Result<Vec<u8>, String> {
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).expect("Could not read file");
-    buffer
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buffer))
+        .map_err(|e| format!("Could not read {}: {}", path, e))?;
+    Ok(buffer)
 }
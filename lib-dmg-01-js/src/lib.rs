@@ -8,10 +8,10 @@ extern crate wasm_bindgen;
 use wasm_bindgen::prelude::*;
 
 use lib_dmg_01::cpu::instruction;
+use lib_dmg_01::FrameBlender;
 
 #[wasm_bindgen]
-#[derive(Serialize)]
-pub struct CPU(lib_dmg_01::CPU);
+pub struct CPU(lib_dmg_01::CPU, Option<FrameBlender>);
 
 #[wasm_bindgen]
 impl CPU {
@@ -19,7 +19,7 @@ impl CPU {
     pub fn new(boot_rom: Option<Vec<u8>>, game_rom: Vec<u8>) -> CPU {
         console_error_panic_hook::set_once();
         let cpu = lib_dmg_01::CPU::new(boot_rom, game_rom);
-        CPU(cpu)
+        CPU(cpu, None)
     }
 
     pub fn set_register(&mut self, register: Register, value: u16) {
@@ -47,18 +47,42 @@ impl CPU {
         self.0.bus.joypad = joypad.0;
     }
 
-    pub fn canvas_buffer(&self, buffer: &mut [u8]) {
-        buffer.copy_from_slice(&self.0.bus.gpu.canvas_buffer);
+    /// Sets how much of the previous frame bleeds into each new one. A ratio of 0
+    /// turns frame blending off.
+    pub fn set_frame_blend(&mut self, ratio: f32) {
+        self.1 = if ratio > 0.0 {
+            Some(FrameBlender::new(ratio))
+        } else {
+            None
+        };
+    }
+
+    /// Copies the current frame into `buffer`. When frame blending is on this should
+    /// be called once per displayed frame.
+    pub fn canvas_buffer(&mut self, buffer: &mut [u8]) {
+        match self.1 {
+            Some(ref mut frame_blender) => {
+                buffer.copy_from_slice(frame_blender.blend(&self.0.bus.gpu.canvas_buffer))
+            }
+            None => buffer.copy_from_slice(&self.0.bus.gpu.canvas_buffer),
+        }
     }
 
     pub fn to_json(&self) -> JsValue {
-        JsValue::from_serde(&self).unwrap()
+        JsValue::from_serde(&self.0).unwrap()
     }
 
     pub fn memory_slice(&self, start: u16, end: u16) -> Vec<u8> {
         self.0.bus.slice(start, end)
     }
 
+    /// Disassembles the instructions that start between `start` and `end` into a list
+    /// of `{ address, label, bytes, text }` objects. `label` is always `null` as no
+    /// symbols are loaded here.
+    pub fn disassemble(&self, start: u16, end: u16) -> JsValue {
+        JsValue::from_serde(&instruction::disassemble_bus(&self.0.bus, start, end)).unwrap()
+    }
+
     pub fn get_tile_set_buffer(&self, outline_tiles: bool) -> Vec<u8> {
         self.0.bus.gpu.tile_set_as_buffer(outline_tiles)
     }
@@ -119,7 +143,7 @@ pub enum Register {
 
 #[wasm_bindgen]
 pub fn add(cpu: CPU, target: Target) -> CPU {
-    let mut cpu = cpu.0;
+    let CPU(mut cpu, frame_blender) = cpu;
     let target = match target {
         Target::A => Some(instruction::ArithmeticTarget::A),
         Target::B => Some(instruction::ArithmeticTarget::B),
@@ -136,7 +160,7 @@ pub fn add(cpu: CPU, target: Target) -> CPU {
         cpu.execute(instruction::Instruction::ADD(target));
     }
 
-    CPU(cpu)
+    CPU(cpu, frame_blender)
 }
 
 #[wasm_bindgen]
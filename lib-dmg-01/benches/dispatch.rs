@@ -0,0 +1,56 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lib_dmg_01::cpu::instruction::Instruction;
+use lib_dmg_01::{assemble, CPU};
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("from_byte", |b| {
+        b.iter(|| {
+            for byte in 0..=255u8 {
+                black_box(Instruction::from_byte(black_box(byte), false));
+                black_box(Instruction::from_byte(black_box(byte), true));
+            }
+        })
+    });
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            for byte in 0..=255u8 {
+                black_box(Instruction::decode(black_box(byte), false));
+                black_box(Instruction::decode(black_box(byte), true));
+            }
+        })
+    });
+    group.finish();
+}
+
+// A loop that mixes loads, arithmetic, prefixed instructions and jumps
+const PROGRAM: &str = "
+org $0100
+main:
+    ld hl, $C000
+    ld b, 0
+.loop:
+    ld a, [hl]
+    add a, b
+    ld [hl+], a
+    swap a
+    bit 0, a
+    dec b
+    jr nz, .loop
+    jp main
+";
+
+fn step(c: &mut Criterion) {
+    let rom = assemble(PROGRAM).expect("the benchmark program assembles");
+    let mut cpu = CPU::new(None, rom);
+    c.bench_function("step 10000 instructions", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                black_box(cpu.step());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, decode, step);
+criterion_main!(benches);
@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cpu::instruction::syntax::{syntax, Operand};
+use crate::cpu::instruction::Instruction;
+
+/// The size of the ROM images produced by `assemble`: two 16 KiB banks
+const ROM_SIZE: usize = 0x8000;
+
+const REGISTERS_AND_CONDITIONS: [&str; 15] = [
+    "a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "nz", "z", "nc",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct AssemblerError {
+    /// The line the error was found on, starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// Assembles `source` into a 32 KiB ROM image that can be handed straight to `CPU::new`.
+///
+/// The syntax is a small subset of RGBDS. Each line holds an optional `label:`, then
+/// an instruction or directive, then an optional `; comment`. Supported directives are
+/// `org ADDRESS` (code starts at 0x0000 otherwise), `db` for bytes and strings and
+/// `dw` for little endian words. Numbers are written as `42`, `$2A`, `0x2A` or
+/// `%101010` and operands may add and subtract numbers and labels:
+///
+/// ```
+/// let rom = lib_dmg_01::assemble(
+///     "    org $100
+///      loop:
+///          inc a
+///          jr nz, loop
+///          ldh [$FF00 + $01], a
+///          db \"done\", 0",
+/// )
+/// .unwrap();
+/// assert_eq!(&rom[0x100..0x103], &[0x3C, 0x20, 0xFD]);
+/// ```
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let templates = templates();
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address: u32 = 0;
+
+    // The first pass works out where everything goes. The size of every statement is
+    // known without knowing the value of any label.
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| AssemblerError {
+            line: line_number,
+            message,
+        };
+
+        let mut line = strip_comment(line).trim();
+        while let Some(label_end) = label_end(line) {
+            let label = &line[..label_end];
+            if labels.insert(label.to_string(), address as i32).is_some() {
+                return Err(error(format!("label '{}' is defined twice", label)));
+            }
+            line = line[label_end..].trim_start_matches(':').trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        let mnemonic = mnemonic.to_lowercase();
+        let operands = split_operands(operands);
+
+        let kind = match mnemonic.as_str() {
+            "org" => {
+                let origin = operands
+                    .first()
+                    .and_then(|operand| parse_expression(operand))
+                    .and_then(|expression| expression.evaluate(&HashMap::new()).ok())
+                    .filter(|origin| *origin >= 0 && *origin as usize <= ROM_SIZE)
+                    .ok_or_else(|| error("org needs an address inside the ROM".to_string()))?;
+                address = origin as u32;
+                continue;
+            }
+            "db" => {
+                let mut values = Vec::new();
+                for operand in operands {
+                    if operand.starts_with('"') {
+                        let string = parse_string(&operand)
+                            .ok_or_else(|| error(format!("invalid string {}", operand)))?;
+                        values.extend(string.bytes().map(Expression::number));
+                    } else {
+                        values.push(
+                            parse_expression(&operand).ok_or_else(|| {
+                                error(format!("invalid expression '{}'", operand))
+                            })?,
+                        );
+                    }
+                }
+                Statement::Bytes(values)
+            }
+            "dw" => {
+                let mut values = Vec::new();
+                for operand in operands {
+                    values.push(
+                        parse_expression(&operand)
+                            .ok_or_else(|| error(format!("invalid expression '{}'", operand)))?,
+                    );
+                }
+                Statement::Words(values)
+            }
+            _ => match_instruction(&templates, &mnemonic, &operands)
+                .ok_or_else(|| error(format!("'{}' is not a valid instruction", line)))?,
+        };
+        let size = kind.size();
+        statements.push((line_number, address as u16, kind));
+        address += size as u32;
+        if address as usize > ROM_SIZE {
+            return Err(error("the program does not fit in 32 KiB".to_string()));
+        }
+    }
+
+    let mut rom = vec![0; ROM_SIZE];
+    for (line_number, address, statement) in statements {
+        let bytes = statement
+            .encode(address, &labels)
+            .map_err(|message| AssemblerError {
+                line: line_number,
+                message,
+            })?;
+        let start = address as usize;
+        rom[start..start + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(rom)
+}
+
+enum Statement {
+    Instruction(Instruction, Option<(Operand, Expression)>),
+    Bytes(Vec<Expression>),
+    Words(Vec<Expression>),
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction(instruction, _) => instruction.length() as usize,
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => values.len() * 2,
+        }
+    }
+
+    fn encode(&self, address: u16, labels: &HashMap<String, i32>) -> Result<Vec<u8>, String> {
+        match self {
+            Statement::Instruction(instruction, None) => Ok(instruction.encode(0)),
+            Statement::Instruction(instruction, Some((operand, expression))) => {
+                let value = expression.evaluate(labels)?;
+                let operand = encode_operand(*operand, value, address)?;
+                Ok(instruction.encode(operand))
+            }
+            Statement::Bytes(values) => {
+                let mut bytes = Vec::with_capacity(values.len());
+                for value in values {
+                    bytes.push(
+                        encode_operand(Operand::Byte, value.evaluate(labels)?, address)? as u8,
+                    );
+                }
+                Ok(bytes)
+            }
+            Statement::Words(values) => {
+                let mut bytes = Vec::with_capacity(values.len() * 2);
+                for value in values {
+                    let word = encode_operand(Operand::Word, value.evaluate(labels)?, address)?;
+                    bytes.push(word as u8);
+                    bytes.push((word >> 8) as u8);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+fn encode_operand(operand: Operand, value: i32, address: u16) -> Result<u16, String> {
+    let in_range = |min: i32, max: i32, description: &str| {
+        if value < min || value > max {
+            Err(format!("{} does not fit in {}", value, description))
+        } else {
+            Ok(value as u16)
+        }
+    };
+    match operand {
+        Operand::Byte => in_range(-128, 0xFF, "a byte").map(|value| value & 0xFF),
+        Operand::Word | Operand::WordIndirect => in_range(-0x8000, 0xFFFF, "a word"),
+        Operand::HighIndirect => {
+            if (0xFF00..=0xFFFF).contains(&value) || (0..=0xFF).contains(&value) {
+                Ok(value as u16 & 0xFF)
+            } else {
+                Err(format!("${:04X} is not between $FF00 and $FFFF", value))
+            }
+        }
+        Operand::Relative => {
+            let offset = value - (address as i32 + 2);
+            if (-128..=127).contains(&offset) {
+                Ok(offset as u16 & 0xFF)
+            } else {
+                Err(format!(
+                    "${:04X} is too far away for a relative jump",
+                    value
+                ))
+            }
+        }
+        Operand::SignedByte | Operand::StackOffset => {
+            in_range(-128, 127, "a signed byte").map(|value| value & 0xFF)
+        }
+        Operand::Fixed(_) => Ok(0),
+    }
+}
+
+/// Every instruction with its mnemonic and operands
+fn templates() -> Vec<(Instruction, &'static str, Vec<Operand>)> {
+    let unprefixed = (0..=255u8)
+        .filter(|byte| *byte != 0xCB)
+        .map(|byte| Instruction::from_byte(byte, false));
+    let prefixed = (0..=255u8).map(|byte| Instruction::from_byte(byte, true));
+    unprefixed
+        .chain(prefixed)
+        .flatten()
+        .filter(|instruction| !matches!(instruction, Instruction::ILLEGAL(_)))
+        .map(|instruction| {
+            let (mnemonic, operands) = syntax(instruction);
+            (instruction, mnemonic, operands)
+        })
+        .collect()
+}
+
+fn match_instruction(
+    templates: &[(Instruction, &'static str, Vec<Operand>)],
+    mnemonic: &str,
+    operands: &[String],
+) -> Option<Statement> {
+    let found = templates
+        .iter()
+        .filter(|(_, template_mnemonic, template)| {
+            *template_mnemonic == mnemonic && template.len() == operands.len()
+        })
+        .find_map(|(instruction, _, template)| {
+            let mut immediate = None;
+            for (operand, text) in template.iter().zip(operands) {
+                if let Some(expression) = match_operand(*operand, text)? {
+                    immediate = Some((*operand, expression));
+                }
+            }
+            Some(Statement::Instruction(*instruction, immediate))
+        });
+    if found.is_some() {
+        return found;
+    }
+
+    // RGBDS also accepts a few shorthands
+    match mnemonic {
+        "ld" => match_instruction(templates, "ldh", operands),
+        "add" | "adc" | "sub" | "sbc" | "and" | "or" | "xor" | "cp" if operands.len() == 1 => {
+            let operands = ["a".to_string(), operands[0].clone()];
+            match_instruction(templates, mnemonic, &operands)
+        }
+        _ => None,
+    }
+}
+
+/// Checks whether `text` fits `operand`. Returns the expression for the immediate if
+/// the operand has one.
+fn match_operand(operand: Operand, text: &str) -> Option<Option<Expression>> {
+    let normalized: String = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    let normalized = match normalized.as_str() {
+        "[hli]" => "[hl+]",
+        "[hld]" => "[hl-]",
+        "[$ff00+c]" | "[0xff00+c]" => "[c]",
+        other => other,
+    };
+    let indirect = if normalized.starts_with('[') && normalized.ends_with(']') {
+        Some(&text.trim()[1..text.trim().len() - 1])
+    } else {
+        None
+    };
+
+    match operand {
+        Operand::Fixed(fixed) => {
+            if fixed == normalized {
+                return Some(None);
+            }
+            // Bit numbers and RST vectors can be written in any base
+            let fixed = parse_expression(fixed)?.evaluate(&HashMap::new()).ok()?;
+            let value = parse_expression(text)?.evaluate(&HashMap::new()).ok()?;
+            if fixed == value {
+                Some(None)
+            } else {
+                None
+            }
+        }
+        Operand::Byte | Operand::Word | Operand::Relative | Operand::SignedByte => {
+            if indirect.is_some() {
+                return None;
+            }
+            parse_expression(text).map(Some)
+        }
+        Operand::WordIndirect | Operand::HighIndirect => parse_expression(indirect?).map(Some),
+        Operand::StackOffset => {
+            let offset = text.trim();
+            if !offset.to_lowercase().starts_with("sp") {
+                return None;
+            }
+            let offset = offset[2..].trim_start();
+            if !offset.starts_with('+') && !offset.starts_with('-') {
+                return None;
+            }
+            parse_expression(offset).map(Some)
+        }
+    }
+}
+
+/// A sum of numbers and labels such as `label + 2` or `$FF00 - 1`
+struct Expression {
+    terms: Vec<(i32, Term)>,
+}
+
+enum Term {
+    Number(i32),
+    Label(String),
+}
+
+impl Expression {
+    fn number(value: u8) -> Expression {
+        Expression {
+            terms: vec![(1, Term::Number(value as i32))],
+        }
+    }
+
+    fn evaluate(&self, labels: &HashMap<String, i32>) -> Result<i32, String> {
+        let mut result = 0i32;
+        for (sign, term) in self.terms.iter() {
+            let value = match term {
+                Term::Number(value) => *value,
+                Term::Label(label) => *labels
+                    .get(label)
+                    .ok_or_else(|| format!("unknown label '{}'", label))?,
+            };
+            result = result.wrapping_add(sign * value);
+        }
+        Ok(result)
+    }
+}
+
+fn parse_expression(text: &str) -> Option<Expression> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut rest = text.as_str();
+    let mut terms = Vec::new();
+    while !rest.is_empty() {
+        let mut sign = 1;
+        loop {
+            if let Some(after) = rest.strip_prefix('-') {
+                sign = -sign;
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('+') {
+                rest = after;
+            } else {
+                break;
+            }
+        }
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        terms.push((sign, parse_term(&rest[..end])?));
+        rest = &rest[end..];
+    }
+    if terms.is_empty() {
+        None
+    } else {
+        Some(Expression { terms })
+    }
+}
+
+fn parse_term(text: &str) -> Option<Term> {
+    let number = if let Some(hex) = text.strip_prefix('$') {
+        i32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix('%') {
+        i32::from_str_radix(binary, 2).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    };
+    if let Some(number) = number {
+        return Some(Term::Number(number));
+    }
+
+    let is_label = text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if is_label && !REGISTERS_AND_CONDITIONS.contains(&text.to_lowercase().as_str()) {
+        Some(Term::Label(text.to_string()))
+    } else {
+        None
+    }
+}
+
+fn parse_string(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        result.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            _ => return None,
+        });
+    }
+    Some(result)
+}
+
+/// Where the label at the start of `line` ends if there is one
+fn label_end(line: &str) -> Option<usize> {
+    let end = line.find(':')?;
+    let label = &line[..end];
+    let is_label = !label.is_empty()
+        && !label.starts_with(|c: char| c.is_ascii_digit())
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if is_label {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_operands(operands: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut in_string = false;
+    for c in operands.chars() {
+        match c {
+            '"' => {
+                in_string = !in_string;
+                current.push(c);
+            }
+            ',' if !in_string => {
+                result.push(current.trim().to_string());
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        result.push(current.trim().to_string());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction::disassemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn assembles_labels_data_and_literals() {
+        let rom = assemble(
+            "
+            org $0100
+            start:
+                ld a, 10          ; decimal
+                ld hl, data
+            .loop:
+                dec a
+                jr nz, .loop
+                ldh [$FF00 + $01], a
+                ld [hli], a
+                jp start
+            data: db \"Hi\\n\", %101, 0x7F
+                dw data + 1, $BEEF
+            ",
+        )
+        .unwrap();
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(
+            &rom[0x100..0x117],
+            &[
+                0x3E, 0x0A, // ld a, 10
+                0x21, 0x0E, 0x01, // ld hl, data
+                0x3D, // dec a
+                0x20, 0xFD, // jr nz, .loop
+                0xE0, 0x01, // ldh [$FF01], a
+                0x22, // ld [hl+], a
+                0xC3, 0x00, 0x01, // jp start
+                b'H', b'i', b'\n', 0b101, 0x7F, // db
+                0x0F, 0x01, 0xEF, 0xBE, // dw
+            ][..]
+        );
+    }
+
+    #[test]
+    fn reassembles_disassembled_instructions() {
+        for byte in 0..=255u8 {
+            for &prefixed in [false, true].iter() {
+                let instruction = match Instruction::from_byte(byte, prefixed) {
+                    Some(Instruction::ILLEGAL(_)) | None => continue,
+                    Some(instruction) => instruction,
+                };
+                let bytes = instruction.encode(0x7F12);
+                let text = &disassemble(&bytes, 0x200)[0].text;
+                let rom = assemble(&format!("org $200\n{}", text))
+                    .unwrap_or_else(|e| panic!("{}: {}", text, e));
+                assert_eq!(&rom[0x200..0x200 + bytes.len()], &bytes[..], "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = assemble("nop\nld a, [bc], 1").unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = assemble("jp nowhere").unwrap_err();
+        assert_eq!(error.message, "unknown label 'nowhere'");
+
+        let error = assemble("start:\norg $200\njr start").unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn assembled_programs_run() {
+        let rom = assemble(
+            "
+            org $0100
+                ld b, 5
+                xor a
+            loop:
+                add a, 3
+                dec b
+                jr nz, loop
+                halt
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(None, rom);
+        for _ in 0..100 {
+            cpu.step();
+        }
+        assert_eq!(cpu.registers.a, 15);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::debugger::WatchpointHit;
+
+/// The address of the interrupt enable register (IE)
+pub const INTERRUPT_ENABLE: u16 = 0xFFFF;
+/// The address of the interrupt flag register (IF)
+pub const INTERRUPT_FLAG: u16 = 0xFF0F;
+
+/// Everything the CPU is connected to: memory, I/O registers and the rest of the
+/// hardware that runs alongside it.
+///
+/// `MemoryBus` is the Game Boy's. Other implementations let the CPU run against
+/// something simpler such as a flat 64 KiB of RAM.
+pub trait Bus {
+    /// Reads a byte without any time passing
+    fn read_byte(&self, address: u16) -> u8;
+
+    /// Reads a byte on behalf of a debugger or tracer rather than the hardware, so
+    /// without triggering watchpoints. The CPU also uses this for its own bookkeeping,
+    /// such as checking which interrupts are pending.
+    fn peek_byte(&self, address: u16) -> u8 {
+        self.read_byte(address)
+    }
+
+    /// Writes a byte without any time passing
+    fn write_byte(&mut self, address: u16, value: u8);
+
+    /// Writes a byte without triggering watchpoints, like `peek_byte` does for reads
+    fn poke_byte(&mut self, address: u16, value: u8) {
+        self.write_byte(address, value)
+    }
+
+    /// Runs the rest of the machine for one machine cycle (4 clock cycles). The CPU
+    /// calls this for cycles it spends on internal work.
+    fn tick(&mut self);
+
+    /// The number of clock cycles that have passed since power on
+    fn cycles(&self) -> u64;
+
+    /// Reads a byte the way the CPU does, which takes one machine cycle
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.read_byte(address)
+    }
+
+    /// Reads a byte of the instruction being executed, which takes one machine cycle.
+    /// `opcode` is whether it's the instruction's first byte. This is `read_cycle`
+    /// except to a bus keeping a code/data log.
+    fn fetch_cycle(&mut self, address: u16, opcode: bool) -> u8 {
+        let _ = opcode;
+        self.read_cycle(address)
+    }
+
+    /// Writes a byte the way the CPU does, which takes one machine cycle
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.write_byte(address, value);
+    }
+
+    /// Whether any interrupt is both requested (IF) and enabled (IE)
+    fn has_interrupt(&self) -> bool {
+        (self.read_byte(INTERRUPT_ENABLE) & self.read_byte(INTERRUPT_FLAG) & 0x1F) != 0
+    }
+
+    /// The bank mapped in at `address`, used to tell apart symbols for code and data
+    /// that share an address. By default that's a cartridge without a memory bank
+    /// controller: bank 1 of ROM at 0x4000-0x7FFF and of WRAM at 0xD000-0xDFFF and
+    /// bank 0 everywhere else.
+    fn bank(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=0x7FFF | 0xD000..=0xDFFF => 1,
+            _ => 0,
+        }
+    }
+
+    /// The first watchpoint triggered since this was last called. Busses without
+    /// watchpoints never have one.
+    fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        None
+    }
+}
+
+/// 64 KiB of RAM with nothing else attached: no I/O registers, no ROM banks and no
+/// hardware running alongside the CPU. Useful for running the CPU against synthetic
+/// memory.
+///
+/// ```
+/// use lib_dmg_01::{FlatBus, CPU};
+///
+/// let mut bus = FlatBus::new();
+/// bus.load(0x0000, &[0x3E, 0x2A]); // ld a, $2A
+/// let mut cpu = CPU::with_bus(bus);
+/// assert_eq!(cpu.step(), 8);
+/// assert_eq!(cpu.registers.a, 0x2A);
+/// ```
+pub struct FlatBus {
+    pub memory: Vec<u8>,
+    cycles: u64,
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    /// Copies `bytes` into memory starting at `address`
+    pub fn load(&mut self, address: u16, bytes: &[u8]) {
+        let start = address as usize;
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> FlatBus {
+        FlatBus::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 4;
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn interrupts_dispatch_through_ie_and_if() {
+        let mut cpu = CPU::with_bus(FlatBus::new());
+        cpu.pc = 0x1234;
+        cpu.sp = 0xD000;
+        cpu.bus.write_byte(INTERRUPT_ENABLE, 0b0_0110);
+        cpu.bus.write_byte(INTERRUPT_FLAG, 0b1_0100);
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.sp, 0xCFFE);
+        assert_eq!(cpu.bus.read_byte(0xCFFF), 0x12);
+        assert_eq!(cpu.bus.read_byte(0xCFFE), 0x34);
+        // Only the timer interrupt that was serviced is acknowledged
+        assert_eq!(cpu.bus.read_byte(INTERRUPT_FLAG), 0b1_0000);
+        assert!(!cpu.interrupts_enabled());
+    }
+
+    #[test]
+    fn runs_without_interrupts_pending() {
+        let mut bus = FlatBus::new();
+        // ld hl, $C000 / ld [hl], $99 / inc [hl]
+        bus.load(0x0000, &[0x21, 0x00, 0xC0, 0x36, 0x99, 0x34]);
+        let mut cpu = CPU::with_bus(bus);
+        assert_eq!(cpu.step() + cpu.step() + cpu.step(), 12 + 12 + 12);
+        assert_eq!(cpu.bus.read_byte(0xC000), 0x9A);
+        assert_eq!(cpu.bus.cycles(), 36);
+    }
+}
@@ -0,0 +1,196 @@
+//! Hooks for watching the traffic on a `MemoryBus`, for tools such as memory heatmaps,
+//! cheat searches and achievement checkers.
+//!
+//! Observers are told of every access after it happens and can't change it. Nothing is
+//! done for them while none are registered.
+//!
+//! ```
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! use lib_dmg_01::{assemble, AccessKind, BusAccess, CPU};
+//!
+//! let rom = assemble("org $0100\n ld a, $2A\n ld [$C000], a\n").unwrap();
+//! let mut cpu = CPU::new(None, rom);
+//! let writes = Rc::new(RefCell::new(Vec::new()));
+//! let seen = Rc::clone(&writes);
+//! cpu.bus.observers.add(move |access: BusAccess| {
+//!     if access.kind == AccessKind::Write {
+//!         seen.borrow_mut().push((access.address, access.value));
+//!     }
+//! });
+//! cpu.step();
+//! cpu.step();
+//! assert_eq!(*writes.borrow(), vec![(0xC000, 0x2A)]);
+//! ```
+
+use std::cell::RefCell;
+
+/// What a `BusAccess` was made for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    /// The CPU reading a byte of the instruction it's executing
+    Fetch,
+    /// An instruction reading memory
+    Read,
+    /// An instruction writing memory
+    Write,
+    /// An instruction reading an I/O register, including IE at 0xFFFF
+    IoRead,
+    /// An instruction writing an I/O register, including IE at 0xFFFF
+    IoWrite,
+    /// OAM DMA copying a byte from `address` to the same offset in OAM
+    Dma,
+}
+
+/// A single read or write made on the bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+    pub address: u16,
+    /// The byte read or written
+    pub value: u8,
+    /// The number of clock cycles since power on when the access happened
+    pub cycle: u64,
+    pub kind: AccessKind,
+}
+
+/// Something told of every access made on a `MemoryBus`. Closures taking a
+/// `BusAccess` are observers.
+pub trait BusObserver {
+    fn observe(&mut self, access: BusAccess);
+}
+
+impl<F: FnMut(BusAccess)> BusObserver for F {
+    fn observe(&mut self, access: BusAccess) {
+        self(access)
+    }
+}
+
+/// The observers registered on a `MemoryBus`, numbered like `Watchpoints`.
+///
+/// Reads happen through `&self` so the observers are kept in a `RefCell`.
+#[derive(Default)]
+pub struct BusObservers {
+    observers: RefCell<Vec<(usize, Box<dyn BusObserver>)>>,
+    last_id: usize,
+}
+
+impl BusObservers {
+    pub fn add<O: BusObserver + 'static>(&mut self, observer: O) -> usize {
+        self.last_id += 1;
+        self.observers
+            .get_mut()
+            .push((self.last_id, Box::new(observer)));
+        self.last_id
+    }
+
+    /// Removes the observer numbered `id`, returning whether there was one
+    pub fn remove(&mut self, id: usize) -> bool {
+        let observers = self.observers.get_mut();
+        let count = observers.len();
+        observers.retain(|(i, _)| *i != id);
+        observers.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.observers.get_mut().clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.borrow().is_empty()
+    }
+
+    pub(crate) fn notify(&self, access: BusAccess) {
+        for (_, observer) in self.observers.borrow_mut().iter_mut() {
+            observer.observe(access);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::cpu::CPU;
+    use std::rc::Rc;
+
+    fn record(cpu: &mut CPU) -> (usize, Rc<RefCell<Vec<BusAccess>>>) {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&accesses);
+        let id = cpu
+            .bus
+            .observers
+            .add(move |access| seen.borrow_mut().push(access));
+        (id, accesses)
+    }
+
+    #[test]
+    fn sees_every_kind_of_access() {
+        let rom = assemble(
+            "
+            org $0100
+                ld hl, $C000    ; $0100
+                ld [hl], $12    ; $0103
+                ld a, [hl]      ; $0105
+                ldh a, [$FF00 + $FF]
+                ld a, $02       ; $0108
+                ldh [$FF00 + $46], a
+                nop             ; $010C
+            org $0200
+                db $AB
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(None, rom);
+        cpu.step();
+        let start = cpu.bus.cycles();
+        let (_, accesses) = record(&mut cpu);
+        for _ in 0..6 {
+            cpu.step();
+        }
+
+        let access = |address, value, cycle, kind| BusAccess {
+            address,
+            value,
+            cycle: start + cycle,
+            kind,
+        };
+        let interrupt_enable = cpu.bus.peek_byte(0xFFFF);
+        assert_eq!(
+            *accesses.borrow(),
+            vec![
+                access(0x0103, 0x36, 4, AccessKind::Fetch),
+                access(0x0104, 0x12, 8, AccessKind::Fetch),
+                access(0xC000, 0x12, 12, AccessKind::Write),
+                access(0x0105, 0x7E, 16, AccessKind::Fetch),
+                access(0xC000, 0x12, 20, AccessKind::Read),
+                access(0x0106, 0xF0, 24, AccessKind::Fetch),
+                access(0x0107, 0xFF, 28, AccessKind::Fetch),
+                access(0xFFFF, interrupt_enable, 32, AccessKind::IoRead),
+                access(0x0108, 0x3E, 36, AccessKind::Fetch),
+                access(0x0109, 0x02, 40, AccessKind::Fetch),
+                access(0x010A, 0xE0, 44, AccessKind::Fetch),
+                access(0x010B, 0x46, 48, AccessKind::Fetch),
+                access(0xFF46, 0x02, 52, AccessKind::IoWrite),
+                access(0x0200, 0xAB, 56, AccessKind::Dma),
+                access(0x010C, 0x00, 56, AccessKind::Fetch),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_observers_are_not_told() {
+        let rom = assemble("org $0100\n nop\n nop\n").unwrap();
+        let mut cpu = CPU::new(None, rom);
+        let (first, removed) = record(&mut cpu);
+        let (_, kept) = record(&mut cpu);
+        cpu.step();
+        assert!(cpu.bus.observers.remove(first));
+        assert!(!cpu.bus.observers.remove(first));
+        cpu.step();
+
+        assert_eq!(removed.borrow().len(), 1);
+        assert_eq!(kept.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,128 @@
+use std::cell::Cell;
+
+/// A record of how each byte of ROM has been used, for telling code from data when
+/// disassembling a game.
+///
+/// Each byte of ROM has a byte of flags in the log, so saved as is (with `to_bytes`)
+/// the log is a file the same size as the ROM in the layout used by the CDL files of
+/// FCEUX and BizHawk. The flags are BizHawk's for the Game Boy with an extra one for
+/// bytes copied by OAM DMA.
+///
+/// A `MemoryBus` with a log records every read of ROM the CPU makes in it. Reads
+/// happen through `&self` so the flags are kept in `Cell`s.
+///
+/// ```
+/// use lib_dmg_01::CodeDataLog;
+///
+/// let log = CodeDataLog::from_bytes(vec![0x01, 0x02, 0x02, 0x04, 0x00]);
+/// assert!(log.is_code(0));
+/// assert!(!log.is_code(1));
+/// assert_eq!(log.flags(3), CodeDataLog::DATA);
+/// assert_eq!(log.to_bytes(), vec![0x01, 0x02, 0x02, 0x04, 0x00]);
+/// ```
+pub struct CodeDataLog {
+    flags: Vec<Cell<u8>>,
+}
+
+impl CodeDataLog {
+    /// Executed as the first byte of an instruction
+    pub const OPCODE: u8 = 0x01;
+    /// Read as part of an instruction other than its first byte
+    pub const OPERAND: u8 = 0x02;
+    /// Read by an instruction as data
+    pub const DATA: u8 = 0x04;
+    /// Copied to OAM by DMA
+    pub const DMA: u8 = 0x08;
+
+    /// An empty log for a ROM of `length` bytes
+    pub fn new(length: usize) -> CodeDataLog {
+        CodeDataLog::from_bytes(vec![0; length])
+    }
+
+    /// Carries on with a log saved by `to_bytes`
+    pub fn from_bytes(bytes: Vec<u8>) -> CodeDataLog {
+        CodeDataLog {
+            flags: bytes.into_iter().map(Cell::new).collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags.iter().map(Cell::get).collect()
+    }
+
+    /// The number of bytes of ROM logged
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    /// How the byte at `offset` in ROM has been used. Offsets past the end of the log
+    /// have no flags.
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    /// Whether the byte at `offset` in ROM has been executed as an opcode
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.flags(offset) & CodeDataLog::OPCODE != 0
+    }
+
+    pub(crate) fn log(&self, offset: usize, flags: u8) {
+        if let Some(cell) = self.flags.get(offset) {
+            cell.set(cell.get() | flags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn logs_how_the_cpu_uses_rom() {
+        let rom = assemble(
+            "
+            org $0100
+                ld hl, table    ; $0100
+                ld a, [hl]      ; $0103
+                ld a, $02       ; $0104
+                ldh [$FF00 + $46], a
+                ld b, $40       ; $0108
+            wait:
+                dec b           ; $010A
+                jr nz, wait
+            done:
+                jr done         ; $010D
+            table:
+                db $AB          ; $010F
+            org $0200
+                db $01, $02
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(None, rom);
+        cpu.bus.code_data_log = Some(CodeDataLog::new(0x8000));
+        cpu.run(2000);
+        let log = cpu.bus.code_data_log.take().unwrap();
+
+        let flags: Vec<u8> = (0x0100..0x0110).map(|offset| log.flags(offset)).collect();
+        let (opcode, operand, data) =
+            (CodeDataLog::OPCODE, CodeDataLog::OPERAND, CodeDataLog::DATA);
+        assert_eq!(
+            flags,
+            vec![
+                opcode, operand, operand, opcode, opcode, operand, opcode, operand, opcode,
+                operand, opcode, opcode, operand, opcode, operand, data,
+            ]
+        );
+        assert_eq!(log.flags(0x0200), CodeDataLog::DMA);
+        assert_eq!(log.flags(0x0200 + 0x9F), CodeDataLog::DMA);
+        assert_eq!(log.flags(0x0200 + 0xA0), 0);
+        assert_eq!(log.to_bytes().len(), 0x8000);
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::CPU;
+use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// How many of the most recent anomalies are kept
+const MAX_ANOMALIES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallKind::Call => write!(f, "call"),
+            CallKind::Rst => write!(f, "rst"),
+            CallKind::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+/// A call the CPU made and hasn't returned from yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// The address of the CALL or RST instruction, or for an interrupt the address of
+    /// the instruction it interrupted
+    pub call_site: u16,
+    pub target: u16,
+    /// The address pushed on to the stack for the matching return
+    pub return_address: u16,
+    /// Where on the stack the return address was pushed
+    pub sp: u16,
+}
+
+/// Code that moves the stack around behind the back of CALL and RET. Games do this on
+/// purpose (jump tables that `push` an address and `ret` to it, popping a return
+/// address to never go back, resetting SP) as well as by mistake. Rather than letting
+/// it scramble the call stack, frames that can no longer be returned to are dropped
+/// and the anomaly is recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackAnomaly {
+    /// The return at `at` went to `actual` but the call that pushed its return address
+    /// expected `expected`, so the return address was changed on the stack
+    ReturnMismatch { at: u16, expected: u16, actual: u16 },
+    /// The return at `at` popped an address to `to` that no call pushed
+    ReturnWithoutCall { at: u16, to: u16 },
+    /// `frames` calls were dropped at `at` because the stack pointer had moved above
+    /// their return addresses without returning
+    Unwound { at: u16, frames: usize },
+}
+
+impl fmt::Display for StackAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackAnomaly::ReturnMismatch {
+                at,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "the return at ${:04X} went to ${:04X} rather than ${:04X}",
+                at, actual, expected
+            ),
+            StackAnomaly::ReturnWithoutCall { at, to } => write!(
+                f,
+                "the return at ${:04X} went to ${:04X} which no call pushed",
+                at, to
+            ),
+            StackAnomaly::Unwound { at, frames } => write!(
+                f,
+                "{} call(s) dropped at ${:04X} as the stack pointer moved past them",
+                frames, at
+            ),
+        }
+    }
+}
+
+/// A shadow of the calls on the stack kept alongside the real one
+#[derive(Debug, Default, Clone)]
+pub(super) struct CallStack {
+    frames: Vec<CallFrame>,
+    anomalies: VecDeque<StackAnomaly>,
+}
+
+impl CallStack {
+    pub(super) fn call(&mut self, frame: CallFrame) {
+        // A new return address at or above an existing one means the stack was reset
+        // or popped past those calls
+        self.unwind(frame.call_site, |existing| existing.sp <= frame.sp);
+        self.frames.push(frame);
+    }
+
+    pub(super) fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    /// Records a return at `at` that popped `to` from `sp`
+    pub(super) fn ret(&mut self, at: u16, sp: u16, to: u16) {
+        self.unwind(at, |frame| frame.sp < sp);
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp => {
+                if frame.return_address != to {
+                    let expected = frame.return_address;
+                    self.flag(StackAnomaly::ReturnMismatch {
+                        at,
+                        expected,
+                        actual: to,
+                    });
+                }
+                self.frames.pop();
+            }
+            _ => self.flag(StackAnomaly::ReturnWithoutCall { at, to }),
+        }
+    }
+
+    // Saved with the rest of the machine so rewinding keeps backtraces correct. The
+    // anomalies are a log of what happened rather than state so they stay as they are.
+    pub(super) fn write_state(&self, writer: &mut StateWriter) {
+        writer.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            writer.u8(match frame.kind {
+                CallKind::Call => 0,
+                CallKind::Rst => 1,
+                CallKind::Interrupt => 2,
+            });
+            writer.u16(frame.call_site);
+            writer.u16(frame.target);
+            writer.u16(frame.return_address);
+            writer.u16(frame.sp);
+        }
+    }
+
+    pub(super) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let length = reader.u32()?;
+        self.frames.clear();
+        for _ in 0..length {
+            let kind = match reader.u8()? {
+                0 => CallKind::Call,
+                1 => CallKind::Rst,
+                2 => CallKind::Interrupt,
+                _ => return Err(StateError::Mismatch("unknown kind of call")),
+            };
+            self.frames.push(CallFrame {
+                kind,
+                call_site: reader.u16()?,
+                target: reader.u16()?,
+                return_address: reader.u16()?,
+                sp: reader.u16()?,
+            });
+        }
+        Ok(())
+    }
+
+    // Drops the innermost frames for which `abandoned` holds
+    fn unwind<F: Fn(&CallFrame) -> bool>(&mut self, at: u16, abandoned: F) {
+        let kept = self
+            .frames
+            .iter()
+            .rposition(|frame| !abandoned(frame))
+            .map_or(0, |index| index + 1);
+        let frames = self.frames.len() - kept;
+        if frames > 0 {
+            self.frames.truncate(kept);
+            self.flag(StackAnomaly::Unwound { at, frames });
+        }
+    }
+
+    fn flag(&mut self, anomaly: StackAnomaly) {
+        if self.anomalies.len() == MAX_ANOMALIES {
+            self.anomalies.pop_front();
+        }
+        self.anomalies.push_back(anomaly);
+    }
+}
+
+/// The calls that led to the current PC, innermost first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    pub pc: u16,
+    pub frames: Vec<CallFrame>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#0  ${:04X}", self.pc)?;
+        for (index, frame) in self.frames.iter().enumerate() {
+            write!(
+                f,
+                "\n#{:<2} ${:04X}  {} ${:04X}",
+                index + 1,
+                frame.call_site,
+                frame.kind,
+                frame.target
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// The calls, RSTs and interrupts the CPU is currently inside of
+    pub fn backtrace(&self) -> Backtrace {
+        Backtrace {
+            pc: self.pc,
+            frames: self.call_stack.frames.iter().rev().cloned().collect(),
+        }
+    }
+
+    /// The most recent times code moved the stack in a way the call stack couldn't
+    /// follow, oldest first
+    pub fn stack_anomalies(&self) -> impl Iterator<Item = &StackAnomaly> {
+        self.call_stack.anomalies.iter()
+    }
+
+    pub(super) fn record_call(&mut self, kind: CallKind, target: u16, return_address: u16) {
+        let call_site = match kind {
+            CallKind::Interrupt => return_address,
+            _ => self.pc,
+        };
+        self.call_stack.call(CallFrame {
+            kind,
+            call_site,
+            target,
+            return_address,
+            sp: self.sp,
+        });
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.called(kind, target, self.call_stack.frames.len());
+        }
+    }
+
+    pub(super) fn record_return(&mut self, sp: u16, to: u16) {
+        self.call_stack.ret(self.pc, sp, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn cpu(source: &str) -> CPU {
+        CPU::new(None, assemble(source).unwrap())
+    }
+
+    fn run_to(cpu: &mut CPU, address: u16) {
+        for _ in 0..1000 {
+            if cpu.pc == address {
+                return;
+            }
+            cpu.step();
+        }
+        panic!("never reached ${:04X}", address);
+    }
+
+    #[test]
+    fn tracks_calls_and_returns() {
+        let mut cpu = cpu("
+            org $0100
+                ld sp, $DFFE
+                call outer      ; $0103
+                jr done         ; $0106
+            outer:
+                rst $38         ; $0108
+                ret
+            inner:
+                nop             ; $010A
+                ret
+            done:
+                halt            ; $010C
+            org $0038
+                call inner      ; $0038
+                ret
+        ");
+        run_to(&mut cpu, 0x010A);
+        assert_eq!(
+            cpu.backtrace().to_string(),
+            "#0  $010A\n\
+             #1  $0038  call $010A\n\
+             #2  $0108  rst $0038\n\
+             #3  $0103  call $0108"
+        );
+        run_to(&mut cpu, 0x010C);
+        assert!(cpu.backtrace().frames.is_empty());
+        assert_eq!(cpu.stack_anomalies().count(), 0);
+    }
+
+    #[test]
+    fn interrupts_are_calls() {
+        let mut cpu = cpu("
+            org $0100
+                di
+                ld a, $01
+                ldh [$FF00 + $FF], a
+                ldh [$FF00 + $0F], a
+                ei
+                nop
+            wait:
+                jr wait         ; $0109
+            org $0040
+                reti
+        ");
+        run_to(&mut cpu, 0x0040);
+        let frame = &cpu.backtrace().frames[0];
+        assert_eq!(frame.kind, CallKind::Interrupt);
+        assert_eq!((frame.call_site, frame.target), (0x0109, 0x0040));
+        cpu.step();
+        assert!(cpu.backtrace().frames.is_empty());
+    }
+
+    #[test]
+    fn flags_stack_manipulation() {
+        let mut cpu = cpu("
+            org $0100
+                ld sp, $DFFE
+                ld hl, target
+                push hl
+                ret             ; $0107
+            target:
+                call discard    ; $0108
+            discard:
+                pop hl          ; $010B
+                call swap       ; $010C
+                nop             ; $010F
+                halt            ; $0110
+            swap:
+                pop hl
+                inc hl
+                push hl
+                ret             ; $0114
+        ");
+        run_to(&mut cpu, 0x0110);
+        assert!(cpu.backtrace().frames.is_empty());
+        assert_eq!(
+            cpu.stack_anomalies().cloned().collect::<Vec<_>>(),
+            vec![
+                StackAnomaly::ReturnWithoutCall {
+                    at: 0x0107,
+                    to: 0x0108
+                },
+                StackAnomaly::Unwound {
+                    at: 0x010C,
+                    frames: 1
+                },
+                StackAnomaly::ReturnMismatch {
+                    at: 0x0114,
+                    expected: 0x010F,
+                    actual: 0x0110
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resetting_the_stack_drops_frames() {
+        let mut cpu = cpu("
+            org $0100
+                ld sp, $DFFE
+                call reset      ; $0103
+            reset:
+                ld sp, $DFFE    ; $0106
+                call leaf       ; $0109
+                halt
+            leaf:
+                nop             ; $010D
+        ");
+        run_to(&mut cpu, 0x010D);
+        let frames = cpu.backtrace().frames;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].call_site, 0x0109);
+        assert_eq!(
+            cpu.stack_anomalies().cloned().collect::<Vec<_>>(),
+            vec![StackAnomaly::Unwound {
+                at: 0x0109,
+                frames: 1
+            }]
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use std::fmt;
+
+use super::syntax::{syntax, Operand};
+use super::Instruction;
+use crate::bus::Bus;
+use crate::code_data_log::CodeDataLog;
+use crate::memory_bus::ROM_BANK_N_END;
+use crate::symbols::Symbols;
+
+// The most bytes of data written on one `db` line
+const DATA_PER_LINE: usize = 8;
+
+/// An instruction decoded from memory along with where it was found.
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct DisassembledInstruction {
+    pub address: u16,
+    /// The label for `address` when disassembling with symbols. It's left to the caller
+    /// to show as `Display` only writes the address, bytes and text.
+    pub label: Option<String>,
+    /// The raw bytes of the instruction including its opcode and operands
+    pub bytes: Vec<u8>,
+    /// `None` when the bytes ran out before the end of the instruction
+    #[cfg_attr(feature = "serialize", serde(skip_serializing))]
+    pub instruction: Option<Instruction>,
+    /// The instruction in RGBDS syntax with its operands filled in (e.g. `jr nz, $0150`)
+    pub text: String,
+}
+
+impl DisassembledInstruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:04X}  {:<9} {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Disassembles `bytes` which are expected to live in memory starting at `address`.
+///
+/// An instruction cut off by the end of `bytes` is returned as `db` data.
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<DisassembledInstruction> {
+    let end = address as u32 + bytes.len() as u32;
+    disassemble_with(
+        address,
+        end,
+        |at| bytes.get(at.wrapping_sub(address) as usize).cloned(),
+        |_| None,
+        |_| false,
+    )
+}
+
+/// Disassembles every instruction that starts between `start` (inclusive) and `end`
+/// (exclusive) on the bus. The last instruction may extend past `end`.
+pub fn disassemble_bus<B: Bus>(bus: &B, start: u16, end: u16) -> Vec<DisassembledInstruction> {
+    disassemble_with(
+        start,
+        end as u32,
+        |at| Some(bus.peek_byte(at)),
+        |_| None,
+        |_| false,
+    )
+}
+
+/// Like `disassemble_bus` but labels instructions and writes jump, call and memory
+/// targets using the labels in `symbols` for the banks currently mapped in.
+pub fn disassemble_bus_with_symbols<B: Bus>(
+    bus: &B,
+    start: u16,
+    end: u16,
+    symbols: &Symbols,
+) -> Vec<DisassembledInstruction> {
+    disassemble_with(
+        start,
+        end as u32,
+        |at| Some(bus.peek_byte(at)),
+        |at| symbols.label_on(bus, at).map(String::from),
+        |_| false,
+    )
+}
+
+/// Like `disassemble_bus_with_symbols` but uses `log` to tell code from data. Bytes
+/// of ROM the log has seen used but never executed as an opcode are written as `db`
+/// lines of data. Bytes never used are disassembled as code.
+///
+/// The offsets in `log` are taken to be addresses, which they are for a cartridge
+/// without banks. Addresses past the end of the log, or outside ROM at 0x0000-0x7FFF,
+/// are always code.
+pub fn disassemble_bus_with_log<B: Bus>(
+    bus: &B,
+    start: u16,
+    end: u16,
+    symbols: &Symbols,
+    log: &CodeDataLog,
+) -> Vec<DisassembledInstruction> {
+    disassemble_with(
+        start,
+        end as u32,
+        |at| Some(bus.peek_byte(at)),
+        |at| symbols.label_on(bus, at).map(String::from),
+        |at| {
+            let flags = if at as usize <= ROM_BANK_N_END {
+                log.flags(at as usize)
+            } else {
+                0
+            };
+            flags != 0 && flags & CodeDataLog::OPCODE == 0
+        },
+    )
+}
+
+fn disassemble_with<F, L, D>(
+    start: u16,
+    end: u32,
+    read: F,
+    label: L,
+    is_data: D,
+) -> Vec<DisassembledInstruction>
+where
+    F: Fn(u16) -> Option<u8>,
+    L: Fn(u16) -> Option<String>,
+    D: Fn(u16) -> bool,
+{
+    let mut result = Vec::new();
+    let mut address = start as u32;
+    while address < end {
+        if is_data(address as u16) {
+            // Data runs up to the next code or label
+            let mut bytes = Vec::with_capacity(DATA_PER_LINE);
+            let mut at = address;
+            while at < end && bytes.len() < DATA_PER_LINE && is_data(at as u16) {
+                if at != address && label(at as u16).is_some() {
+                    break;
+                }
+                match read(at as u16) {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+                at += 1;
+            }
+            let mut instruction = data(address as u16, bytes);
+            instruction.label = label(address as u16);
+            address = at;
+            result.push(instruction);
+            continue;
+        }
+        let mut instruction = disassemble_one(address as u16, &read, &label);
+        instruction.label = label(address as u16);
+        address += instruction.length() as u32;
+        result.push(instruction);
+    }
+    result
+}
+
+fn disassemble_one<F, L>(address: u16, read: &F, label: &L) -> DisassembledInstruction
+where
+    F: Fn(u16) -> Option<u8>,
+    L: Fn(u16) -> Option<String>,
+{
+    let mut bytes = Vec::with_capacity(3);
+    let opcode = read(address).expect("disassembling outside of the given bytes");
+    bytes.push(opcode);
+
+    let prefixed = opcode == 0xCB;
+    let instruction = if prefixed {
+        read(address.wrapping_add(1)).and_then(|byte| {
+            bytes.push(byte);
+            Instruction::from_byte(byte, true)
+        })
+    } else {
+        Instruction::from_byte(opcode, false)
+    };
+
+    let instruction = match instruction {
+        Some(instruction) => instruction,
+        None => return data(address, bytes),
+    };
+    for offset in bytes.len() as u16..instruction.length() {
+        match read(address.wrapping_add(offset)) {
+            Some(byte) => bytes.push(byte),
+            None => return data(address, bytes),
+        }
+    }
+
+    let text = format_instruction(instruction, address, &bytes, label);
+    DisassembledInstruction {
+        address,
+        label: None,
+        bytes,
+        instruction: Some(instruction),
+        text,
+    }
+}
+
+fn data(address: u16, bytes: Vec<u8>) -> DisassembledInstruction {
+    let values: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    DisassembledInstruction {
+        address,
+        label: None,
+        text: format!("db {}", values.join(", ")),
+        bytes,
+        instruction: None,
+    }
+}
+
+fn format_instruction<L>(instruction: Instruction, address: u16, bytes: &[u8], label: &L) -> String
+where
+    L: Fn(u16) -> Option<String>,
+{
+    if let Instruction::ILLEGAL(opcode) = instruction {
+        return format!("db ${:02X}", opcode);
+    }
+
+    // Other 16 bit immediates are as likely to be numbers as addresses
+    let jumps = matches!(instruction, Instruction::JP(_) | Instruction::CALL(_));
+    let (mnemonic, operands) = syntax(instruction);
+    let operands: Vec<String> = operands
+        .into_iter()
+        .map(|operand| format_operand(operand, address, bytes, jumps, label))
+        .collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+fn format_operand<L>(operand: Operand, address: u16, bytes: &[u8], jumps: bool, label: &L) -> String
+where
+    L: Fn(u16) -> Option<String>,
+{
+    let word = || (bytes[2] as u16) << 8 | bytes[1] as u16;
+    let target = |address: u16| label(address).unwrap_or_else(|| format!("${:04X}", address));
+    let signed = || {
+        let offset = bytes[1] as i8;
+        if offset < 0 {
+            format!("-${:02X}", offset.unsigned_abs())
+        } else {
+            format!("${:02X}", offset)
+        }
+    };
+    match operand {
+        Operand::Fixed(text) => text.to_string(),
+        Operand::Byte => format!("${:02X}", bytes[1]),
+        Operand::Word if jumps => target(word()),
+        Operand::Word => format!("${:04X}", word()),
+        Operand::WordIndirect => format!("[{}]", target(word())),
+        Operand::HighIndirect => match label(0xFF00 | bytes[1] as u16) {
+            Some(label) => format!("[{}]", label),
+            None => format!("[$FF{:02X}]", bytes[1]),
+        },
+        Operand::Relative => target(
+            address
+                .wrapping_add(2)
+                .wrapping_add(bytes[1] as i8 as i16 as u16),
+        ),
+        Operand::SignedByte => signed(),
+        Operand::StackOffset => {
+            let offset = signed();
+            if offset.starts_with('-') {
+                format!("sp{}", offset)
+            } else {
+                format!("sp+{}", offset)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(bytes: &[u8], address: u16) -> Vec<(u16, String)> {
+        disassemble(bytes, address)
+            .into_iter()
+            .map(|instruction| (instruction.address, instruction.text))
+            .collect()
+    }
+
+    #[test]
+    fn disassembles_with_resolved_operands() {
+        let program = [
+            0x00, // nop
+            0x3E, 0x01, // ld a, $01
+            0x21, 0x00, 0xC0, // ld hl, $C000
+            0x22, // ld [hl+], a
+            0xE0, 0x44, // ldh [$FF44], a
+            0xF2, // ldh a, [c]
+            0x20, 0xF5, // jr nz, $0151
+            0xCB, 0x7E, // bit 7, [hl]
+            0xF8, 0xFE, // ld hl, sp-$02
+            0xC4, 0x34, 0x12, // call nz, $1234
+            0xFF, // rst $38
+            0xD3, // illegal
+        ];
+        assert_eq!(
+            texts(&program, 0x150),
+            vec![
+                (0x150, "nop".to_string()),
+                (0x151, "ld a, $01".to_string()),
+                (0x153, "ld hl, $C000".to_string()),
+                (0x156, "ld [hl+], a".to_string()),
+                (0x157, "ldh [$FF44], a".to_string()),
+                (0x159, "ldh a, [c]".to_string()),
+                (0x15A, "jr nz, $0151".to_string()),
+                (0x15C, "bit 7, [hl]".to_string()),
+                (0x15E, "ld hl, sp-$02".to_string()),
+                (0x160, "call nz, $1234".to_string()),
+                (0x163, "rst $38".to_string()),
+                (0x164, "db $D3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_labels_from_symbols() {
+        let mut bus = crate::bus::FlatBus::new();
+        bus.load(
+            0x150,
+            &[
+                0xCD, 0x00, 0x40, // call Banked
+                0x18, 0xFB, // jr main
+                0xFA, 0x00, 0xC0, // ld a, [wCounter]
+                0xE0, 0x80, // ldh [hFlag], a
+                0x21, 0x50, 0x01, // ld hl, $0150
+            ],
+        );
+        let symbols = Symbols::parse(
+            "00:0150 main\n01:4000 Banked\n02:4000 OtherBank\n00:C000 wCounter\n00:FF80 hFlag",
+        )
+        .unwrap();
+        let disassembly = disassemble_bus_with_symbols(&bus, 0x150, 0x15D, &symbols);
+        let texts: Vec<&str> = disassembly.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "call Banked",
+                "jr main",
+                "ld a, [wCounter]",
+                "ldh [hFlag], a",
+                "ld hl, $0150",
+            ]
+        );
+        assert_eq!(disassembly[0].label.as_deref(), Some("main"));
+        assert_eq!(disassembly[1].label, None);
+    }
+
+    #[test]
+    fn separates_code_from_data_with_a_code_data_log() {
+        let mut bus = crate::bus::FlatBus::new();
+        bus.load(
+            0x150,
+            &[
+                0x21, 0x58, 0x01, // ld hl, table
+                0x2A, // ld a, [hl+]
+                0x18, 0xFA, // jr main
+                0x00, 0x00, // never used
+                0x3E, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, // table
+            ],
+        );
+        let log = CodeDataLog::new(0x8000);
+        for (address, flags) in &[
+            (0x150, CodeDataLog::OPCODE),
+            (0x151, CodeDataLog::OPERAND),
+            (0x152, CodeDataLog::OPERAND),
+            (0x153, CodeDataLog::OPCODE),
+            (0x154, CodeDataLog::OPCODE),
+            (0x155, CodeDataLog::OPERAND),
+        ] {
+            log.log(*address, *flags);
+        }
+        for address in 0x158..0x162 {
+            log.log(address, CodeDataLog::DATA);
+        }
+        let symbols = Symbols::parse("00:0150 main\n00:0158 table\n00:0160 table.end").unwrap();
+        let disassembly = disassemble_bus_with_log(&bus, 0x150, 0x162, &symbols, &log);
+        let lines: Vec<(u16, &str)> = disassembly
+            .iter()
+            .map(|i| (i.address, i.text.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (0x150, "ld hl, $0158"),
+                (0x153, "ld a, [hl+]"),
+                (0x154, "jr main"),
+                (0x156, "nop"),
+                (0x157, "nop"),
+                (0x158, "db $3E, $01, $02, $03, $04, $05, $06, $07"),
+                (0x160, "db $08, $09"),
+            ]
+        );
+        assert_eq!(disassembly[6].label.as_deref(), Some("table.end"));
+
+        // A log of a larger ROM says nothing about RAM at the same offsets
+        let log = CodeDataLog::new(0x10000);
+        bus.load(0xC000, &[0x3C]);
+        log.log(0xC000, CodeDataLog::DATA);
+        let disassembly = disassemble_bus_with_log(&bus, 0xC000, 0xC001, &symbols, &log);
+        assert_eq!(disassembly[0].text, "inc a");
+    }
+
+    #[test]
+    fn truncated_instructions_are_data() {
+        let disassembly = disassemble(&[0x00, 0xC3, 0x50], 0x100);
+        assert_eq!(disassembly.len(), 2);
+        assert_eq!(disassembly[1].text, "db $C3, $50");
+        assert_eq!(disassembly[1].length(), 2);
+        assert!(disassembly[1].instruction.is_none());
+        assert_eq!(format!("{}", disassembly[0]), "0100  00        nop");
+    }
+}
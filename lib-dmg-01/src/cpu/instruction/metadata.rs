@@ -0,0 +1,336 @@
+use super::syntax::{syntax, Operand};
+use super::{
+    ArithmeticTarget, IncDecTarget, Indirect, Instruction, JumpTest, LoadByteSource,
+    LoadByteTarget, LoadType, PrefixTarget, StackTarget,
+};
+
+/// What an instruction does to one of the flags
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub enum FlagEffect {
+    /// The flag is left alone
+    Unaffected,
+    /// The flag is always set to 1
+    Set,
+    /// The flag is always set to 0
+    Reset,
+    /// The flag is set depending on the result
+    Affected,
+}
+
+impl FlagEffect {
+    /// The symbol used for the effect in opcode tables: `-`, `1`, `0` or `?`
+    pub fn symbol(&self) -> char {
+        match self {
+            FlagEffect::Unaffected => '-',
+            FlagEffect::Set => '1',
+            FlagEffect::Reset => '0',
+            FlagEffect::Affected => '?',
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct FlagEffects {
+    pub zero: FlagEffect,
+    pub subtract: FlagEffect,
+    pub half_carry: FlagEffect,
+    pub carry: FlagEffect,
+}
+
+/// Everything there is to know about an opcode short of executing it.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct OpcodeMetadata {
+    /// The opcode byte. For prefixed instructions this is the byte after 0xCB.
+    pub opcode: u8,
+    pub prefixed: bool,
+    #[cfg_attr(feature = "serialize", serde(skip_serializing))]
+    pub instruction: Instruction,
+    /// The instruction in RGBDS syntax with placeholders for its immediate operands
+    /// (e.g. `ld a, n8` or `jr nz, n16`)
+    pub mnemonic: String,
+    pub description: &'static str,
+    /// The number of bytes including any prefix and immediate operands
+    pub length: u16,
+    /// The number of clock cycles taken. For conditional jumps, calls and returns this
+    /// is the number of cycles when the condition holds.
+    pub cycles: u8,
+    /// For conditional jumps, calls and returns the number of clock cycles when the
+    /// condition does not hold
+    pub cycles_not_taken: Option<u8>,
+    pub flags: FlagEffects,
+}
+
+/// The metadata for an opcode. This is `None` only for the unprefixed 0xCB which is
+/// the prefix itself rather than an instruction.
+pub fn metadata(opcode: u8, prefixed: bool) -> Option<OpcodeMetadata> {
+    if opcode == 0xCB && !prefixed {
+        return None;
+    }
+    let instruction = Instruction::from_byte(opcode, prefixed)?;
+    let (cycles, cycles_not_taken) = cycles(instruction);
+    Some(OpcodeMetadata {
+        opcode,
+        prefixed,
+        instruction,
+        mnemonic: mnemonic(instruction),
+        description: description(instruction),
+        length: instruction.length(),
+        cycles,
+        cycles_not_taken,
+        flags: flags(instruction),
+    })
+}
+
+/// The metadata for every opcode: the unprefixed opcodes in order followed by the
+/// prefixed ones.
+pub fn metadata_table() -> Vec<OpcodeMetadata> {
+    let unprefixed = (0..=255u8).filter_map(|opcode| metadata(opcode, false));
+    let prefixed = (0..=255u8).filter_map(|opcode| metadata(opcode, true));
+    unprefixed.chain(prefixed).collect()
+}
+
+pub(super) fn mnemonic(instruction: Instruction) -> String {
+    if let Instruction::ILLEGAL(opcode) = instruction {
+        return format!("db ${:02X}", opcode);
+    }
+    let (mnemonic, operands) = syntax(instruction);
+    let operands: Vec<&str> = operands
+        .into_iter()
+        .map(|operand| match operand {
+            Operand::Fixed(text) => text,
+            Operand::Byte => "n8",
+            Operand::Word | Operand::Relative => "n16",
+            Operand::WordIndirect | Operand::HighIndirect => "[n16]",
+            Operand::SignedByte => "e8",
+            Operand::StackOffset => "sp+e8",
+        })
+        .collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+fn cycles(instruction: Instruction) -> (u8, Option<u8>) {
+    let conditional = |test, taken, not_taken| match test {
+        JumpTest::Always => (taken, None),
+        _ => (taken, Some(not_taken)),
+    };
+    let arithmetic = |target| match target {
+        ArithmeticTarget::D8 | ArithmeticTarget::HLI => 8,
+        _ => 4,
+    };
+    let prefix = |target| match target {
+        PrefixTarget::HLI => 16,
+        _ => 8,
+    };
+    let inc_dec = |target| match target {
+        IncDecTarget::HLI => 12,
+        IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP => 8,
+        _ => 4,
+    };
+
+    let cycles = match instruction {
+        Instruction::INC(target) | Instruction::DEC(target) => inc_dec(target),
+        Instruction::ADD(target)
+        | Instruction::ADC(target)
+        | Instruction::SUB(target)
+        | Instruction::SBC(target)
+        | Instruction::AND(target)
+        | Instruction::OR(target)
+        | Instruction::XOR(target)
+        | Instruction::CP(target) => arithmetic(target),
+        Instruction::ADDHL(_) => 8,
+        Instruction::ADDSP => 16,
+        Instruction::CCF
+        | Instruction::SCF
+        | Instruction::RRA
+        | Instruction::RLA
+        | Instruction::RRCA
+        | Instruction::RLCA
+        | Instruction::CPL
+        | Instruction::DAA => 4,
+
+        // BIT only reads (HL) where the others also have to write it back
+        Instruction::BIT(PrefixTarget::HLI, _) => 12,
+        Instruction::BIT(target, _)
+        | Instruction::RES(target, _)
+        | Instruction::SET(target, _)
+        | Instruction::SRL(target)
+        | Instruction::RR(target)
+        | Instruction::RL(target)
+        | Instruction::RRC(target)
+        | Instruction::RLC(target)
+        | Instruction::SRA(target)
+        | Instruction::SLA(target)
+        | Instruction::SWAP(target) => prefix(target),
+
+        Instruction::JP(test) => return conditional(test, 16, 12),
+        Instruction::JR(test) => return conditional(test, 12, 8),
+        Instruction::JPI => 4,
+
+        Instruction::LD(load_type) => match load_type {
+            LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8) => 12,
+            LoadType::Byte(LoadByteTarget::HLI, _)
+            | LoadType::Byte(_, LoadByteSource::D8)
+            | LoadType::Byte(_, LoadByteSource::HLI) => 8,
+            LoadType::Byte(_, _) => 4,
+            LoadType::Word(_) => 12,
+            LoadType::AFromIndirect(Indirect::WordIndirect)
+            | LoadType::IndirectFromA(Indirect::WordIndirect) => 16,
+            LoadType::AFromIndirect(_) | LoadType::IndirectFromA(_) => 8,
+            LoadType::AFromByteAddress | LoadType::ByteAddressFromA => 12,
+            LoadType::SPFromHL => 8,
+            LoadType::HLFromSPN => 12,
+            LoadType::IndirectFromSP => 20,
+        },
+
+        Instruction::PUSH(_) => 16,
+        Instruction::POP(_) => 12,
+        Instruction::CALL(test) => return conditional(test, 24, 12),
+        Instruction::RET(JumpTest::Always) => 16,
+        Instruction::RET(test) => return conditional(test, 20, 8),
+        Instruction::RETI => 16,
+        Instruction::RST(_) => 16,
+
+        Instruction::HALT
+        | Instruction::NOP
+        | Instruction::DI
+        | Instruction::EI
+        | Instruction::STOP
+        | Instruction::ILLEGAL(_) => 4,
+    };
+    (cycles, None)
+}
+
+fn flags(instruction: Instruction) -> FlagEffects {
+    use self::FlagEffect::{Affected as A, Reset as R, Set as S, Unaffected as U};
+    let effects = |zero, subtract, half_carry, carry| FlagEffects {
+        zero,
+        subtract,
+        half_carry,
+        carry,
+    };
+
+    match instruction {
+        Instruction::INC(IncDecTarget::BC)
+        | Instruction::INC(IncDecTarget::DE)
+        | Instruction::INC(IncDecTarget::HL)
+        | Instruction::INC(IncDecTarget::SP)
+        | Instruction::DEC(IncDecTarget::BC)
+        | Instruction::DEC(IncDecTarget::DE)
+        | Instruction::DEC(IncDecTarget::HL)
+        | Instruction::DEC(IncDecTarget::SP) => effects(U, U, U, U),
+        Instruction::INC(_) => effects(A, R, A, U),
+        Instruction::DEC(_) => effects(A, S, A, U),
+        Instruction::ADD(_) | Instruction::ADC(_) => effects(A, R, A, A),
+        Instruction::ADDHL(_) => effects(U, R, A, A),
+        Instruction::ADDSP => effects(R, R, A, A),
+        Instruction::SUB(_) | Instruction::SBC(_) | Instruction::CP(_) => effects(A, S, A, A),
+        Instruction::AND(_) => effects(A, R, S, R),
+        Instruction::OR(_) | Instruction::XOR(_) => effects(A, R, R, R),
+        Instruction::CCF => effects(U, R, R, A),
+        Instruction::SCF => effects(U, R, R, S),
+        Instruction::RRA | Instruction::RLA | Instruction::RRCA | Instruction::RLCA => {
+            effects(R, R, R, A)
+        }
+        Instruction::CPL => effects(U, S, S, U),
+        Instruction::DAA => effects(A, U, R, A),
+
+        Instruction::BIT(_, _) => effects(A, R, S, U),
+        Instruction::RES(_, _) | Instruction::SET(_, _) => effects(U, U, U, U),
+        Instruction::SWAP(_) => effects(A, R, R, R),
+        Instruction::SRL(_)
+        | Instruction::RR(_)
+        | Instruction::RL(_)
+        | Instruction::RRC(_)
+        | Instruction::RLC(_)
+        | Instruction::SRA(_)
+        | Instruction::SLA(_) => effects(A, R, R, A),
+
+        Instruction::LD(LoadType::HLFromSPN) => effects(R, R, A, A),
+        Instruction::POP(StackTarget::AF) => effects(A, A, A, A),
+        _ => effects(U, U, U, U),
+    }
+}
+
+fn description(instruction: Instruction) -> &'static str {
+    match instruction {
+        Instruction::INC(_) => "Increment the target by 1",
+        Instruction::DEC(_) => "Decrement the target by 1",
+        Instruction::ADD(_) => "Add the source to A",
+        Instruction::ADC(_) => "Add the source and the carry flag to A",
+        Instruction::ADDHL(_) => "Add the source to HL",
+        Instruction::ADDSP => "Add a signed byte to SP",
+        Instruction::SUB(_) => "Subtract the source from A",
+        Instruction::SBC(_) => "Subtract the source and the carry flag from A",
+        Instruction::AND(_) => "Bitwise and A with the source",
+        Instruction::OR(_) => "Bitwise or A with the source",
+        Instruction::XOR(_) => "Bitwise xor A with the source",
+        Instruction::CP(_) => "Subtract the source from A without storing the result",
+        Instruction::CCF => "Toggle the carry flag",
+        Instruction::SCF => "Set the carry flag",
+        Instruction::RRA => "Rotate A right through the carry flag",
+        Instruction::RLA => "Rotate A left through the carry flag",
+        Instruction::RRCA => "Rotate A right",
+        Instruction::RLCA => "Rotate A left",
+        Instruction::CPL => "Toggle every bit of A",
+        Instruction::DAA => "Adjust A to be binary coded decimal after an addition or subtraction",
+        Instruction::BIT(_, _) => "Test whether a bit of the target is set",
+        Instruction::RES(_, _) => "Set a bit of the target to 0",
+        Instruction::SET(_, _) => "Set a bit of the target to 1",
+        Instruction::SRL(_) => "Shift the target right, shifting in 0",
+        Instruction::RR(_) => "Rotate the target right through the carry flag",
+        Instruction::RL(_) => "Rotate the target left through the carry flag",
+        Instruction::RRC(_) => "Rotate the target right",
+        Instruction::RLC(_) => "Rotate the target left",
+        Instruction::SRA(_) => "Shift the target right, keeping the top bit",
+        Instruction::SLA(_) => "Shift the target left, shifting in 0",
+        Instruction::SWAP(_) => "Swap the upper and lower nibbles of the target",
+        Instruction::JP(_) => "Jump to an address if the condition holds",
+        Instruction::JR(_) => "Jump by a signed offset if the condition holds",
+        Instruction::JPI => "Jump to the address in HL",
+        Instruction::LD(_) => "Copy the source into the target",
+        Instruction::PUSH(_) => "Push a register pair onto the stack",
+        Instruction::POP(_) => "Pop a register pair off the stack",
+        Instruction::CALL(_) => {
+            "Push the address of the next instruction and jump if the condition holds"
+        }
+        Instruction::RET(_) => "Pop an address off the stack and jump to it if the condition holds",
+        Instruction::RETI => "Return and enable interrupts",
+        Instruction::RST(_) => "Call one of the fixed restart vectors",
+        Instruction::HALT => "Stop executing until an interrupt is pending",
+        Instruction::NOP => "Do nothing",
+        Instruction::DI => "Disable interrupts",
+        Instruction::EI => "Enable interrupts after the next instruction",
+        Instruction::STOP => "Stop the clock until a button is pressed",
+        Instruction::ILLEGAL(_) => "An unused opcode. Executing it locks up the CPU",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_every_opcode() {
+        let table = metadata_table();
+        assert_eq!(table.len(), 511);
+
+        let jr = metadata(0x20, false).unwrap();
+        assert_eq!(jr.mnemonic, "jr nz, n16");
+        assert_eq!(
+            (jr.length, jr.cycles, jr.cycles_not_taken),
+            (2, 12, Some(8))
+        );
+
+        let bit = metadata(0x46, true).unwrap();
+        assert_eq!(bit.mnemonic, "bit 0, [hl]");
+        assert_eq!((bit.length, bit.cycles), (2, 12));
+        assert_eq!(bit.flags.half_carry.symbol(), '1');
+    }
+}
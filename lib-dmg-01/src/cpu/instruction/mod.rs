@@ -1,5 +1,15 @@
 use std;
 
+mod disassembler;
+mod metadata;
+pub(crate) mod syntax;
+
+pub use self::disassembler::{
+    disassemble, disassemble_bus, disassemble_bus_with_log, disassemble_bus_with_symbols,
+    DisassembledInstruction,
+};
+pub use self::metadata::{metadata, metadata_table, FlagEffect, FlagEffects, OpcodeMetadata};
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum IncDecTarget {
     A,
@@ -232,10 +242,125 @@ pub enum Instruction {
     NOP,
     DI,
     EI,
+    STOP,
+
+    // One of the 11 unused opcodes which lock up the CPU when executed
+    ILLEGAL(u8),
+}
+
+static INSTRUCTIONS: [Option<Instruction>; 256] = decode_table(false);
+static PREFIXED_INSTRUCTIONS: [Option<Instruction>; 256] = decode_table(true);
+
+const fn decode_table(prefixed: bool) -> [Option<Instruction>; 256] {
+    let mut table = [None; 256];
+    let mut byte = 0;
+    while byte < table.len() {
+        table[byte] = Instruction::from_byte(byte as u8, prefixed);
+        byte += 1;
+    }
+    table
 }
 
 impl Instruction {
-    pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
+    /// Whether the instruction is encoded behind the 0xCB prefix byte
+    pub fn is_prefixed(&self) -> bool {
+        matches!(
+            self,
+            Instruction::BIT(_, _)
+                | Instruction::RES(_, _)
+                | Instruction::SET(_, _)
+                | Instruction::SRL(_)
+                | Instruction::RR(_)
+                | Instruction::RL(_)
+                | Instruction::RRC(_)
+                | Instruction::RLC(_)
+                | Instruction::SRA(_)
+                | Instruction::SLA(_)
+                | Instruction::SWAP(_)
+        )
+    }
+
+    /// The number of bytes the instruction takes up including its opcode, any prefix
+    /// and any immediate operands
+    pub fn length(&self) -> u16 {
+        match self {
+            _ if self.is_prefixed() => 2,
+            Instruction::ADD(ArithmeticTarget::D8)
+            | Instruction::ADC(ArithmeticTarget::D8)
+            | Instruction::SUB(ArithmeticTarget::D8)
+            | Instruction::SBC(ArithmeticTarget::D8)
+            | Instruction::AND(ArithmeticTarget::D8)
+            | Instruction::OR(ArithmeticTarget::D8)
+            | Instruction::XOR(ArithmeticTarget::D8)
+            | Instruction::CP(ArithmeticTarget::D8)
+            | Instruction::ADDSP
+            | Instruction::JR(_)
+            | Instruction::STOP
+            | Instruction::LD(LoadType::Byte(_, LoadByteSource::D8))
+            | Instruction::LD(LoadType::AFromByteAddress)
+            | Instruction::LD(LoadType::ByteAddressFromA)
+            | Instruction::LD(LoadType::HLFromSPN) => 2,
+            Instruction::JP(_)
+            | Instruction::CALL(_)
+            | Instruction::LD(LoadType::Word(_))
+            | Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect))
+            | Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect))
+            | Instruction::LD(LoadType::IndirectFromSP) => 3,
+            _ => 1,
+        }
+    }
+
+    /// Encodes the instruction into the bytes `from_byte` decodes it from (including any
+    /// 0xCB prefix). `operand` fills in the immediate operand if the instruction has one:
+    /// 8 bit immediates (including the signed offsets of JR, `add sp` and `ld hl, sp+`)
+    /// take its low byte and 16 bit immediates take all of it, little endian.
+    ///
+    /// Panics for instructions that no opcode decodes to such as `LD [hl], [hl]`.
+    pub fn encode(&self, operand: u16) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3);
+        if self.is_prefixed() {
+            bytes.push(0xCB);
+        }
+        bytes.push(self.opcode());
+        if *self == Instruction::STOP {
+            // STOP has no operand but is always followed by a padding byte
+            bytes.push(0x00);
+        }
+        if bytes.len() < self.length() as usize {
+            bytes.push(operand as u8);
+        }
+        if bytes.len() < self.length() as usize {
+            bytes.push((operand >> 8) as u8);
+        }
+        bytes
+    }
+
+    /// The opcode that decodes to this instruction. For prefixed instructions this is
+    /// the byte following the 0xCB prefix.
+    pub fn opcode(&self) -> u8 {
+        if let Instruction::ILLEGAL(opcode) = *self {
+            return opcode;
+        }
+        let prefixed = self.is_prefixed();
+        (0..=255u8)
+            .filter(|byte| prefixed || *byte != 0xCB)
+            .find(|byte| Instruction::from_byte(*byte, prefixed) == Some(*self))
+            .unwrap_or_else(|| panic!("{:?} has no opcode", self))
+    }
+
+    /// Decodes an opcode using tables built from `from_byte` at compile time. This gives
+    /// exactly the same result as `from_byte` but is a single lookup rather than a match
+    /// on every instruction executed.
+    #[inline]
+    pub fn decode(byte: u8, prefixed: bool) -> Option<Instruction> {
+        if prefixed {
+            PREFIXED_INSTRUCTIONS[byte as usize]
+        } else {
+            INSTRUCTIONS[byte as usize]
+        }
+    }
+
+    pub const fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
             Instruction::from_byte_prefixed(byte)
         } else {
@@ -243,7 +368,7 @@ impl Instruction {
         }
     }
 
-    fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
+    const fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
         match byte {
             0x00 => Some(Instruction::RLC(PrefixTarget::B)),
             0x01 => Some(Instruction::RLC(PrefixTarget::C)),
@@ -535,7 +660,7 @@ impl Instruction {
         }
     }
 
-    fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
+    const fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
         match byte {
             0x3c => Some(Instruction::INC(IncDecTarget::A)),
             0x04 => Some(Instruction::INC(IncDecTarget::B)),
@@ -1053,8 +1178,169 @@ impl Instruction {
             0x76 => Some(Instruction::HALT),
             0xf3 => Some(Instruction::DI),
             0xfb => Some(Instruction::EI),
+            0x10 => Some(Instruction::STOP),
+
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                Some(Instruction::ILLEGAL(byte))
+            }
 
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_decodes() {
+        for byte in 0..=255u8 {
+            assert!(
+                Instruction::from_byte(byte, true).is_some(),
+                "0xcb{:02x} did not decode",
+                byte
+            );
+            // 0xCB is not an instruction of its own but the prefix for the other table
+            if byte != 0xCB {
+                assert!(
+                    Instruction::from_byte(byte, false).is_some(),
+                    "0x{:02x} did not decode",
+                    byte
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_tables_match_from_byte() {
+        for &prefixed in [false, true].iter() {
+            for byte in 0..=255u8 {
+                assert_eq!(
+                    Instruction::decode(byte, prefixed),
+                    Instruction::from_byte(byte, prefixed),
+                    "opcode {:02X} prefixed: {}",
+                    byte,
+                    prefixed
+                );
+            }
+        }
+    }
+
+    // The opcode chart written out by hand rather than derived from `from_byte`: the
+    // irregular first and last quarters of the unprefixed page as a table and the
+    // blocks laid out by register from the bits of the opcode
+    fn chart(opcode: u8, prefixed: bool) -> String {
+        #[rustfmt::skip]
+        const FIRST_QUARTER: [&str; 64] = [
+            "nop",          "ld bc, n16",   "ld [bc], a",   "inc bc",
+            "inc b",        "dec b",        "ld b, n8",     "rlca",
+            "ld [n16], sp", "add hl, bc",   "ld a, [bc]",   "dec bc",
+            "inc c",        "dec c",        "ld c, n8",     "rrca",
+            "stop",         "ld de, n16",   "ld [de], a",   "inc de",
+            "inc d",        "dec d",        "ld d, n8",     "rla",
+            "jr n16",       "add hl, de",   "ld a, [de]",   "dec de",
+            "inc e",        "dec e",        "ld e, n8",     "rra",
+            "jr nz, n16",   "ld hl, n16",   "ld [hl+], a",  "inc hl",
+            "inc h",        "dec h",        "ld h, n8",     "daa",
+            "jr z, n16",    "add hl, hl",   "ld a, [hl+]",  "dec hl",
+            "inc l",        "dec l",        "ld l, n8",     "cpl",
+            "jr nc, n16",   "ld sp, n16",   "ld [hl-], a",  "inc sp",
+            "inc [hl]",     "dec [hl]",     "ld [hl], n8",  "scf",
+            "jr c, n16",    "add hl, sp",   "ld a, [hl-]",  "dec sp",
+            "inc a",        "dec a",        "ld a, n8",     "ccf",
+        ];
+        #[rustfmt::skip]
+        const LAST_QUARTER: [&str; 64] = [
+            "ret nz",       "pop bc",       "jp nz, n16",   "jp n16",
+            "call nz, n16", "push bc",      "add a, n8",    "rst $00",
+            "ret z",        "ret",          "jp z, n16",    "",
+            "call z, n16",  "call n16",     "adc a, n8",    "rst $08",
+            "ret nc",       "pop de",       "jp nc, n16",   "db $D3",
+            "call nc, n16", "push de",      "sub a, n8",    "rst $10",
+            "ret c",        "reti",         "jp c, n16",    "db $DB",
+            "call c, n16",  "db $DD",       "sbc a, n8",    "rst $18",
+            "ldh [n16], a", "pop hl",       "ldh [c], a",   "db $E3",
+            "db $E4",       "push hl",      "and a, n8",    "rst $20",
+            "add sp, e8",   "jp hl",        "ld [n16], a",  "db $EB",
+            "db $EC",       "db $ED",       "xor a, n8",    "rst $28",
+            "ldh a, [n16]", "pop af",       "ldh a, [c]",   "di",
+            "db $F4",       "push af",      "or a, n8",     "rst $30",
+            "ld hl, sp+e8", "ld sp, hl",    "ld a, [n16]",  "ei",
+            "db $FC",       "db $FD",       "cp a, n8",     "rst $38",
+        ];
+        const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+        const ARITHMETIC: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+        const SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+        const BITS: [&str; 4] = ["", "bit", "res", "set"];
+
+        let register = REGISTERS[(opcode & 0x07) as usize];
+        let middle = (opcode >> 3) & 0x07;
+        match (prefixed, opcode >> 6) {
+            (false, 0) => FIRST_QUARTER[opcode as usize].to_string(),
+            (false, 1) if opcode == 0x76 => "halt".to_string(),
+            (false, 1) => format!("ld {}, {}", REGISTERS[middle as usize], register),
+            (false, 2) => format!("{} a, {}", ARITHMETIC[middle as usize], register),
+            (false, _) => LAST_QUARTER[(opcode - 0xC0) as usize].to_string(),
+            (true, 0) => format!("{} {}", SHIFTS[middle as usize], register),
+            (true, block) => format!("{} {}, {}", BITS[block as usize], middle, register),
+        }
+    }
+
+    #[test]
+    fn decoding_tables_match_the_opcode_chart() {
+        for &prefixed in [false, true].iter() {
+            for opcode in 0..=255u8 {
+                let decoded = Instruction::decode(opcode, prefixed);
+                if opcode == 0xCB && !prefixed {
+                    assert_eq!(decoded, None);
+                    continue;
+                }
+                let decoded = decoded.map(super::metadata::mnemonic);
+                assert_eq!(
+                    decoded.as_deref(),
+                    Some(chart(opcode, prefixed).as_str()),
+                    "opcode {:02X} prefixed: {}",
+                    opcode,
+                    prefixed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encode_is_the_inverse_of_from_byte() {
+        for byte in 0..=255u8 {
+            if byte != 0xCB {
+                let instruction = Instruction::from_byte(byte, false).unwrap();
+                let bytes = instruction.encode(0x1234);
+                assert_eq!(bytes[0], byte);
+                assert_eq!(bytes.len(), instruction.length() as usize);
+            }
+            let instruction = Instruction::from_byte(byte, true).unwrap();
+            assert_eq!(instruction.encode(0), vec![0xCB, byte]);
+        }
+        assert_eq!(
+            Instruction::LD(LoadType::Word(LoadWordTarget::HL)).encode(0xC000),
+            vec![0x21, 0x00, 0xC0]
+        );
+        assert_eq!(Instruction::STOP.encode(0x12), vec![0x10, 0x00]);
+    }
+
+    #[test]
+    fn unused_opcodes_are_illegal() {
+        let illegal: Vec<u8> = (0..=255u8)
+            .filter(|byte| *byte != 0xCB)
+            .filter(|byte| {
+                matches!(
+                    Instruction::from_byte(*byte, false),
+                    Some(Instruction::ILLEGAL(_))
+                )
+            })
+            .collect();
+        assert_eq!(
+            illegal,
+            vec![0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use super::{
+    ADDHLTarget, ArithmeticTarget, BitPosition, IncDecTarget, Indirect, Instruction, JumpTest,
+    LoadByteSource, LoadByteTarget, LoadType, LoadWordTarget, PrefixTarget, RSTLocation,
+    StackTarget,
+};
+
+/// How one operand of an instruction is written in RGBDS syntax. This is shared by the
+/// disassembler (which fills the operands in) and the assembler (which parses them).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    /// Text that is always the same such as a register (`a`, `[hl+]`), a condition
+    /// (`nz`), a bit number or an RST vector
+    Fixed(&'static str),
+    /// An 8 bit immediate (`n8`)
+    Byte,
+    /// A 16 bit immediate (`n16`)
+    Word,
+    /// Memory at a 16 bit immediate address (`[n16]`)
+    WordIndirect,
+    /// Memory at 0xFF00 plus an 8 bit immediate (`[$FF00 + n8]`)
+    HighIndirect,
+    /// A jump target stored as a signed offset from the next instruction
+    Relative,
+    /// A signed 8 bit immediate (`e8`)
+    SignedByte,
+    /// The stack pointer plus a signed 8 bit immediate (`sp + e8`)
+    StackOffset,
+}
+
+/// The mnemonic and operands of `instruction`.
+///
+/// Illegal opcodes have no mnemonic of their own and are written as `db` data.
+pub fn syntax(instruction: Instruction) -> (&'static str, Vec<Operand>) {
+    use self::Operand::*;
+
+    let arithmetic = |mnemonic, target| match target {
+        ArithmeticTarget::D8 => (mnemonic, vec![Fixed("a"), Byte]),
+        target => (mnemonic, vec![Fixed("a"), Fixed(arithmetic_target(target))]),
+    };
+    let prefix = |mnemonic, target| (mnemonic, vec![Fixed(prefix_target(target))]);
+    let bit = |mnemonic, target, position| {
+        (
+            mnemonic,
+            vec![Fixed(bit_position(position)), Fixed(prefix_target(target))],
+        )
+    };
+    let conditional = |mnemonic, test, operand| match test {
+        JumpTest::Always => (mnemonic, vec![operand]),
+        test => (mnemonic, vec![Fixed(condition(test)), operand]),
+    };
+
+    match instruction {
+        Instruction::INC(target) => ("inc", vec![Fixed(inc_dec_target(target))]),
+        Instruction::DEC(target) => ("dec", vec![Fixed(inc_dec_target(target))]),
+        Instruction::ADD(target) => arithmetic("add", target),
+        Instruction::ADC(target) => arithmetic("adc", target),
+        Instruction::ADDHL(target) => ("add", vec![Fixed("hl"), Fixed(add_hl_target(target))]),
+        Instruction::ADDSP => ("add", vec![Fixed("sp"), SignedByte]),
+        Instruction::SUB(target) => arithmetic("sub", target),
+        Instruction::SBC(target) => arithmetic("sbc", target),
+        Instruction::AND(target) => arithmetic("and", target),
+        Instruction::OR(target) => arithmetic("or", target),
+        Instruction::XOR(target) => arithmetic("xor", target),
+        Instruction::CP(target) => arithmetic("cp", target),
+        Instruction::CCF => ("ccf", vec![]),
+        Instruction::SCF => ("scf", vec![]),
+        Instruction::RRA => ("rra", vec![]),
+        Instruction::RLA => ("rla", vec![]),
+        Instruction::RRCA => ("rrca", vec![]),
+        Instruction::RLCA => ("rlca", vec![]),
+        Instruction::CPL => ("cpl", vec![]),
+        Instruction::DAA => ("daa", vec![]),
+
+        Instruction::BIT(target, position) => bit("bit", target, position),
+        Instruction::RES(target, position) => bit("res", target, position),
+        Instruction::SET(target, position) => bit("set", target, position),
+        Instruction::SRL(target) => prefix("srl", target),
+        Instruction::RR(target) => prefix("rr", target),
+        Instruction::RL(target) => prefix("rl", target),
+        Instruction::RRC(target) => prefix("rrc", target),
+        Instruction::RLC(target) => prefix("rlc", target),
+        Instruction::SRA(target) => prefix("sra", target),
+        Instruction::SLA(target) => prefix("sla", target),
+        Instruction::SWAP(target) => prefix("swap", target),
+
+        Instruction::JP(test) => conditional("jp", test, Word),
+        Instruction::JR(test) => conditional("jr", test, Relative),
+        Instruction::JPI => ("jp", vec![Fixed("hl")]),
+
+        Instruction::LD(load_type) => match load_type {
+            LoadType::Byte(target, LoadByteSource::D8) => {
+                ("ld", vec![Fixed(load_byte_target(target)), Byte])
+            }
+            LoadType::Byte(target, source) => (
+                "ld",
+                vec![
+                    Fixed(load_byte_target(target)),
+                    Fixed(load_byte_source(source)),
+                ],
+            ),
+            LoadType::Word(target) => ("ld", vec![Fixed(load_word_target(target)), Word]),
+            LoadType::AFromIndirect(Indirect::LastByteIndirect) => {
+                ("ldh", vec![Fixed("a"), Fixed("[c]")])
+            }
+            LoadType::IndirectFromA(Indirect::LastByteIndirect) => {
+                ("ldh", vec![Fixed("[c]"), Fixed("a")])
+            }
+            LoadType::AFromIndirect(source) => ("ld", vec![Fixed("a"), indirect(source)]),
+            LoadType::IndirectFromA(target) => ("ld", vec![indirect(target), Fixed("a")]),
+            LoadType::AFromByteAddress => ("ldh", vec![Fixed("a"), HighIndirect]),
+            LoadType::ByteAddressFromA => ("ldh", vec![HighIndirect, Fixed("a")]),
+            LoadType::SPFromHL => ("ld", vec![Fixed("sp"), Fixed("hl")]),
+            LoadType::HLFromSPN => ("ld", vec![Fixed("hl"), StackOffset]),
+            LoadType::IndirectFromSP => ("ld", vec![WordIndirect, Fixed("sp")]),
+        },
+
+        Instruction::PUSH(target) => ("push", vec![Fixed(stack_target(target))]),
+        Instruction::POP(target) => ("pop", vec![Fixed(stack_target(target))]),
+        Instruction::CALL(test) => conditional("call", test, Word),
+        Instruction::RET(JumpTest::Always) => ("ret", vec![]),
+        Instruction::RET(test) => ("ret", vec![Fixed(condition(test))]),
+        Instruction::RETI => ("reti", vec![]),
+        Instruction::RST(location) => ("rst", vec![Fixed(rst_location(location))]),
+
+        Instruction::HALT => ("halt", vec![]),
+        Instruction::NOP => ("nop", vec![]),
+        Instruction::DI => ("di", vec![]),
+        Instruction::EI => ("ei", vec![]),
+        Instruction::STOP => ("stop", vec![]),
+        Instruction::ILLEGAL(_) => ("db", vec![]),
+    }
+}
+
+fn condition(test: JumpTest) -> &'static str {
+    match test {
+        JumpTest::NotZero => "nz",
+        JumpTest::NotCarry => "nc",
+        JumpTest::Zero => "z",
+        JumpTest::Carry => "c",
+        JumpTest::Always => "",
+    }
+}
+
+fn inc_dec_target(target: IncDecTarget) -> &'static str {
+    match target {
+        IncDecTarget::A => "a",
+        IncDecTarget::B => "b",
+        IncDecTarget::C => "c",
+        IncDecTarget::D => "d",
+        IncDecTarget::E => "e",
+        IncDecTarget::H => "h",
+        IncDecTarget::L => "l",
+        IncDecTarget::HLI => "[hl]",
+        IncDecTarget::BC => "bc",
+        IncDecTarget::DE => "de",
+        IncDecTarget::HL => "hl",
+        IncDecTarget::SP => "sp",
+    }
+}
+
+fn arithmetic_target(target: ArithmeticTarget) -> &'static str {
+    match target {
+        ArithmeticTarget::A => "a",
+        ArithmeticTarget::B => "b",
+        ArithmeticTarget::C => "c",
+        ArithmeticTarget::D => "d",
+        ArithmeticTarget::E => "e",
+        ArithmeticTarget::H => "h",
+        ArithmeticTarget::L => "l",
+        ArithmeticTarget::HLI => "[hl]",
+        ArithmeticTarget::D8 => "n8",
+    }
+}
+
+fn add_hl_target(target: ADDHLTarget) -> &'static str {
+    match target {
+        ADDHLTarget::BC => "bc",
+        ADDHLTarget::DE => "de",
+        ADDHLTarget::HL => "hl",
+        ADDHLTarget::SP => "sp",
+    }
+}
+
+fn prefix_target(target: PrefixTarget) -> &'static str {
+    match target {
+        PrefixTarget::A => "a",
+        PrefixTarget::B => "b",
+        PrefixTarget::C => "c",
+        PrefixTarget::D => "d",
+        PrefixTarget::E => "e",
+        PrefixTarget::H => "h",
+        PrefixTarget::L => "l",
+        PrefixTarget::HLI => "[hl]",
+    }
+}
+
+fn bit_position(position: BitPosition) -> &'static str {
+    match position {
+        BitPosition::B0 => "0",
+        BitPosition::B1 => "1",
+        BitPosition::B2 => "2",
+        BitPosition::B3 => "3",
+        BitPosition::B4 => "4",
+        BitPosition::B5 => "5",
+        BitPosition::B6 => "6",
+        BitPosition::B7 => "7",
+    }
+}
+
+fn rst_location(location: RSTLocation) -> &'static str {
+    match location {
+        RSTLocation::X00 => "$00",
+        RSTLocation::X08 => "$08",
+        RSTLocation::X10 => "$10",
+        RSTLocation::X18 => "$18",
+        RSTLocation::X20 => "$20",
+        RSTLocation::X28 => "$28",
+        RSTLocation::X30 => "$30",
+        RSTLocation::X38 => "$38",
+    }
+}
+
+fn load_byte_target(target: LoadByteTarget) -> &'static str {
+    match target {
+        LoadByteTarget::A => "a",
+        LoadByteTarget::B => "b",
+        LoadByteTarget::C => "c",
+        LoadByteTarget::D => "d",
+        LoadByteTarget::E => "e",
+        LoadByteTarget::H => "h",
+        LoadByteTarget::L => "l",
+        LoadByteTarget::HLI => "[hl]",
+    }
+}
+
+fn load_byte_source(source: LoadByteSource) -> &'static str {
+    match source {
+        LoadByteSource::A => "a",
+        LoadByteSource::B => "b",
+        LoadByteSource::C => "c",
+        LoadByteSource::D => "d",
+        LoadByteSource::E => "e",
+        LoadByteSource::H => "h",
+        LoadByteSource::L => "l",
+        LoadByteSource::HLI => "[hl]",
+        LoadByteSource::D8 => "n8",
+    }
+}
+
+fn load_word_target(target: LoadWordTarget) -> &'static str {
+    match target {
+        LoadWordTarget::BC => "bc",
+        LoadWordTarget::DE => "de",
+        LoadWordTarget::HL => "hl",
+        LoadWordTarget::SP => "sp",
+    }
+}
+
+fn indirect(indirect: Indirect) -> Operand {
+    match indirect {
+        Indirect::BCIndirect => Operand::Fixed("[bc]"),
+        Indirect::DEIndirect => Operand::Fixed("[de]"),
+        Indirect::HLIndirectMinus => Operand::Fixed("[hl-]"),
+        Indirect::HLIndirectPlus => Operand::Fixed("[hl+]"),
+        Indirect::WordIndirect => Operand::WordIndirect,
+        Indirect::LastByteIndirect => Operand::Fixed("[c]"),
+    }
+}
+
+fn stack_target(target: StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl",
+    }
+}
@@ -1,14 +1,40 @@
+mod call_stack;
 pub mod flags_register;
 pub mod instruction;
+mod profile;
 pub mod registers;
+mod trace;
 
+use self::call_stack::CallStack;
+pub use self::call_stack::{Backtrace, CallFrame, CallKind, StackAnomaly};
 use self::instruction::{
     ADDHLTarget, ArithmeticTarget, BitPosition, IncDecTarget, Indirect, Instruction, JumpTest,
     LoadByteSource, LoadByteTarget, LoadType, LoadWordTarget, PrefixTarget, StackTarget,
 };
+pub use self::profile::{AddressProfile, FunctionProfile, InterruptProfile, Profiler};
 use self::registers::Registers;
+pub use self::trace::Tracer;
 
-use crate::memory_bus::{MemoryBus, LCDSTAT_VECTOR, TIMER_VECTOR, VBLANK_VECTOR};
+use crate::bus::{Bus, INTERRUPT_ENABLE, INTERRUPT_FLAG};
+use crate::debugger::Breakpoints;
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::symbols::Symbols;
+
+use crate::memory_bus::{
+    MemoryBus, HEADER_CHECKSUM_ADDRESS, JOYPAD_VECTOR, LCDSTAT_VECTOR, SERIAL_VECTOR, TIMER_VECTOR,
+    VBLANK_VECTOR,
+};
+
+// The interrupt handlers in order of priority which is also the order of their bits
+// in IE and IF
+const INTERRUPT_VECTORS: [u16; 5] = [
+    VBLANK_VECTOR,
+    LCDSTAT_VECTOR,
+    TIMER_VECTOR,
+    SERIAL_VECTOR,
+    JOYPAD_VECTOR,
+];
 
 /// # Macros
 ///
@@ -125,14 +151,13 @@ macro_rules! arithmetic_instruction {
                     $self.$work(value);
                 }
                 ArithmeticTarget::HLI => {
-                    let value = $self.bus.read_byte($self.registers.get_hl());
+                    let value = $self.bus.read_cycle($self.registers.get_hl());
                     $self.$work(value);
                 }
             };
             match $register {
-                ArithmeticTarget::D8  => ($self.pc.wrapping_add(2), 8),
-                ArithmeticTarget::HLI => ($self.pc.wrapping_add(1), 8),
-                _                     => ($self.pc.wrapping_add(1), 4)
+                ArithmeticTarget::D8 => $self.pc.wrapping_add(2),
+                _                    => $self.pc.wrapping_add(1)
             }
         }
     };
@@ -162,15 +187,14 @@ macro_rules! arithmetic_instruction {
                     $self.registers.a = result;
                 }
                 ArithmeticTarget::HLI => {
-                    let value = $self.bus.read_byte($self.registers.get_hl());
+                    let value = $self.bus.read_cycle($self.registers.get_hl());
                     let result = $self.$work(value);
                     $self.registers.a = result;
                 }
             };
             match $register {
-                ArithmeticTarget::D8  => ($self.pc.wrapping_add(2), 8),
-                ArithmeticTarget::HLI => ($self.pc.wrapping_add(1), 8),
-                _                     => ($self.pc.wrapping_add(1), 4)
+                ArithmeticTarget::D8 => $self.pc.wrapping_add(2),
+                _                    => $self.pc.wrapping_add(1)
             }
         }
     };
@@ -199,16 +223,12 @@ macro_rules! prefix_instruction {
                 PrefixTarget::L => manipulate_8bit_register!($self: l => $work => l),
                 PrefixTarget::HLI => {
                     let hl = $self.registers.get_hl();
-                    let value = $self.bus.read_byte(hl);
+                    let value = $self.bus.read_cycle(hl);
                     let result = $self.$work(value);
-                    $self.bus.write_byte(hl, result);
+                    $self.bus.write_cycle(hl, result);
                 }
             }
-            let cycles = match $register {
-                PrefixTarget::HLI => 16,
-                _                 => 8
-            };
-            ($self.pc.wrapping_add(2), cycles)
+            $self.pc.wrapping_add(2)
         }
     };
     // Macro pattern for matching a register and then manipulating the register at a specific bit
@@ -233,16 +253,12 @@ macro_rules! prefix_instruction {
                 PrefixTarget::L => manipulate_8bit_register!($self: (l @ $bit_position) => $work => l),
                 PrefixTarget::HLI => {
                     let hl = $self.registers.get_hl();
-                    let value = $self.bus.read_byte(hl);
+                    let value = $self.bus.read_cycle(hl);
                     let result = $self.$work(value, $bit_position);
-                    $self.bus.write_byte(hl, result);
+                    $self.bus.write_cycle(hl, result);
                 }
             }
-            let cycles = match $register {
-                PrefixTarget::HLI => 16,
-                _                 => 8
-            };
-            ($self.pc.wrapping_add(2), cycles)
+            $self.pc.wrapping_add(2)
         }
     };
     // Macro pattern for matching a register and then manipulating the register at a specific bit
@@ -266,51 +282,262 @@ macro_rules! prefix_instruction {
                 PrefixTarget::H => manipulate_8bit_register!($self: (h @ $bit_position) => $work),
                 PrefixTarget::L => manipulate_8bit_register!($self: (l @ $bit_position) => $work),
                 PrefixTarget::HLI => {
-                    let value = $self.bus.read_byte($self.registers.get_hl());
+                    let value = $self.bus.read_cycle($self.registers.get_hl());
                     $self.$work(value, $bit_position);
                 }
             }
-            let cycles = match $register {
-                PrefixTarget::HLI => 16,
-                _                 => 8
-            };
-            ($self.pc.wrapping_add(2), cycles)
+            $self.pc.wrapping_add(2)
         }
     };
 }
 
+// LD B,B does nothing so emulators and test ROMs use it as a breakpoint
+const SOFTWARE_BREAKPOINT: Instruction =
+    Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::B));
+
+/// The Sharp SM83 CPU at the heart of the Game Boy.
+///
+/// The CPU can be connected to any `Bus` though it's normally the Game Boy's own
+/// `MemoryBus`.
 #[cfg_attr(feature = "serialize", derive(Serialize))]
-pub struct CPU {
+pub struct CPU<B = MemoryBus> {
     pub registers: Registers,
-    pc: u16,
-    sp: u16,
-    pub bus: MemoryBus,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: B,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub breakpoints: Breakpoints,
+    /// Labels the debugger, disassembler and tracer show in place of addresses
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub symbols: Symbols,
     is_halted: bool,
+    is_stopped: bool,
+    is_locked: bool,
+    halt_bug: bool,
     interrupts_enabled: bool,
+    enabling_interrupts: bool,
+    software_breakpoints: bool,
+    hit_software_breakpoint: bool,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    tracer: Option<Tracer>,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    profiler: Option<Profiler>,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    call_stack: CallStack,
 }
 
 impl CPU {
     pub fn new(boot_rom: Option<Vec<u8>>, game_rom: Vec<u8>) -> CPU {
+        CPU::with_model(boot_rom, game_rom, Model::default())
+    }
+
+    /// Creates a CPU emulating a specific hardware `model`.
+    ///
+    /// Without a boot ROM the CPU starts at 0x100 with its registers (and the I/O
+    /// registers) set to the values that model's boot ROM leaves behind.
+    pub fn with_model(boot_rom: Option<Vec<u8>>, game_rom: Vec<u8>, model: Model) -> CPU {
+        let skip_boot_rom = boot_rom.is_none();
+        let header_checksum = game_rom.get(HEADER_CHECKSUM_ADDRESS).copied();
+        let mut cpu = CPU::with_bus(MemoryBus::new(boot_rom, game_rom));
+        if skip_boot_rom {
+            cpu.set_post_boot_state(model, header_checksum);
+        }
+        cpu
+    }
+
+    fn set_post_boot_state(&mut self, model: Model, header_checksum: Option<u8>) {
+        // The boot ROM finishes by comparing the header checksum which leaves the
+        // half carry and carry flags set unless the checksum happens to be 0. A ROM
+        // too short to have a header gets the flags most games leave.
+        let checksum_flags = if header_checksum == Some(0) {
+            0x80
+        } else {
+            0xB0
+        };
+        let (af, bc, de, hl) = match model {
+            Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            Model::DMG => (0x0100 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::MGB => (0xFF00 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+        };
+        self.registers.set_af(af);
+        self.registers.set_bc(bc);
+        self.registers.set_de(de);
+        self.registers.set_hl(hl);
+        self.pc = 0x0100;
+        self.sp = 0xFFFE;
+        self.bus.set_post_boot_state(model);
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Creates a CPU connected to `bus` in its power on state: every register is 0
+    /// and execution starts at address 0.
+    pub fn with_bus(bus: B) -> CPU<B> {
         CPU {
             registers: Registers::new(),
             pc: 0x0,
             sp: 0x00,
-            bus: MemoryBus::new(boot_rom, game_rom),
+            bus,
+            breakpoints: Breakpoints::default(),
+            symbols: Symbols::default(),
             is_halted: false,
+            is_stopped: false,
+            is_locked: false,
+            halt_bug: false,
             interrupts_enabled: true,
+            enabling_interrupts: false,
+            software_breakpoints: false,
+            hit_software_breakpoint: false,
+            tracer: None,
+            profiler: None,
+            call_stack: CallStack::default(),
         }
     }
 
+    /// Whether interrupts are enabled (IME)
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.interrupts_enabled = enabled;
+        self.enabling_interrupts = false;
+    }
+
+    /// Whether the CPU is waiting in HALT for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    /// Whether the CPU has hard-locked by executing one of the illegal opcodes.
+    /// Once locked the CPU never executes another instruction though the rest of
+    /// the machine keeps running.
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Makes `LD B,B` act as a software breakpoint. The instruction still does nothing
+    /// but `hit_software_breakpoint` reports when it runs. Test ROMs such as Mooneye's
+    /// use it to signal that they have finished.
+    pub fn set_software_breakpoints(&mut self, enabled: bool) {
+        self.software_breakpoints = enabled;
+    }
+
+    /// Whether the last call to `step` executed an `LD B,B` while software breakpoints
+    /// are enabled.
+    pub fn hit_software_breakpoint(&self) -> bool {
+        self.hit_software_breakpoint
+    }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        let r = &self.registers;
+        for register in &[r.a, r.b, r.c, r.d, r.e, r.h, r.l, u8::from(r.f)] {
+            writer.u8(*register);
+        }
+        writer.u16(self.pc);
+        writer.u16(self.sp);
+        writer.bool(self.is_halted);
+        writer.bool(self.is_stopped);
+        writer.bool(self.is_locked);
+        writer.bool(self.halt_bug);
+        writer.bool(self.interrupts_enabled);
+        writer.bool(self.enabling_interrupts);
+        self.call_stack.write_state(writer);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let r = &mut self.registers;
+        r.a = reader.u8()?;
+        r.b = reader.u8()?;
+        r.c = reader.u8()?;
+        r.d = reader.u8()?;
+        r.e = reader.u8()?;
+        r.h = reader.u8()?;
+        r.l = reader.u8()?;
+        r.f = reader.u8()?.into();
+        self.pc = reader.u16()?;
+        self.sp = reader.u16()?;
+        self.is_halted = reader.bool()?;
+        self.is_stopped = reader.bool()?;
+        self.is_locked = reader.bool()?;
+        self.halt_bug = reader.bool()?;
+        self.interrupts_enabled = reader.bool()?;
+        self.enabling_interrupts = reader.bool()?;
+        self.call_stack.read_state(reader)
+    }
+
     pub fn step(&mut self) -> u8 {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
+        if self.profiler.is_none() {
+            return self.step_instruction();
+        }
+        let pc = self.pc;
+        let cycles = self.step_instruction();
+        self.profile(pc);
+        cycles
+    }
+
+    fn step_instruction(&mut self) -> u8 {
+        self.hit_software_breakpoint = false;
+        if self.is_locked {
+            self.bus.tick();
+            return 4;
+        }
+        if self.is_stopped {
+            // Pressing a button on one of the selected joypad lines pulls that line
+            // low and wakes the CPU back up. Until then the clock is stopped so
+            // nothing else in the machine moves either.
+            if (self.bus.peek_byte(0xFF00) & 0b1111) == 0b1111 {
+                return 4;
+            }
+            self.is_stopped = false;
+        }
+
+        if self.interrupts_enabled && self.bus.has_interrupt() {
+            return self.interrupt();
+        }
+        if self.is_halted {
+            // A pending interrupt ends HALT even when interrupts are disabled. In that
+            // case execution simply continues after the HALT instruction.
+            if !self.bus.has_interrupt() {
+                self.bus.tick();
+                return 4;
+            }
+            self.is_halted = false;
+        }
+
+        // EI only takes effect once the instruction following it has executed
+        let enable_interrupts = self.enabling_interrupts;
+        // The HALT bug: the CPU fails to increment the PC after fetching the opcode
+        // following HALT so that byte gets read twice.
+        let halt_bug = self.halt_bug;
+        self.halt_bug = false;
+
+        if self.tracer.is_some() {
+            self.trace();
+        }
+
+        // The instruction is only peeked at here. Fetching it takes time which is
+        // accounted for by `execute`.
+        let mut instruction_byte = self.bus.peek_byte(self.pc);
 
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.read_next_byte();
+            instruction_byte = if halt_bug {
+                instruction_byte
+            } else {
+                self.bus.peek_byte(self.pc.wrapping_add(1))
+            };
+        }
+        if halt_bug {
+            // Everything after the opcode is now read one byte too early
+            self.pc = self.pc.wrapping_sub(1);
         }
 
-        let (next_pc, mut cycles) =
-            if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
+        let (next_pc, cycles) =
+            if let Some(instruction) = Instruction::decode(instruction_byte, prefixed) {
+                if self.software_breakpoints && instruction == SOFTWARE_BREAKPOINT {
+                    self.hit_software_breakpoint = true;
+                }
                 self.execute(instruction)
             } else {
                 let description = format!(
@@ -319,52 +546,71 @@ impl CPU {
                     instruction_byte
                 );
                 panic!(
-                    "0x{:x}: Unknown instruction found - {}",
-                    self.pc, description
+                    "0x{:x}: Unknown instruction found - {}\n{}",
+                    self.pc,
+                    description,
+                    self.backtrace()
                 )
             };
 
-        self.bus.step(cycles);
+        self.pc = next_pc;
 
-        if self.bus.has_interrupt() {
-            self.is_halted = false;
-        }
-        if !self.is_halted {
-            self.pc = next_pc;
+        if enable_interrupts && self.enabling_interrupts {
+            self.enabling_interrupts = false;
+            self.interrupts_enabled = true;
         }
 
-        let mut interrupted = false;
-        if self.interrupts_enabled {
-            if self.bus.interrupt_enable.vblank && self.bus.interrupt_flag.vblank {
-                interrupted = true;
-                self.bus.interrupt_flag.vblank = false;
-                self.interrupt(VBLANK_VECTOR)
-            }
-            if self.bus.interrupt_enable.lcdstat && self.bus.interrupt_flag.lcdstat {
-                interrupted = true;
-                self.bus.interrupt_flag.lcdstat = false;
-                self.interrupt(LCDSTAT_VECTOR)
-            }
-            if self.bus.interrupt_enable.timer && self.bus.interrupt_flag.timer {
-                interrupted = true;
-                self.bus.interrupt_flag.timer = false;
-                self.interrupt(TIMER_VECTOR)
-            }
-        }
-        if interrupted {
-            cycles += 12;
-        }
         cycles
     }
 
-    fn interrupt(&mut self, location: u16) {
+    fn interrupt(&mut self) -> u8 {
+        // Only the pending interrupt with the highest priority is serviced. The others
+        // stay requested until it has been handled.
+        let flag = self.bus.peek_byte(INTERRUPT_FLAG);
+        let pending = self.bus.peek_byte(INTERRUPT_ENABLE) & flag & 0x1F;
+        let interrupt = pending.trailing_zeros() as usize;
+        self.bus.poke_byte(INTERRUPT_FLAG, flag & !(1 << interrupt));
+        let location = INTERRUPT_VECTORS[interrupt];
+
+        if self.halt_bug {
+            // When EI is directly followed by HALT the interrupt returns to the HALT
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         self.interrupts_enabled = false;
+        self.enabling_interrupts = false;
+        self.is_halted = false;
+
+        // Dispatching takes 5 machine cycles: two internal ones, two to push the PC
+        // and a final one to jump to the vector
+        let start = self.bus.cycles();
+        self.bus.tick();
+        self.bus.tick();
         self.push(self.pc);
+        self.record_call(CallKind::Interrupt, location, self.pc);
+        self.bus.tick();
         self.pc = location;
-        self.bus.step(12);
+        (self.bus.cycles() - start) as u8
     }
 
+    /// Executes `instruction` (which is assumed to be at the current PC) and returns the
+    /// address of the next instruction along with the number of clock cycles it took.
+    ///
+    /// Every memory access the instruction makes takes a machine cycle (4 clock cycles)
+    /// and the rest of the hardware is stepped as each one happens, so the timer, GPU
+    /// and DMA see reads and writes at the point in the instruction they really occur.
     pub fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
+        let start = self.bus.cycles();
+        // Fetching the opcode, and the prefix byte before it, takes a machine cycle each
+        self.bus.fetch_cycle(self.pc, true);
+        if instruction.is_prefixed() {
+            self.bus.fetch_cycle(self.pc.wrapping_add(1), false);
+        }
+        let next_pc = self.execute_instruction(instruction);
+        (next_pc, (self.bus.cycles() - start) as u8)
+    }
+
+    fn execute_instruction(&mut self, instruction: Instruction) -> u16 {
         // OPCodes Map: http://pastraiser.com/cpu/gameboy/gameboy_opcodes.html
         // OPCodes Explanation: https://web.archive.org/web/20181009131634/http://www.chrisantonellis.com/files/gameboy/gb-instructions.txt
         match instruction {
@@ -372,11 +618,11 @@ impl CPU {
                 // DESCRIPTION: (increment) - increment the value in a specific register by 1
                 // WHEN: target is 16 bit register
                 // PC: +1
-                // Cycles: 12
+                // Cycles: 8
                 // Z:- S:- H:- C:-
                 // WHEN: target is (HL)
                 // PC:+1
-                // Cycles: 8
+                // Cycles: 12
                 // ELSE:
                 // PC: +1
                 // Cycles: 4
@@ -391,9 +637,9 @@ impl CPU {
                     IncDecTarget::L => manipulate_8bit_register!(self: l => inc_8bit => l),
                     IncDecTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let amount = self.bus.read_byte(hl);
+                        let amount = self.bus.read_cycle(hl);
                         let result = self.inc_8bit(amount);
-                        self.bus.write_byte(hl, result);
+                        self.bus.write_cycle(hl, result);
                     }
                     IncDecTarget::BC => {
                         manipulate_16bit_register!(self: get_bc => inc_16bit => set_bc)
@@ -410,22 +656,22 @@ impl CPU {
                         self.sp = result;
                     }
                 };
-                let cycles = match target {
-                    IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP => 8,
-                    IncDecTarget::HLI => 12,
-                    _ => 4,
-                };
-                (self.pc.wrapping_add(1), cycles)
+                if let IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP =
+                    target
+                {
+                    self.bus.tick();
+                }
+                self.pc.wrapping_add(1)
             }
             Instruction::DEC(target) => {
                 // DESCRIPTION: (decrement) - decrement the value in a specific register by 1
                 // WHEN: target is 16 bit register
                 // PC: +1
-                // Cycles: 12
+                // Cycles: 8
                 // Z:- S:- H:- C:-
                 // WHEN: target is (HL)
                 // PC:+1
-                // Cycles: 8
+                // Cycles: 12
                 // ELSE:
                 // PC: +1
                 // Cycles: 4
@@ -440,9 +686,9 @@ impl CPU {
                     IncDecTarget::L => manipulate_8bit_register!(self: l => dec_8bit => l),
                     IncDecTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let amount = self.bus.read_byte(hl);
+                        let amount = self.bus.read_cycle(hl);
                         let result = self.dec_8bit(amount);
-                        self.bus.write_byte(hl, result);
+                        self.bus.write_cycle(hl, result);
                     }
                     IncDecTarget::BC => {
                         manipulate_16bit_register!(self: get_bc => dec_16bit => set_bc)
@@ -459,12 +705,12 @@ impl CPU {
                         self.sp = result;
                     }
                 };
-                let cycles = match target {
-                    IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP => 8,
-                    IncDecTarget::HLI => 12,
-                    _ => 4,
-                };
-                (self.pc.wrapping_add(1), cycles)
+                if let IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP =
+                    target
+                {
+                    self.bus.tick();
+                }
+                self.pc.wrapping_add(1)
             }
             Instruction::ADD(register) => {
                 // DESCRIPTION: (add) - add the value stored in a specific register
@@ -495,7 +741,8 @@ impl CPU {
                 };
                 let result = self.add_hl(value);
                 self.registers.set_hl(result);
-                (self.pc.wrapping_add(1), 8)
+                self.bus.tick();
+                self.pc.wrapping_add(1)
             }
             Instruction::ADDSP => {
                 // DESCRIPTION: (add stack pointer) - add a one byte signed number to
@@ -521,8 +768,10 @@ impl CPU {
                 self.registers.f.subtract = false;
 
                 self.sp = result;
+                self.bus.tick();
+                self.bus.tick();
 
-                (self.pc.wrapping_add(2), 16)
+                self.pc.wrapping_add(2)
             }
             Instruction::ADC(register) => {
                 // DESCRIPTION: (add with carry) - add the value stored in a specific
@@ -637,7 +886,7 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = !self.registers.f.carry;
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::SCF => {
                 // DESCRIPTION: (set carry flag) - set the carry flag to true
@@ -647,7 +896,7 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = true;
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::RRA => {
                 // DESCRIPTION: (rotate right A register) - bit rotate A register right through the carry flag
@@ -655,7 +904,7 @@ impl CPU {
                 // Cycles: 4
                 // Z:0 S:0 H:0 C:?
                 manipulate_8bit_register!(self: a => rotate_right_through_carry_retain_zero => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::RLA => {
                 // DESCRIPTION: (rotate left A register) - bit rotate A register left through the carry flag
@@ -663,7 +912,7 @@ impl CPU {
                 // Cycles: 4
                 // Z:0 S:0 H:0 C:?
                 manipulate_8bit_register!(self: a => rotate_left_through_carry_retain_zero => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::RRCA => {
                 // DESCRIPTION: (rotate right A register) - bit rotate A register right (not through the carry flag)
@@ -671,7 +920,7 @@ impl CPU {
                 // Cycles: 4
                 // Z:0 S:0 H:0 C:?
                 manipulate_8bit_register!(self: a => rotate_right_retain_zero => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::RLCA => {
                 // DESCRIPTION: (rotate left A register) - bit rotate A register left (not through the carry flag)
@@ -679,7 +928,7 @@ impl CPU {
                 // Cycles: 4
                 // Z:0 S:0 H:0 C:?
                 manipulate_8bit_register!(self: a => rotate_left_retain_zero => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::CPL => {
                 // DESCRIPTION: (complement) - toggle every bit of the A register
@@ -687,20 +936,20 @@ impl CPU {
                 // Cycles: 4
                 // Z:- S:1 H:1 C:-
                 manipulate_8bit_register!(self: a => complement => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::DAA => {
                 // PC:+1
                 // Cycles: 4
                 // Z:? S:- H:0 C:?
                 manipulate_8bit_register!(self: a => decimal_adjust => a);
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::BIT(register, bit_position) => {
                 // DESCRIPTION: (bit test) - test to see if a specific bit of a specific register is set
                 // PC:+2
                 // WHEN: target is (HL):
-                // Cycles: 16
+                // Cycles: 12
                 // ELSE:
                 // Cycles: 8
                 // Z:? S:0 H:1 C:-
@@ -824,7 +1073,7 @@ impl CPU {
                 // DESCRIPTION: conditionally jump to the address that is N bytes away in memory
                 // where N is the next byte in memory interpreted as a signed byte
                 // PC:?/+2
-                // Cycles: 16/12
+                // Cycles: 12/8
                 // Z:- N:- H:- C:-
                 let jump_condition = match test {
                     JumpTest::NotZero => !self.registers.f.zero,
@@ -841,7 +1090,7 @@ impl CPU {
                 // PC:HL
                 // Cycles: 4
                 // Z:- N:- H:- C:-
-                (self.registers.get_hl(), 4)
+                self.registers.get_hl()
             }
             Instruction::LD(load_type) => {
                 match load_type {
@@ -853,6 +1102,9 @@ impl CPU {
                     // WHEN: source is (HL)
                     // PC:+1
                     // Cycles: 8
+                    // WHEN: target is (HL)
+                    // PC:+1 (+2 when source is d8)
+                    // Cycles: 8 (12 when source is d8)
                     // ELSE:
                     // PC:+1
                     // Cycles: 4
@@ -867,7 +1119,7 @@ impl CPU {
                             LoadByteSource::H => self.registers.h,
                             LoadByteSource::L => self.registers.l,
                             LoadByteSource::D8 => self.read_next_byte(),
-                            LoadByteSource::HLI => self.bus.read_byte(self.registers.get_hl()),
+                            LoadByteSource::HLI => self.bus.read_cycle(self.registers.get_hl()),
                         };
                         match target {
                             LoadByteTarget::A => self.registers.a = source_value,
@@ -878,13 +1130,12 @@ impl CPU {
                             LoadByteTarget::H => self.registers.h = source_value,
                             LoadByteTarget::L => self.registers.l = source_value,
                             LoadByteTarget::HLI => {
-                                self.bus.write_byte(self.registers.get_hl(), source_value)
+                                self.bus.write_cycle(self.registers.get_hl(), source_value)
                             }
                         };
                         match source {
-                            LoadByteSource::D8 => (self.pc.wrapping_add(2), 8),
-                            LoadByteSource::HLI => (self.pc.wrapping_add(1), 8),
-                            _ => (self.pc.wrapping_add(1), 4),
+                            LoadByteSource::D8 => self.pc.wrapping_add(2),
+                            _ => self.pc.wrapping_add(1),
                         }
                     }
                     // DESCRIPTION: load next word in memory into a particular register
@@ -899,7 +1150,7 @@ impl CPU {
                             LoadWordTarget::HL => self.registers.set_hl(word),
                             LoadWordTarget::SP => self.sp = word,
                         };
-                        (self.pc.wrapping_add(3), 12)
+                        self.pc.wrapping_add(3)
                     }
                     // DESCRIPTION: load a particular value stored at the source address into A
                     // WHEN: source is word indirect
@@ -911,27 +1162,30 @@ impl CPU {
                     // Z:- N:- H:- C:-
                     LoadType::AFromIndirect(source) => {
                         self.registers.a = match source {
-                            Indirect::BCIndirect => self.bus.read_byte(self.registers.get_bc()),
-                            Indirect::DEIndirect => self.bus.read_byte(self.registers.get_de()),
+                            Indirect::BCIndirect => self.bus.read_cycle(self.registers.get_bc()),
+                            Indirect::DEIndirect => self.bus.read_cycle(self.registers.get_de()),
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.read_byte(hl)
+                                self.bus.read_cycle(hl)
                             }
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.read_byte(hl)
+                                self.bus.read_cycle(hl)
+                            }
+                            Indirect::WordIndirect => {
+                                let address = self.read_next_word();
+                                self.bus.read_cycle(address)
                             }
-                            Indirect::WordIndirect => self.bus.read_byte(self.read_next_word()),
                             Indirect::LastByteIndirect => {
-                                self.bus.read_byte(0xFF00 + self.registers.c as u16)
+                                self.bus.read_cycle(0xFF00 + self.registers.c as u16)
                             }
                         };
 
                         match source {
-                            Indirect::WordIndirect => (self.pc.wrapping_add(3), 16),
-                            _ => (self.pc.wrapping_add(1), 8),
+                            Indirect::WordIndirect => self.pc.wrapping_add(3),
+                            _ => self.pc.wrapping_add(1),
                         }
                     }
                     // DESCRIPTION: load the A register into memory at the source address
@@ -947,35 +1201,35 @@ impl CPU {
                         match target {
                             Indirect::BCIndirect => {
                                 let bc = self.registers.get_bc();
-                                self.bus.write_byte(bc, a)
+                                self.bus.write_cycle(bc, a)
                             }
                             Indirect::DEIndirect => {
                                 let de = self.registers.get_de();
-                                self.bus.write_byte(de, a)
+                                self.bus.write_cycle(de, a)
                             }
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.write_byte(hl, a);
+                                self.bus.write_cycle(hl, a);
                             }
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.write_byte(hl, a);
+                                self.bus.write_cycle(hl, a);
                             }
                             Indirect::WordIndirect => {
                                 let word = self.read_next_word();
-                                self.bus.write_byte(word, a);
+                                self.bus.write_cycle(word, a);
                             }
                             Indirect::LastByteIndirect => {
                                 let c = self.registers.c as u16;
-                                self.bus.write_byte(0xFF00 + c, a);
+                                self.bus.write_cycle(0xFF00 + c, a);
                             }
                         };
 
                         match target {
-                            Indirect::WordIndirect => (self.pc.wrapping_add(3), 16),
-                            _ => (self.pc.wrapping_add(1), 8),
+                            Indirect::WordIndirect => self.pc.wrapping_add(3),
+                            _ => self.pc.wrapping_add(1),
                         }
                     }
                     // DESCRIPTION: Load the value in A into memory location located at 0xFF plus
@@ -985,8 +1239,8 @@ impl CPU {
                     // Z:- N:- H:- C:-
                     LoadType::ByteAddressFromA => {
                         let offset = self.read_next_byte() as u16;
-                        self.bus.write_byte(0xFF00 + offset, self.registers.a);
-                        (self.pc.wrapping_add(2), 12)
+                        self.bus.write_cycle(0xFF00 + offset, self.registers.a);
+                        self.pc.wrapping_add(2)
                     }
                     // DESCRIPTION: Load the value located at 0xFF plus an offset stored as the next byte in memory into A
                     // PC:+2
@@ -994,8 +1248,8 @@ impl CPU {
                     // Z:- N:- H:- C:-
                     LoadType::AFromByteAddress => {
                         let offset = self.read_next_byte() as u16;
-                        self.registers.a = self.bus.read_byte(0xFF00 + offset);
-                        (self.pc.wrapping_add(2), 12)
+                        self.registers.a = self.bus.read_cycle(0xFF00 + offset);
+                        self.pc.wrapping_add(2)
                     }
                     // DESCRIPTION: Load the value in HL into SP
                     // PC:+1
@@ -1003,7 +1257,8 @@ impl CPU {
                     // Z:- N:- H:- C:-
                     LoadType::SPFromHL => {
                         self.sp = self.registers.get_hl();
-                        (self.pc.wrapping_add(1), 8)
+                        self.bus.tick();
+                        self.pc.wrapping_add(1)
                     }
                     // DESCRIPTION: Load memory address with the contents of SP
                     // PC:+3
@@ -1012,10 +1267,10 @@ impl CPU {
                     LoadType::IndirectFromSP => {
                         let address = self.read_next_word();
                         let sp = self.sp;
-                        self.bus.write_byte(address, (sp & 0xFF) as u8);
+                        self.bus.write_cycle(address, (sp & 0xFF) as u8);
                         self.bus
-                            .write_byte(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
-                        (self.pc.wrapping_add(3), 20)
+                            .write_cycle(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
+                        self.pc.wrapping_add(3)
                     }
                     // DESCRIPTION: load HL with SP plus some specified byte
                     // PC:+2
@@ -1031,7 +1286,8 @@ impl CPU {
                         // of the byte and word level like you might expect for 16 bit values
                         self.registers.f.half_carry = (self.sp & 0xF) + (value & 0xF) > 0xF;
                         self.registers.f.carry = (self.sp & 0xFF) + (value & 0xFF) > 0xFF;
-                        (self.pc.wrapping_add(2), 12)
+                        self.bus.tick();
+                        self.pc.wrapping_add(2)
                     }
                 }
             }
@@ -1046,8 +1302,9 @@ impl CPU {
                     StackTarget::DE => self.registers.get_de(),
                     StackTarget::HL => self.registers.get_hl(),
                 };
+                self.bus.tick();
                 self.push(value);
-                (self.pc.wrapping_add(1), 16)
+                self.pc.wrapping_add(1)
             }
             Instruction::POP(target) => {
                 // DESCRIPTION: pop a value from the stack and store it in a given register
@@ -1064,7 +1321,7 @@ impl CPU {
                     StackTarget::DE => self.registers.set_de(result),
                     StackTarget::HL => self.registers.set_hl(result),
                 };
-                (self.pc.wrapping_add(1), 12)
+                self.pc.wrapping_add(1)
             }
             Instruction::CALL(test) => {
                 // DESCRIPTION: Conditionally PUSH the would be instruction on to the
@@ -1096,57 +1353,84 @@ impl CPU {
                     JumpTest::Carry => self.registers.f.carry,
                     JumpTest::Always => true,
                 };
-                let next_pc = self.return_(jump_condition);
-
-                let cycles = if jump_condition && test == JumpTest::Always {
-                    16
-                } else if jump_condition {
-                    20
-                } else {
-                    8
-                };
-                (next_pc, cycles)
+                if test != JumpTest::Always {
+                    // Checking the condition takes a cycle of its own
+                    self.bus.tick();
+                }
+                self.return_(jump_condition)
             }
             Instruction::RETI => {
                 // PC:?
                 // Cycles: 16
                 // Z:- N:- H:- C:-
                 self.interrupts_enabled = true;
-                (self.pop(), 16)
+                let sp = self.sp;
+                let next_pc = self.pop();
+                self.record_return(sp, next_pc);
+                self.bus.tick();
+                next_pc
             }
             Instruction::RST(loc) => {
                 // PC:?
-                // Cycles: 24
+                // Cycles: 16
                 // Z:- N:- H:- C:-
                 self.rst();
-                (loc.to_hex(), 24)
+                self.record_call(CallKind::Rst, loc.to_hex(), self.pc.wrapping_add(1));
+                loc.to_hex()
             }
             Instruction::NOP => {
                 // PC:+1
                 // Cycles: 4
                 // Z:- N:- H:- C:-
-                (self.pc.wrapping_add(1), 4)
+                self.pc.wrapping_add(1)
             }
             Instruction::HALT => {
                 // PC:+1
                 // Cycles: 4
                 // Z:- N:- H:- C:-
-                self.is_halted = true;
-                (self.pc.wrapping_add(1), 4)
+                if !self.interrupts_enabled && self.bus.has_interrupt() {
+                    // HALT does not halt when an interrupt is already pending with
+                    // interrupts disabled. Instead the CPU trips over the HALT bug.
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
+                }
+                self.pc.wrapping_add(1)
             }
             Instruction::DI => {
                 // PC:+1
                 // Cycles: 4
                 // Z:- N:- H:- C:-
                 self.interrupts_enabled = false;
-                (self.pc.wrapping_add(1), 4)
+                self.enabling_interrupts = false;
+                self.pc.wrapping_add(1)
             }
             Instruction::EI => {
+                // DESCRIPTION: (enable interrupts) - interrupts are enabled after the
+                // instruction following EI has executed
                 // PC:+1
                 // Cycles: 4
                 // Z:- N:- H:- C:-
-                self.interrupts_enabled = true;
-                (self.pc.wrapping_add(1), 4)
+                self.enabling_interrupts = true;
+                self.pc.wrapping_add(1)
+            }
+            Instruction::STOP => {
+                // DESCRIPTION: (stop) - stop the clock and enter very low power mode until a
+                // button is pressed. The divider register is reset as well.
+                // PC:+2
+                // Cycles: 4
+                // Z:- N:- H:- C:-
+                self.is_stopped = true;
+                self.bus.poke_byte(0xFF04, 0);
+                self.pc.wrapping_add(2)
+            }
+            Instruction::ILLEGAL(_) => {
+                // DESCRIPTION: one of the unused opcodes. Real hardware locks up for good.
+                // PC:+0
+                // Cycles: 4
+                // Z:- N:- H:- C:-
+                self.is_locked = true;
+                self.pc
             }
         }
     }
@@ -1154,33 +1438,35 @@ impl CPU {
     #[inline(always)]
     fn push(&mut self, value: u16) {
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.bus.write_cycle(self.sp, ((value & 0xFF00) >> 8) as u8);
 
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.bus.write_cycle(self.sp, (value & 0xFF) as u8);
     }
 
     #[inline(always)]
     fn pop(&mut self) -> u16 {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.bus.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.bus.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
         (msb << 8) | lsb
     }
 
     #[inline(always)]
-    fn read_next_word(&self) -> u16 {
+    fn read_next_word(&mut self) -> u16 {
         // Gameboy is little endian so read pc + 2 as most significant bit
         // and pc + 1 as least significant bit
-        ((self.bus.read_byte(self.pc + 2) as u16) << 8) | (self.bus.read_byte(self.pc + 1) as u16)
+        let lsb = self.bus.fetch_cycle(self.pc.wrapping_add(1), false) as u16;
+        let msb = self.bus.fetch_cycle(self.pc.wrapping_add(2), false) as u16;
+        (msb << 8) | lsb
     }
 
     #[inline(always)]
-    fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+    fn read_next_byte(&mut self) -> u8 {
+        self.bus.fetch_cycle(self.pc.wrapping_add(1), false)
     }
 
     #[inline(always)]
@@ -1521,45 +1807,55 @@ impl CPU {
     }
 
     #[inline(always)]
-    fn jump(&self, should_jump: bool) -> (u16, u8) {
+    fn jump(&mut self, should_jump: bool) -> u16 {
+        // The address is read whether or not the jump is taken
+        let address = self.read_next_word();
         if should_jump {
-            (self.read_next_word(), 16)
+            self.bus.tick();
+            address
         } else {
-            (self.pc.wrapping_add(3), 12)
+            self.pc.wrapping_add(3)
         }
     }
 
     #[inline(always)]
-    fn jump_relative(&self, should_jump: bool) -> (u16, u8) {
+    fn jump_relative(&mut self, should_jump: bool) -> u16 {
         let next_step = self.pc.wrapping_add(2);
+        let offset = self.read_next_byte() as i8;
         if should_jump {
-            let offset = self.read_next_byte() as i8;
-            let pc = if offset >= 0 {
+            self.bus.tick();
+            if offset >= 0 {
                 next_step.wrapping_add(offset as u16)
             } else {
                 next_step.wrapping_sub(offset.abs() as u16)
-            };
-            (pc, 16)
+            }
         } else {
-            (next_step, 12)
+            next_step
         }
     }
 
     #[inline(always)]
-    fn call(&mut self, should_jump: bool) -> (u16, u8) {
+    fn call(&mut self, should_jump: bool) -> u16 {
         let next_pc = self.pc.wrapping_add(3);
+        let address = self.read_next_word();
         if should_jump {
+            self.bus.tick();
             self.push(next_pc);
-            (self.read_next_word(), 24)
+            self.record_call(CallKind::Call, address, next_pc);
+            address
         } else {
-            (next_pc, 12)
+            next_pc
         }
     }
 
     #[inline(always)]
     fn return_(&mut self, should_jump: bool) -> u16 {
         if should_jump {
-            self.pop()
+            let sp = self.sp;
+            let address = self.pop();
+            self.record_return(sp, address);
+            self.bus.tick();
+            address
         } else {
             self.pc.wrapping_add(1)
         }
@@ -1567,18 +1863,32 @@ impl CPU {
 
     #[inline(always)]
     fn rst(&mut self) {
+        self.bus.tick();
         self.push(self.pc.wrapping_add(1));
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::flags_register::FlagsRegister;
+    use super::instruction::FlagEffect;
     use super::*;
 
+    // The tests below are written against a CPU in its power on state (everything
+    // zeroed and starting at address 0) rather than the state the boot ROM leaves behind
+    fn power_on_cpu() -> CPU {
+        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        cpu.registers = Registers::new();
+        cpu.pc = 0;
+        cpu.sp = 0;
+        cpu.bus.write_byte(0xFF0F, 0);
+        cpu
+    }
+
     macro_rules! test_instruction {
         ( $instruction:expr, $( $($register:ident).* => $value:expr ),* ) => {
             {
-                let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+                let mut cpu = power_on_cpu();
                 $(
                     cpu.registers$(.$register)* = $value;
                  )*
@@ -1626,7 +1936,7 @@ mod tests {
     #[test]
     fn execute_inc_16bit_byte_overflow() {
         let instruction = Instruction::INC(IncDecTarget::BC);
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.set_bc(0xFF);
         cpu.execute(instruction);
 
@@ -1639,7 +1949,7 @@ mod tests {
     #[test]
     fn execute_inc_16bit_overflow() {
         let instruction = Instruction::INC(IncDecTarget::BC);
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.set_bc(0xFFFF);
         cpu.execute(instruction);
 
@@ -1677,7 +1987,7 @@ mod tests {
     #[test]
     fn execute_dec_16bit_underflow() {
         let instruction = Instruction::DEC(IncDecTarget::BC);
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.set_bc(0x0000);
         cpu.execute(instruction);
 
@@ -2065,7 +2375,7 @@ mod tests {
     // JP
     #[test]
     fn execute_jp() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.pc = 0xF8;
         cpu.bus.write_byte(0xF9, 0xFC);
         cpu.bus.write_byte(0xFA, 0x02);
@@ -2081,7 +2391,7 @@ mod tests {
     // JR
     #[test]
     fn execute_jr() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.pc = 0xF8;
         cpu.bus.write_byte(0xF9, 0x4);
         let (next_pc, _) = cpu.execute(Instruction::JR(JumpTest::Always));
@@ -2096,7 +2406,7 @@ mod tests {
     // LD a, (??)
     #[test]
     fn execute_ld_a_indirect() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.set_bc(0xF9);
         cpu.bus.write_byte(0xF9, 0x4);
         cpu.execute(Instruction::LD(LoadType::AFromIndirect(
@@ -2118,7 +2428,7 @@ mod tests {
     // LD ?, ?
     #[test]
     fn execute_ld_byte() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.b = 0x4;
         cpu.execute(Instruction::LD(LoadType::Byte(
             LoadByteTarget::D,
@@ -2132,7 +2442,7 @@ mod tests {
     // PUSH/POP
     #[test]
     fn execute_push_pop() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.registers.b = 0x4;
         cpu.registers.c = 0x89;
         cpu.sp = 0x10;
@@ -2148,12 +2458,358 @@ mod tests {
         assert_eq!(cpu.registers.e, 0x89);
     }
 
+    // STOP
+    #[test]
+    fn execute_stop_waits_for_joypad() {
+        let mut cpu = power_on_cpu();
+        cpu.bus.write_byte(0, 0x10); // STOP
+        cpu.bus.write_byte(1, 0x00);
+        cpu.bus.write_byte(2, 0x3C); // INC A
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.registers.a, 0);
+
+        cpu.bus.joypad.right = true;
+        cpu.step();
+        assert_eq!(cpu.pc, 3);
+        assert_eq!(cpu.registers.a, 1);
+    }
+
+    // Illegal opcodes
+    #[test]
+    fn execute_illegal_locks_cpu() {
+        let mut cpu = power_on_cpu();
+        cpu.bus.write_byte(0, 0x3C); // INC A
+        cpu.bus.write_byte(1, 0xD3); // Illegal
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_locked());
+
+        cpu.step();
+        assert_eq!(cpu.pc, 1);
+        assert_eq!(cpu.registers.a, 1);
+    }
+
+    // Interrupts
+    #[test]
+    fn interrupt_services_highest_priority_only() {
+        let mut cpu = power_on_cpu();
+        cpu.sp = 0xD000;
+        cpu.pc = 0x1234;
+        cpu.bus.write_byte(0xFFFF, 0b11111);
+        cpu.bus.write_byte(0xFF0F, 0b00101); // VBlank and Timer
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.pc, VBLANK_VECTOR);
+        assert_eq!(cpu.sp, 0xCFFE);
+        assert_eq!(cpu.bus.read_byte(0xCFFE), 0x34);
+        assert_eq!(cpu.bus.read_byte(0xCFFF), 0x12);
+        assert!(cpu.bus.interrupt_flag.timer);
+        assert!(!cpu.bus.interrupt_flag.vblank);
+        assert!(!cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn interrupt_dispatches_serial_and_joypad() {
+        let mut cpu = power_on_cpu();
+        cpu.sp = 0xD000;
+        cpu.bus.write_byte(0xFFFF, 0b11000);
+        cpu.bus.write_byte(0xFF0F, 0b10000);
+        cpu.step();
+        assert_eq!(cpu.pc, JOYPAD_VECTOR);
+
+        cpu.interrupts_enabled = true;
+        cpu.bus.write_byte(0xFF0F, 0b01000);
+        cpu.step();
+        assert_eq!(cpu.pc, SERIAL_VECTOR);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        let mut cpu = power_on_cpu();
+        cpu.sp = 0xD000;
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(0, 0xFB); // EI
+        cpu.bus.write_byte(1, 0x00); // NOP
+        cpu.bus.write_byte(0xFFFF, 0b1);
+        cpu.bus.write_byte(0xFF0F, 0b1);
+
+        cpu.step();
+        assert_eq!(cpu.pc, 1);
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+        cpu.step();
+        assert_eq!(cpu.pc, VBLANK_VECTOR);
+    }
+
+    #[test]
+    fn di_cancels_pending_ei() {
+        let mut cpu = power_on_cpu();
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(0, 0xFB); // EI
+        cpu.bus.write_byte(1, 0xF3); // DI
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn halt_bug_repeats_next_byte() {
+        let mut cpu = power_on_cpu();
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(0, 0x76); // HALT
+        cpu.bus.write_byte(1, 0x3C); // INC A
+        cpu.bus.write_byte(0xFFFF, 0b100);
+        cpu.bus.write_byte(0xFF0F, 0b100);
+
+        cpu.step();
+        assert!(!cpu.is_halted);
+        cpu.step();
+        assert_eq!(cpu.pc, 1);
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.registers.a, 2);
+    }
+
+    #[test]
+    fn halt_waits_for_interrupt() {
+        let mut cpu = power_on_cpu();
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(0, 0x76); // HALT
+        cpu.bus.write_byte(1, 0x3C); // INC A
+        cpu.bus.write_byte(0xFFFF, 0b100);
+
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.pc, 1);
+
+        cpu.bus.write_byte(0xFF0F, 0b100);
+        cpu.step();
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.registers.a, 1);
+    }
+
     // -----------------------------------------------------------------------------
 
     // Step
+    #[test]
+    fn execute_takes_a_machine_cycle_per_memory_access() {
+        let mut cpu = power_on_cpu();
+        let cases = [
+            (Instruction::NOP, 4),
+            (Instruction::INC(IncDecTarget::BC), 8),
+            (Instruction::INC(IncDecTarget::HLI), 12),
+            (Instruction::JR(JumpTest::Zero), 12),
+            (Instruction::JR(JumpTest::NotZero), 8),
+            (Instruction::BIT(PrefixTarget::HLI, BitPosition::B0), 12),
+            (Instruction::SET(PrefixTarget::HLI, BitPosition::B0), 16),
+            (
+                Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8)),
+                12,
+            ),
+            (Instruction::RST(instruction::RSTLocation::X00), 16),
+            (Instruction::CALL(JumpTest::Always), 24),
+            (Instruction::RET(JumpTest::Zero), 20),
+            (Instruction::RET(JumpTest::NotZero), 8),
+        ];
+        for &(instruction, expected) in cases.iter() {
+            cpu.pc = 0;
+            cpu.sp = 0xD000;
+            cpu.registers.set_hl(0xC000);
+            cpu.registers.f.zero = true;
+            let start = cpu.bus.cycles();
+            let (_, cycles) = cpu.execute(instruction);
+            assert_eq!(cycles, expected, "{:?}", instruction);
+            assert_eq!(
+                cpu.bus.cycles() - start,
+                expected as u64,
+                "{:?}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn execute_matches_the_opcode_metadata() {
+        fn run(
+            instruction: Instruction,
+            flags: bool,
+            condition: Option<bool>,
+        ) -> (u8, FlagsRegister) {
+            let mut cpu = power_on_cpu();
+            cpu.sp = 0xD000;
+            cpu.registers.set_hl(0xC000);
+            cpu.registers.f = FlagsRegister::from(if flags { 0xF0 } else { 0x00 });
+            let test = match instruction {
+                Instruction::JP(test)
+                | Instruction::JR(test)
+                | Instruction::CALL(test)
+                | Instruction::RET(test) => test,
+                _ => JumpTest::Always,
+            };
+            if let Some(taken) = condition {
+                match test {
+                    JumpTest::NotZero => cpu.registers.f.zero = !taken,
+                    JumpTest::Zero => cpu.registers.f.zero = taken,
+                    JumpTest::NotCarry => cpu.registers.f.carry = !taken,
+                    JumpTest::Carry => cpu.registers.f.carry = taken,
+                    JumpTest::Always => {}
+                }
+            }
+            let (_, cycles) = cpu.execute(instruction);
+            (cycles, cpu.registers.f)
+        }
+        fn check(effect: FlagEffect, before: bool, after: bool) -> bool {
+            match effect {
+                FlagEffect::Unaffected => after == before,
+                FlagEffect::Set => after,
+                FlagEffect::Reset => !after,
+                FlagEffect::Affected => true,
+            }
+        }
+
+        for metadata in instruction::metadata_table() {
+            let instruction = metadata.instruction;
+            assert_eq!(
+                run(instruction, false, Some(true)).0,
+                metadata.cycles,
+                "{:?}",
+                instruction
+            );
+            if let Some(not_taken) = metadata.cycles_not_taken {
+                assert_eq!(
+                    run(instruction, false, Some(false)).0,
+                    not_taken,
+                    "{:?}",
+                    instruction
+                );
+            }
+
+            for &before in [false, true].iter() {
+                let (_, f) = run(instruction, before, None);
+                let effects = metadata.flags;
+                assert!(check(effects.zero, before, f.zero), "Z {:?}", instruction);
+                assert!(
+                    check(effects.subtract, before, f.subtract),
+                    "N {:?}",
+                    instruction
+                );
+                assert!(
+                    check(effects.half_carry, before, f.half_carry),
+                    "H {:?}",
+                    instruction
+                );
+                assert!(check(effects.carry, before, f.carry), "C {:?}", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn oam_dma_copies_a_byte_per_machine_cycle() {
+        let mut cpu = power_on_cpu();
+        for offset in 0..0xA0 {
+            cpu.bus.write_byte(0xC000 + offset, offset as u8 + 1);
+        }
+        cpu.bus.write_byte(0xFF46, 0xC0);
+        assert_eq!(cpu.bus.read_byte(0xFE00), 0);
+
+        cpu.bus.tick();
+        cpu.bus.tick();
+        assert_eq!(cpu.bus.read_byte(0xFE00), 1);
+        assert_eq!(cpu.bus.read_byte(0xFE01), 2);
+        assert_eq!(cpu.bus.read_byte(0xFE02), 0);
+
+        for _ in 0..160 {
+            cpu.bus.tick();
+        }
+        assert_eq!(cpu.bus.read_byte(0xFE9F), 0xA0);
+    }
+
+    #[test]
+    fn ld_b_b_is_a_software_breakpoint_when_enabled() {
+        let mut cpu = power_on_cpu();
+        cpu.bus.write_byte(0x0000, 0x40); // LD B,B
+        cpu.bus.write_byte(0x0001, 0x40);
+        cpu.bus.write_byte(0x0002, 0x00); // NOP
+        cpu.step();
+        assert!(!cpu.hit_software_breakpoint());
+
+        cpu.set_software_breakpoints(true);
+        cpu.step();
+        assert!(cpu.hit_software_breakpoint());
+        assert_eq!(cpu.pc, 2);
+        cpu.step();
+        assert!(!cpu.hit_software_breakpoint());
+    }
+
+    #[test]
+    fn serial_transfer_completes_with_an_interrupt() {
+        let mut cpu = power_on_cpu();
+        cpu.bus.write_byte(0xFF01, b'P');
+        cpu.bus.write_byte(0xFF02, 0x81);
+        assert_eq!(cpu.bus.serial_output, b"P");
+        assert_eq!(cpu.bus.read_byte(0xFF02), 0xFF);
+
+        for _ in 0..1023 {
+            cpu.bus.tick();
+        }
+        assert!(!cpu.bus.interrupt_flag.serial);
+        cpu.bus.tick();
+        assert!(cpu.bus.interrupt_flag.serial);
+        assert_eq!(cpu.bus.read_byte(0xFF01), 0xFF);
+        assert_eq!(cpu.bus.read_byte(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn starts_in_post_boot_state_without_boot_rom() {
+        let mut game_rom = vec![0; 0x8000];
+        game_rom[HEADER_CHECKSUM_ADDRESS] = 0x66;
+
+        let cpu = CPU::new(None, game_rom.clone());
+        assert_eq!(cpu.pc, 0x0100);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.registers.get_af(), 0x01B0);
+        assert_eq!(cpu.registers.get_bc(), 0x0013);
+        assert_eq!(cpu.registers.get_de(), 0x00D8);
+        assert_eq!(cpu.registers.get_hl(), 0x014D);
+        assert_eq!(cpu.bus.read_byte(0xFF04), 0xAB);
+        assert_eq!(cpu.bus.read_byte(0xFF0F), 0xE1);
+        assert_eq!(cpu.bus.read_byte(0xFF40), 0x91);
+        assert_eq!(cpu.bus.read_byte(0xFF05), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFF07), 0xF8);
+        assert_eq!(cpu.bus.read_byte(0xFF43), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFF45), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFF47), 0xFC);
+
+        let cpu = CPU::with_model(None, game_rom.clone(), Model::MGB);
+        assert_eq!(cpu.registers.get_af(), 0xFFB0);
+
+        let cpu = CPU::with_model(None, game_rom, Model::DMG0);
+        assert_eq!(cpu.registers.get_af(), 0x0100);
+        assert_eq!(cpu.registers.get_bc(), 0xFF13);
+        assert_eq!(cpu.registers.get_hl(), 0x8403);
+        assert_eq!(cpu.bus.read_byte(0xFF04), 0x18);
+
+        let cpu = CPU::new(Some(vec![0; 0x100]), vec![0; 0x8000]);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.registers.get_af(), 0);
+
+        // Too short to have a header
+        let mut cpu = CPU::new(None, vec![0; 0x101]);
+        assert_eq!(cpu.registers.get_af(), 0x01B0);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0101);
+        assert_eq!(cpu.bus.read_byte(0x7FFF), 0);
+    }
+
     #[test]
     fn test_step() {
-        let mut cpu = CPU::new(None, vec![0; 0xFFFF]);
+        let mut cpu = power_on_cpu();
         cpu.bus.write_byte(0, 0x23); //INC(HL)
         cpu.bus.write_byte(1, 0xB5); //OR(L)
         cpu.bus.write_byte(2, 0xCB); //PREFIX
@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use super::{CallKind, CPU};
+use crate::bus::Bus;
+use crate::memory_bus::{
+    JOYPAD_VECTOR, LCDSTAT_VECTOR, SERIAL_VECTOR, TIMER_VECTOR, VBLANK_VECTOR,
+};
+
+/// The time spent on the instructions at one address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddressProfile {
+    /// How many times the instruction ran
+    pub runs: u64,
+    pub cycles: u64,
+}
+
+/// The time spent in the function called at one address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// How many times it was called, RSTed to or interrupted into
+    pub calls: u64,
+    /// Cycles spent in the function including the functions it calls
+    pub cycles: u64,
+    /// Cycles spent in the function itself
+    pub self_cycles: u64,
+}
+
+/// The time spent handling one interrupt
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptProfile {
+    /// How many times the handler ran
+    pub count: u64,
+    /// Cycles from dispatching the interrupt to returning from its handler, summed
+    /// over every time it ran
+    pub cycles: u64,
+    /// The most cycles it took to handle once
+    pub longest: u64,
+}
+
+// An interrupt whose handler hasn't returned yet
+struct Handling {
+    vector: u16,
+    start: u64,
+    // The depth of the call stack with the interrupt on it
+    depth: usize,
+}
+
+/// Adds up where the CPU spends its clock cycles: on the instruction at each address,
+/// in each function (anything called, RSTed or interrupted to) and handling each
+/// interrupt. The functions are followed using the CPU's call stack.
+///
+/// Cycles spent waiting in HALT (or STOP) are counted separately from the instruction
+/// at the PC, as are the cycles taken to dispatch an interrupt which instead count
+/// towards the interrupt's handler.
+///
+/// Once profiling is done the results can be written out as a text report or as
+/// folded stacks for [flamegraph.pl](https://github.com/brendangregg/FlameGraph) and
+/// tools like it.
+#[derive(Default)]
+pub struct Profiler {
+    addresses: HashMap<u16, AddressProfile>,
+    functions: HashMap<u16, FunctionProfile>,
+    interrupts: BTreeMap<u16, InterruptProfile>,
+    // Cycles by the targets of the calls on the stack, outermost first
+    stacks: HashMap<Vec<u16>, u64>,
+    halted_stacks: HashMap<Vec<u16>, u64>,
+    handling: Vec<Handling>,
+    // Whether the step being profiled dispatched an interrupt
+    dispatching: bool,
+    total_cycles: u64,
+    halted_cycles: u64,
+    // The cycle the last step profiled ended on
+    cycle: u64,
+    // The targets of the calls on the stack when the last step profiled ended
+    stack: Vec<u16>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Every clock cycle profiled
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// The clock cycles the CPU spent halted or stopped
+    pub fn halted_cycles(&self) -> u64 {
+        self.halted_cycles
+    }
+
+    /// The addresses instructions ran at, with the most cycles first
+    pub fn addresses(&self) -> Vec<(u16, AddressProfile)> {
+        let mut addresses: Vec<_> = self.addresses.iter().map(|(a, p)| (*a, *p)).collect();
+        addresses.sort_by(|(a, p), (b, q)| q.cycles.cmp(&p.cycles).then(a.cmp(b)));
+        addresses
+    }
+
+    /// The functions by the address they were called at, with the most cycles (counting
+    /// the functions they call) first
+    pub fn functions(&self) -> Vec<(u16, FunctionProfile)> {
+        let mut functions: Vec<_> = self.functions.iter().map(|(a, p)| (*a, *p)).collect();
+        functions.sort_by(|(a, p), (b, q)| q.cycles.cmp(&p.cycles).then(a.cmp(b)));
+        functions
+    }
+
+    /// The interrupts that were handled by the address of their handler
+    pub fn interrupts(&self) -> impl Iterator<Item = (u16, &InterruptProfile)> {
+        self.interrupts
+            .iter()
+            .map(|(vector, profile)| (*vector, profile))
+    }
+
+    /// Writes a report of the `limit` addresses and functions that took the most cycles
+    /// and of the interrupts. Addresses are named using the symbols of `cpu`.
+    pub fn write_report<B: Bus, W: Write>(
+        &self,
+        cpu: &CPU<B>,
+        limit: usize,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total_cycles.max(1) as f64;
+        writeln!(
+            writer,
+            "{} cycles profiled, {} ({:.1}%) halted",
+            self.total_cycles,
+            self.halted_cycles,
+            percent(self.halted_cycles)
+        )?;
+
+        writeln!(
+            writer,
+            "\nAddresses\n{:>12} {:>6} {:>10}  address",
+            "cycles", "%", "runs"
+        )?;
+        for (address, profile) in self.addresses().into_iter().take(limit) {
+            writeln!(
+                writer,
+                "{:>12} {:>5.1}% {:>10}  {}",
+                profile.cycles,
+                percent(profile.cycles),
+                profile.runs,
+                describe(cpu, address)
+            )?;
+        }
+
+        writeln!(
+            writer,
+            "\nFunctions\n{:>12} {:>6} {:>12} {:>6} {:>10}  function",
+            "cycles", "%", "self", "%", "calls"
+        )?;
+        for (address, profile) in self.functions().into_iter().take(limit) {
+            writeln!(
+                writer,
+                "{:>12} {:>5.1}% {:>12} {:>5.1}% {:>10}  {}",
+                profile.cycles,
+                percent(profile.cycles),
+                profile.self_cycles,
+                percent(profile.self_cycles),
+                profile.calls,
+                describe(cpu, address)
+            )?;
+        }
+
+        writeln!(
+            writer,
+            "\nInterrupts\n{:>12} {:>6} {:>10} {:>10} {:>10}  interrupt",
+            "cycles", "%", "count", "average", "longest"
+        )?;
+        for (vector, profile) in self.interrupts() {
+            writeln!(
+                writer,
+                "{:>12} {:>5.1}% {:>10} {:>10} {:>10}  {} ({})",
+                profile.cycles,
+                percent(profile.cycles),
+                profile.count,
+                profile.cycles / profile.count.max(1),
+                profile.longest,
+                interrupt_name(vector),
+                describe(cpu, vector)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the cycles spent in each stack of calls as lines of `outer;inner cycles`,
+    /// the folded stack format taken by flamegraph.pl. Functions are named by their
+    /// label in the symbols of `cpu` or their address. Code outside of any call is in
+    /// `[top level]` and time spent halted in a `[halted]` frame.
+    pub fn write_folded<B: Bus, W: Write>(&self, cpu: &CPU<B>, mut writer: W) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for (stacks, halted) in &[(&self.stacks, false), (&self.halted_stacks, true)] {
+            for (stack, cycles) in stacks.iter() {
+                let mut frames: Vec<String> = stack
+                    .iter()
+                    .map(|target| cpu.symbols.describe_on(&cpu.bus, *target))
+                    .collect();
+                if frames.is_empty() {
+                    frames.push("[top level]".to_string());
+                }
+                if *halted {
+                    frames.push("[halted]".to_string());
+                }
+                lines.push((frames.join(";"), *cycles));
+            }
+        }
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(writer, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn called(&mut self, kind: CallKind, target: u16, depth: usize) {
+        self.functions.entry(target).or_default().calls += 1;
+        if kind == CallKind::Interrupt {
+            // The interrupt started being dispatched at the start of this step
+            self.dispatching = true;
+            self.handling.push(Handling {
+                vector: target,
+                start: self.cycle,
+                depth,
+            });
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Starts profiling every instruction executed. This replaces any profiler that
+    /// was already set.
+    pub fn set_profiler(&mut self, mut profiler: Profiler) {
+        profiler.cycle = self.bus.cycles();
+        profiler.stack.clear();
+        profiler
+            .stack
+            .extend(self.call_stack.frames().iter().map(|frame| frame.target));
+        // Handlers being timed might never return on this CPU
+        profiler.handling.clear();
+        self.profiler = Some(profiler);
+    }
+
+    /// The profiler, if profiling
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stops profiling and hands back the profiler
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Counts the cycles since the last step towards the instruction at `pc`, the
+    // functions that were on the call stack before it ran and any interrupt being
+    // handled. So a call counts towards the caller and a return towards the function
+    // returning.
+    pub(super) fn profile(&mut self, pc: u16) {
+        let now = self.bus.cycles();
+        let depth = self.call_stack.frames().len();
+        let halted = (self.is_halted || self.is_stopped || self.is_locked) && self.pc == pc;
+        let profiler = match self.profiler {
+            Some(ref mut profiler) => profiler,
+            None => return,
+        };
+        // Loading a state can move the clock backwards
+        let cycles = now.saturating_sub(profiler.cycle);
+        let dispatched = std::mem::replace(&mut profiler.dispatching, false);
+        profiler.cycle = now;
+        profiler.total_cycles += cycles;
+
+        if halted {
+            profiler.halted_cycles += cycles;
+        } else if !dispatched {
+            let address = profiler.addresses.entry(pc).or_default();
+            address.runs += 1;
+            address.cycles += cycles;
+        }
+
+        if dispatched {
+            profiler.stack.push(self.pc);
+        }
+        let stacks = if halted {
+            &mut profiler.halted_stacks
+        } else {
+            &mut profiler.stacks
+        };
+        match stacks.get_mut(&profiler.stack[..]) {
+            Some(stack_cycles) => *stack_cycles += cycles,
+            None => {
+                stacks.insert(profiler.stack.clone(), cycles);
+            }
+        }
+        for (index, target) in profiler.stack.iter().enumerate() {
+            // Recursive functions only count once
+            if profiler.stack[..index].contains(target) {
+                continue;
+            }
+            profiler.functions.entry(*target).or_default().cycles += cycles;
+        }
+        if let Some(target) = profiler.stack.last() {
+            profiler.functions.entry(*target).or_default().self_cycles += cycles;
+        }
+        profiler.stack.clear();
+        profiler
+            .stack
+            .extend(self.call_stack.frames().iter().map(|frame| frame.target));
+
+        while profiler
+            .handling
+            .last()
+            .is_some_and(|handling| handling.depth > depth)
+        {
+            let handling = profiler.handling.pop().unwrap();
+            let interrupt = profiler.interrupts.entry(handling.vector).or_default();
+            let duration = now - handling.start;
+            interrupt.count += 1;
+            interrupt.cycles += duration;
+            interrupt.longest = interrupt.longest.max(duration);
+        }
+    }
+}
+
+fn describe<B: Bus>(cpu: &CPU<B>, address: u16) -> String {
+    match cpu.symbols.nearest(cpu.bus.bank(address), address) {
+        Some(_) => format!(
+            "${:04X} {}",
+            address,
+            cpu.symbols.describe_on(&cpu.bus, address)
+        ),
+        None => format!("${:04X}", address),
+    }
+}
+
+fn interrupt_name(vector: u16) -> &'static str {
+    match vector {
+        VBLANK_VECTOR => "VBlank",
+        LCDSTAT_VECTOR => "LCD STAT",
+        TIMER_VECTOR => "Timer",
+        SERIAL_VECTOR => "Serial",
+        JOYPAD_VECTOR => "Joypad",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // A loop calling a function that calls another twice and then halting until a
+    // timer interrupt every 4096 cycles
+    fn profiled_cpu(cycles: u64) -> CPU {
+        let rom = assemble(
+            "
+            org $0100
+                di
+                ld sp, $DFFE
+                ld a, $04
+                ldh [$FF00 + $FF], a
+                ld a, $05
+                ldh [$FF00 + $07], a
+                ei
+            loop:
+                call work       ; $010D
+                halt            ; $0110
+                jr loop
+            work:
+                call leaf       ; $0113
+                call leaf
+                ret
+            leaf:
+                nop             ; $011A
+                ret
+            org $0050
+                push af
+                pop af
+                reti
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(None, rom);
+        cpu.symbols.insert(0, 0x0113, "work");
+        cpu.symbols.insert(0, 0x011A, "leaf");
+        cpu.set_profiler(Profiler::new());
+        cpu.run(cycles);
+        cpu
+    }
+
+    #[test]
+    fn counts_cycles_by_address_and_function() {
+        let cpu = profiled_cpu(100_000);
+        let profiler = cpu.profiler().unwrap();
+        let functions: HashMap<_, _> = profiler.functions().into_iter().collect();
+        let work = functions[&0x0113];
+        let leaf = functions[&0x011A];
+        assert!(work.calls > 20);
+        assert_eq!(leaf.calls, work.calls * 2);
+        assert_eq!(leaf.cycles, leaf.calls * (4 + 16));
+        assert_eq!(work.self_cycles, work.calls * (24 + 24 + 16));
+        assert_eq!(work.cycles, work.self_cycles + leaf.cycles);
+
+        let addresses: HashMap<_, _> = profiler.addresses().into_iter().collect();
+        assert_eq!(addresses[&0x011A].runs, leaf.calls);
+        assert_eq!(addresses[&0x011A].cycles, leaf.calls * 4);
+        let instructions: u64 = addresses.values().map(|address| address.cycles).sum();
+        let interrupts: u64 = profiler.interrupts().map(|(_, timer)| timer.count).sum();
+        assert_eq!(
+            instructions + interrupts * 20 + profiler.halted_cycles(),
+            profiler.total_cycles()
+        );
+        assert!(profiler.halted_cycles() > profiler.total_cycles() / 2);
+    }
+
+    #[test]
+    fn times_interrupt_handlers() {
+        let cpu = profiled_cpu(100_000);
+        let profiler = cpu.profiler().unwrap();
+        let interrupts: Vec<_> = profiler.interrupts().collect();
+        assert_eq!(interrupts.len(), 1);
+        let (vector, timer) = interrupts[0];
+        assert_eq!(vector, TIMER_VECTOR);
+        assert!(timer.count >= 20);
+        // Dispatching, push af, pop af and reti
+        assert_eq!(timer.longest, 20 + 16 + 12 + 16);
+        assert_eq!(timer.cycles, timer.count * timer.longest);
+    }
+
+    #[test]
+    fn writes_reports() {
+        let cpu = profiled_cpu(100_000);
+        let profiler = cpu.profiler().unwrap();
+
+        let mut report = Vec::new();
+        profiler.write_report(&cpu, 5, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("Timer ($0050)"));
+        assert!(report.contains("$011A leaf\n"));
+        assert!(report.contains("  $0113 work\n"));
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&cpu, &mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let mut total = 0;
+        let mut stacks = Vec::new();
+        for line in folded.lines() {
+            let (stack, cycles) = line.split_at(line.rfind(' ').unwrap());
+            total += cycles.trim().parse::<u64>().unwrap();
+            stacks.push(stack);
+        }
+        assert_eq!(total, profiler.total_cycles());
+        assert!(stacks.contains(&"[top level]"));
+        assert!(stacks.contains(&"[top level];[halted]"));
+        assert!(stacks.contains(&"work;leaf"));
+        assert!(stacks.contains(&"$0050"));
+    }
+}
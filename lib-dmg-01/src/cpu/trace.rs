@@ -0,0 +1,265 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use super::CPU;
+use crate::bus::Bus;
+
+/// Writes a line describing the state of the CPU before each instruction it executes
+/// in the format used by [Gameboy Doctor](https://github.com/robert/gameboy-doctor):
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+/// ```
+///
+/// `PCMEM` is the 4 bytes starting at the PC. Traces from two emulators running the
+/// same ROM can be diffed to find the first instruction where they disagree. Note
+/// that Gameboy Doctor's own logs were made with LY always reading 0x90.
+///
+/// Only instructions whose address is in the traced range and which start between the
+/// start and stop cycles are written. If writing fails tracing stops and the error is
+/// returned by `finish`.
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    addresses: RangeInclusive<u16>,
+    start_cycle: u64,
+    stop_cycle: u64,
+    labels: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// Traces every instruction to `writer`
+    pub fn new<W: Write + 'static>(writer: W) -> Tracer {
+        Tracer {
+            writer: Box::new(writer),
+            addresses: 0..=0xFFFF,
+            start_cycle: 0,
+            stop_cycle: u64::MAX,
+            labels: false,
+            error: None,
+        }
+    }
+
+    /// Only traces instructions at addresses in `addresses`
+    pub fn addresses(mut self, addresses: RangeInclusive<u16>) -> Tracer {
+        self.addresses = addresses;
+        self
+    }
+
+    /// Only traces instructions starting at or after clock cycle `cycle`
+    pub fn start_at_cycle(mut self, cycle: u64) -> Tracer {
+        self.start_cycle = cycle;
+        self
+    }
+
+    /// Only traces instructions starting before clock cycle `cycle`
+    pub fn stop_at_cycle(mut self, cycle: u64) -> Tracer {
+        self.stop_cycle = cycle;
+        self
+    }
+
+    /// Writes a `label:` line before every traced instruction that has a label in the
+    /// CPU's symbols. The extra lines have to be filtered out before diffing the trace
+    /// against Gameboy Doctor's.
+    pub fn labels(mut self) -> Tracer {
+        self.labels = true;
+        self
+    }
+
+    /// Flushes the trace, returning the first error that happened while writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+
+    fn wants(&self, pc: u16, cycle: u64) -> bool {
+        self.error.is_none()
+            && self.addresses.contains(&pc)
+            && cycle >= self.start_cycle
+            && cycle < self.stop_cycle
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(error) = writeln!(self.writer, "{}", line) {
+            self.error = Some(error);
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Starts writing a trace of every instruction executed. This replaces any
+    /// tracer that was already set.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing and hands back the tracer so it can be finished
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// The current state of the CPU as a line of a Gameboy Doctor trace
+    pub fn trace_line(&self) -> String {
+        let r = &self.registers;
+        let mut line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:",
+            r.a,
+            u8::from(r.f),
+            r.b,
+            r.c,
+            r.d,
+            r.e,
+            r.h,
+            r.l,
+            self.sp,
+            self.pc
+        );
+        for offset in 0..4 {
+            if offset > 0 {
+                line.push(',');
+            }
+            let byte = self.bus.peek_byte(self.pc.wrapping_add(offset));
+            let _ = write!(line, "{:02X}", byte);
+        }
+        line
+    }
+
+    pub(super) fn trace(&mut self) {
+        let (wanted, labels) = match self.tracer {
+            Some(ref tracer) => (tracer.wants(self.pc, self.bus.cycles()), tracer.labels),
+            None => (false, false),
+        };
+        if wanted {
+            let label = match self.symbols.label_on(&self.bus, self.pc) {
+                Some(label) if labels => Some(format!("{}:", label)),
+                _ => None,
+            };
+            let line = self.trace_line();
+            if let Some(ref mut tracer) = self.tracer {
+                if let Some(label) = label {
+                    tracer.write_line(&label);
+                }
+                tracer.write_line(&line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::symbols::Symbols;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    // nop / ld a, $42 / inc b / jr -5
+    fn looping_cpu() -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0x0100, &[0x00, 0x3E, 0x42, 0x04, 0x18, 0xFB]);
+        let mut cpu = CPU::with_bus(bus);
+        cpu.pc = 0x0100;
+        cpu.sp = 0xFFFE;
+        cpu.registers.set_af(0x01B0);
+        cpu.registers.set_bc(0x0013);
+        cpu.registers.set_de(0x00D8);
+        cpu.registers.set_hl(0x014D);
+        cpu
+    }
+
+    #[test]
+    fn traces_in_gameboy_doctor_format() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = looping_cpu();
+        cpu.set_tracer(Tracer::new(buffer.clone()));
+        for _ in 0..4 {
+            cpu.step();
+        }
+        cpu.take_tracer().unwrap().finish().unwrap();
+        cpu.step();
+
+        assert_eq!(
+            buffer.lines(),
+            vec![
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,3E,42,04",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:3E,42,04,18",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FB,00",
+                "A:42 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FB,00,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_labels() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = looping_cpu();
+        cpu.symbols = Symbols::parse("00:0103 loop").unwrap();
+        cpu.set_tracer(
+            Tracer::new(buffer.clone())
+                .addresses(0x0103..=0x0104)
+                .labels(),
+        );
+        for _ in 0..5 {
+            cpu.step();
+        }
+
+        assert_eq!(
+            buffer.lines(),
+            vec![
+                "loop:",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FB,00",
+                "A:42 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FB,00,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_address_and_cycle() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = looping_cpu();
+        // `inc b` runs at cycles 12, 36, 60 and 84
+        cpu.set_tracer(
+            Tracer::new(buffer.clone())
+                .addresses(0x0103..=0x0103)
+                .start_at_cycle(13)
+                .stop_at_cycle(84),
+        );
+        for _ in 0..20 {
+            cpu.step();
+        }
+
+        assert_eq!(
+            buffer.lines(),
+            vec![
+                "A:42 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FB,00",
+                "A:42 F:10 B:02 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FB,00",
+            ]
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! Breakpoints and watchpoints for stopping the emulator when it reaches an address or
+//! touches memory.
+//!
+//! Breakpoints belong to the `CPU` and are checked by `CPU::run` before each
+//! instruction. Watchpoints belong to the `MemoryBus` and are checked on every read and
+//! write it makes. Neither is looked at while none are set.
+
+use std::cell::Cell;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::bus::Bus;
+use crate::cpu::CPU;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+const REGISTER_NAMES: [(&str, Register); 14] = [
+    ("a", Register::A),
+    ("b", Register::B),
+    ("c", Register::C),
+    ("d", Register::D),
+    ("e", Register::E),
+    ("f", Register::F),
+    ("h", Register::H),
+    ("l", Register::L),
+    ("af", Register::AF),
+    ("bc", Register::BC),
+    ("de", Register::DE),
+    ("hl", Register::HL),
+    ("sp", Register::SP),
+    ("pc", Register::PC),
+];
+
+impl Register {
+    pub fn value<B: Bus>(self, cpu: &CPU<B>) -> u16 {
+        let r = &cpu.registers;
+        match self {
+            Register::A => r.a as u16,
+            Register::B => r.b as u16,
+            Register::C => r.c as u16,
+            Register::D => r.d as u16,
+            Register::E => r.e as u16,
+            Register::F => u8::from(r.f) as u16,
+            Register::H => r.h as u16,
+            Register::L => r.l as u16,
+            Register::AF => r.get_af(),
+            Register::BC => r.get_bc(),
+            Register::DE => r.get_de(),
+            Register::HL => r.get_hl(),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        REGISTER_NAMES
+            .iter()
+            .find(|(_, register)| *register == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+}
+
+impl FromStr for Register {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Register, String> {
+        let name = s.to_lowercase();
+        REGISTER_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, register)| *register)
+            .ok_or_else(|| format!("Unknown register '{}'", s))
+    }
+}
+
+/// Parses a number in decimal or in hex with a `$` or `0x` prefix
+pub fn parse_number(s: &str) -> Result<u16, String> {
+    let result = if let Some(hex) = s.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    result.map_err(|_| format!("'{}' is not a number between 0 and $FFFF", s))
+}
+
+/// One side of a `Condition`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Value(u16),
+    Register(Register),
+    /// The byte at an address, written `[$C000]`
+    Memory(u16),
+    /// The byte at the address held in a register, written `[hl]`
+    Indirect(Register),
+}
+
+impl Operand {
+    pub fn value<B: Bus>(self, cpu: &CPU<B>) -> u16 {
+        match self {
+            Operand::Value(value) => value,
+            Operand::Register(register) => register.value(cpu),
+            Operand::Memory(address) => cpu.bus.peek_byte(address) as u16,
+            Operand::Indirect(register) => cpu.bus.peek_byte(register.value(cpu)) as u16,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Operand, String> {
+        let s = s.trim();
+        if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            return match inner.trim().parse()? {
+                Operand::Value(address) => Ok(Operand::Memory(address)),
+                Operand::Register(register) => Ok(Operand::Indirect(register)),
+                _ => Err(format!("'{}' can't be used as an address", inner)),
+            };
+        }
+        if s.starts_with(|c: char| c == '$' || c.is_ascii_digit()) {
+            parse_number(s).map(Operand::Value)
+        } else {
+            s.parse().map(Operand::Register)
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Value(value) if value <= 0xFF => write!(f, "${:02X}", value),
+            Operand::Value(value) => write!(f, "${:04X}", value),
+            Operand::Register(register) => write!(f, "{}", register.name()),
+            Operand::Memory(address) => write!(f, "[${:04X}]", address),
+            Operand::Indirect(register) => write!(f, "[{}]", register.name()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// Longer symbols come first so `<=` isn't mistaken for `<`
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+/// A comparison between registers, memory and numbers such as `a == $42`,
+/// `[hl] != 0` or `sp < $DF00`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub left: Operand,
+    pub comparison: Comparison,
+    pub right: Operand,
+}
+
+impl Condition {
+    pub fn holds<B: Bus>(&self, cpu: &CPU<B>) -> bool {
+        let (left, right) = (self.left.value(cpu), self.right.value(cpu));
+        match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        for (symbol, comparison) in COMPARISONS.iter() {
+            if let Some(index) = s.find(symbol) {
+                return Ok(Condition {
+                    left: s[..index].parse()?,
+                    comparison: *comparison,
+                    right: s[index + symbol.len()..].parse()?,
+                });
+            }
+        }
+        Err(format!(
+            "'{}' does not compare two values (e.g. a == $42)",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = COMPARISONS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .map(|(symbol, _)| *symbol)
+            .unwrap();
+        write!(f, "{} {} {}", self.left, symbol, self.right)
+    }
+}
+
+/// Stops execution before the instruction at `address` runs, or only when `condition`
+/// holds if there is one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            condition: None,
+        }
+    }
+
+    /// Only stops when `condition` holds
+    pub fn when(mut self, condition: Condition) -> Breakpoint {
+        self.condition = Some(condition);
+        self
+    }
+}
+
+/// The breakpoints set on a CPU. Each one gets a number, starting at 1, which is used
+/// to remove it and to report when it stops execution.
+#[derive(Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<(usize, Breakpoint)>,
+    last_id: usize,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        self.last_id += 1;
+        self.breakpoints.push((self.last_id, breakpoint));
+        self.last_id
+    }
+
+    /// Removes the breakpoint numbered `id`, returning whether there was one
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|(i, _)| *i != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(usize, Breakpoint)> {
+        self.breakpoints.iter()
+    }
+
+    /// The first breakpoint that stops `cpu` where it is now
+    pub fn triggered<B: Bus>(&self, cpu: &CPU<B>) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| {
+                breakpoint.address == cpu.pc
+                    && match breakpoint.condition {
+                        Some(ref condition) => condition.holds(cpu),
+                        None => true,
+                    }
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write which changes the value in memory
+    Change,
+    /// A read or a write
+    Access,
+}
+
+/// Stops execution when an address in `addresses` is accessed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addresses: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+/// The access which triggered a watchpoint. `kind` is the kind of watchpoint except
+/// that `Access` watchpoints give the kind of access (`Read` or `Write`). For reads
+/// `old` and `new` are both the value read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchpointHit {
+    pub id: usize,
+    pub kind: WatchKind,
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// The watchpoints set on a `MemoryBus`, numbered like `Breakpoints`.
+///
+/// Reads happen through `&self` so the first hit is kept in a `Cell` until it is
+/// taken.
+#[derive(Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<(usize, Watchpoint)>,
+    last_id: usize,
+    hit: Cell<Option<WatchpointHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.last_id += 1;
+        self.watchpoints.push((self.last_id, watchpoint));
+        self.last_id
+    }
+
+    /// Removes the watchpoint numbered `id`, returning whether there was one
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|(i, _)| *i != id);
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+        self.hit.set(None);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(usize, Watchpoint)> {
+        self.watchpoints.iter()
+    }
+
+    /// The first watchpoint triggered since this was last called
+    pub fn take_hit(&mut self) -> Option<WatchpointHit> {
+        self.hit.take()
+    }
+
+    pub(crate) fn watches_changes_to(&self, address: u16) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|(_, w)| w.kind == WatchKind::Change && w.addresses.contains(&address))
+    }
+
+    pub(crate) fn check(&self, kind: WatchKind, address: u16, old: u8, new: u8) {
+        if self.hit.get().is_some() {
+            return;
+        }
+        let triggered = self.watchpoints.iter().find(|(_, w)| {
+            w.addresses.contains(&address)
+                && match w.kind {
+                    WatchKind::Read => kind == WatchKind::Read,
+                    WatchKind::Write => kind == WatchKind::Write,
+                    WatchKind::Change => kind == WatchKind::Write && old != new,
+                    WatchKind::Access => true,
+                }
+        });
+        if let Some((id, watchpoint)) = triggered {
+            self.hit.set(Some(WatchpointHit {
+                id: *id,
+                kind: if watchpoint.kind == WatchKind::Access {
+                    kind
+                } else {
+                    watchpoint.kind
+                },
+                address,
+                old,
+                new,
+            }));
+        }
+    }
+}
+
+/// Why `CPU::run` stopped before running for as long as it was asked to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The numbered breakpoint was reached. The instruction there has not run yet.
+    Breakpoint(usize),
+    /// The last instruction triggered a watchpoint
+    Watchpoint(WatchpointHit),
+    /// The last instruction was an `LD B,B` with software breakpoints enabled
+    SoftwareBreakpoint,
+}
+
+impl<B: Bus> CPU<B> {
+    /// Runs for at least `cycles` clock cycles unless a breakpoint, watchpoint or
+    /// software breakpoint stops execution first.
+    ///
+    /// A breakpoint at the current PC doesn't stop execution before the first
+    /// instruction, so calling `run` again continues after a breakpoint stopped it.
+    pub fn run(&mut self, cycles: u64) -> Option<Stop> {
+        // The cycles each step takes are added up rather than read from the bus, whose
+        // clock doesn't move while the CPU is stopped
+        self.run_counting(|_, elapsed| elapsed >= cycles)
+    }
+
+    /// Runs until `done`, which is asked before each instruction, returns true unless
+    /// a breakpoint, watchpoint or software breakpoint stops execution first. Like
+    /// `run` a breakpoint at the current PC is skipped.
+    pub fn run_until<F>(&mut self, mut done: F) -> Option<Stop>
+    where
+        F: FnMut(&CPU<B>) -> bool,
+    {
+        self.run_counting(|cpu, _| done(cpu))
+    }
+
+    // Like `run_until` but `done` is also given the clock cycles run so far
+    fn run_counting<F>(&mut self, mut done: F) -> Option<Stop>
+    where
+        F: FnMut(&CPU<B>, u64) -> bool,
+    {
+        // Anything accessed before running doesn't count
+        self.bus.take_watchpoint_hit();
+        let mut first = true;
+        let mut elapsed = 0;
+        while !done(self, elapsed) {
+            if !first && !self.breakpoints.is_empty() && !self.is_halted() {
+                if let Some(id) = self.breakpoints.triggered(self) {
+                    return Some(Stop::Breakpoint(id));
+                }
+            }
+            first = false;
+            elapsed += self.step() as u64;
+            if self.hit_software_breakpoint() {
+                return Some(Stop::SoftwareBreakpoint);
+            }
+            if let Some(hit) = self.bus.take_watchpoint_hit() {
+                return Some(Stop::Watchpoint(hit));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn cpu_running(source: &str) -> CPU {
+        let rom = assemble(source).unwrap();
+        CPU::new(None, rom)
+    }
+
+    #[test]
+    fn parses_conditions() {
+        let condition: Condition = "a == $42".parse().unwrap();
+        assert_eq!(
+            condition,
+            Condition {
+                left: Operand::Register(Register::A),
+                comparison: Comparison::Equal,
+                right: Operand::Value(0x42),
+            }
+        );
+        let condition: Condition = "[hl]>=0x10".parse().unwrap();
+        assert_eq!(condition.left, Operand::Indirect(Register::HL));
+        assert_eq!(condition.comparison, Comparison::GreaterOrEqual);
+        assert_eq!(condition.right, Operand::Value(0x10));
+        let condition: Condition = "[$C000] != SP".parse().unwrap();
+        assert_eq!(condition.to_string(), "[$C000] != sp");
+
+        assert!("a".parse::<Condition>().is_err());
+        assert!("x == 1".parse::<Condition>().is_err());
+        assert!("a == $10000".parse::<Condition>().is_err());
+        assert!("[[hl]] == 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut cpu = cpu_running(
+            "org $0100
+            ld b, 0
+            loop:
+            inc b
+            jr loop",
+        );
+        let id = cpu.breakpoints.add(Breakpoint::new(0x0102));
+        let condition = "b == 3".parse().unwrap();
+        let conditional = cpu.breakpoints.add(Breakpoint::new(0x0103).when(condition));
+
+        assert_eq!(cpu.run(1000), Some(Stop::Breakpoint(id)));
+        assert_eq!((cpu.pc, cpu.registers.b), (0x0102, 0));
+        assert_eq!(cpu.run(1000), Some(Stop::Breakpoint(id)));
+        assert_eq!((cpu.pc, cpu.registers.b), (0x0102, 1));
+
+        assert!(cpu.breakpoints.remove(id));
+        assert!(!cpu.breakpoints.remove(id));
+        assert_eq!(cpu.run(1000), Some(Stop::Breakpoint(conditional)));
+        assert_eq!((cpu.pc, cpu.registers.b), (0x0103, 3));
+
+        cpu.breakpoints.clear();
+        let start = cpu.bus.cycles();
+        assert_eq!(cpu.run(1000), None);
+        assert!(cpu.bus.cycles() - start >= 1000);
+        assert_eq!(cpu.run_until(|cpu| cpu.registers.b == 200), None);
+        assert_eq!(cpu.registers.b, 200);
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        let mut cpu = cpu_running(
+            "org $0100
+            ld hl, $C000
+            ld [hl], 1
+            ld [hl], 1
+            ld a, [$C001]
+            ld [hl], 2",
+        );
+        let write = cpu.bus.watchpoints.add(Watchpoint {
+            addresses: 0xC000..=0xC000,
+            kind: WatchKind::Write,
+        });
+        let change = cpu.bus.watchpoints.add(Watchpoint {
+            addresses: 0xC000..=0xC000,
+            kind: WatchKind::Change,
+        });
+        let access = cpu.bus.watchpoints.add(Watchpoint {
+            addresses: 0xC001..=0xC0FF,
+            kind: WatchKind::Access,
+        });
+        // Looking at memory from outside doesn't trigger anything
+        assert_eq!(cpu.bus.peek_byte(0xC001), 0);
+
+        let hit = |id, kind, address, old, new| {
+            Some(Stop::Watchpoint(WatchpointHit {
+                id,
+                kind,
+                address,
+                old,
+                new,
+            }))
+        };
+        assert_eq!(cpu.run(1000), hit(write, WatchKind::Write, 0xC000, 0, 1));
+        cpu.bus.watchpoints.remove(write);
+        assert_eq!(cpu.run(1000), hit(access, WatchKind::Read, 0xC001, 0, 0));
+        assert_eq!(cpu.pc, 0x010A);
+        assert_eq!(cpu.run(1000), hit(change, WatchKind::Change, 0xC000, 1, 2));
+    }
+
+    #[test]
+    fn runs_while_stopped() {
+        let mut cpu = cpu_running(
+            "org $0100
+            stop
+            nop",
+        );
+        let start = cpu.bus.cycles();
+        assert_eq!(cpu.run(1000), None);
+        assert_eq!(cpu.pc, 0x0102);
+        // Nothing but the CPU moves until a button is pressed
+        assert_eq!(cpu.bus.cycles() - start, 4);
+    }
+
+    #[test]
+    fn dispatching_interrupts_does_not_trigger_watchpoints() {
+        let mut cpu = cpu_running(
+            "org $0100
+            nop
+            nop",
+        );
+        cpu.bus.poke_byte(0xFFFF, 0b1);
+        cpu.bus.poke_byte(0xFF0F, 0b1);
+        cpu.bus.watchpoints.add(Watchpoint {
+            addresses: 0xFFFF..=0xFFFF,
+            kind: WatchKind::Read,
+        });
+        cpu.bus.watchpoints.add(Watchpoint {
+            addresses: 0xFF0F..=0xFF0F,
+            kind: WatchKind::Change,
+        });
+
+        assert_eq!(cpu.run_until(|cpu| cpu.pc == 0x0040), None);
+        assert_eq!(cpu.bus.peek_byte(0xFF0F) & 0b1, 0);
+    }
+
+    #[test]
+    fn stops_at_software_breakpoints() {
+        let mut cpu = cpu_running(
+            "org $0100
+            nop
+            ld b, b
+            nop",
+        );
+        assert_eq!(cpu.run(1000), None);
+
+        let mut cpu = cpu_running(
+            "org $0100
+            nop
+            ld b, b
+            nop",
+        );
+        cpu.set_software_breakpoints(true);
+        assert_eq!(cpu.run(1000), Some(Stop::SoftwareBreakpoint));
+        assert_eq!(cpu.pc, 0x0102);
+    }
+}
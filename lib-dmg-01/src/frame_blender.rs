@@ -0,0 +1,101 @@
+/// Mixes every new frame with the previously displayed output to imitate the slow
+/// response time of the original DMG LCD.
+///
+/// Some games flicker sprites on alternate frames and rely on the LCD to blend them
+/// into a steady (if slightly transparent) image. Without this ghosting such sprites
+/// strobe on modern displays.
+pub struct FrameBlender {
+    // The weight of the previous output out of 256
+    previous_weight: u16,
+    previous: Vec<u8>,
+}
+
+impl FrameBlender {
+    /// Creates a blender where `ratio` is how much of the previous output bleeds into
+    /// each new frame. A ratio of 0.0 leaves frames untouched, 0.5 is an even mix and
+    /// values approaching 1.0 leave longer and longer trails.
+    pub fn new(ratio: f32) -> FrameBlender {
+        let mut blender = FrameBlender {
+            previous_weight: 0,
+            previous: Vec::new(),
+        };
+        blender.set_ratio(ratio);
+        blender
+    }
+
+    pub fn ratio(&self) -> f32 {
+        self.previous_weight as f32 / 256.0
+    }
+
+    /// Sets how much of the previous output bleeds into each new frame. Ratios are
+    /// clamped to between 0.0 and 255/256 so new frames always show through: at 1.0
+    /// the screen would never change again.
+    pub fn set_ratio(&mut self, ratio: f32) {
+        let ratio = if ratio.is_nan() {
+            0.0
+        } else {
+            ratio.clamp(0.0, 1.0)
+        };
+        self.previous_weight = ((ratio * 256.0) as u16).min(255);
+    }
+
+    /// Blends an RGBA `frame` (such as the GPU's canvas buffer) with the previous output
+    /// and returns the result. This should be called once per displayed frame.
+    pub fn blend(&mut self, frame: &[u8]) -> &[u8] {
+        if self.previous.len() != frame.len() {
+            // Nothing to blend with yet
+            self.previous = frame.to_vec();
+            return &self.previous;
+        }
+
+        // The difference from the new frame is scaled down rather than the two frames
+        // summed so the rounding is always towards the new frame. Otherwise at high
+        // ratios the output can get stuck short of it.
+        let previous_weight = self.previous_weight as i32;
+        for (previous, new) in self.previous.chunks_mut(4).zip(frame.chunks(4)) {
+            for channel in 0..3 {
+                let difference = previous[channel] as i32 - new[channel] as i32;
+                previous[channel] =
+                    (new[channel] as i32 + difference * previous_weight / 256) as u8;
+            }
+            // The alpha channel is always taken from the new frame
+            previous[3] = new[3];
+        }
+
+        &self.previous
+    }
+
+    /// Forgets the previous output so the next frame is shown as is.
+    pub fn reset(&mut self) {
+        self.previous.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blends_with_previous_output() {
+        let mut blender = FrameBlender::new(0.5);
+        assert_eq!(blender.blend(&[0, 0, 0, 255]), &[0, 0, 0, 255]);
+        assert_eq!(blender.blend(&[200, 100, 0, 255]), &[100, 50, 0, 255]);
+        assert_eq!(blender.blend(&[200, 100, 0, 255]), &[150, 75, 0, 255]);
+
+        let mut blender = FrameBlender::new(0.0);
+        blender.blend(&[0, 0, 0, 255]);
+        assert_eq!(blender.blend(&[200, 100, 0, 255]), &[200, 100, 0, 255]);
+    }
+
+    #[test]
+    fn new_frames_always_show_through() {
+        let mut blender = FrameBlender::new(1.0);
+        assert!(blender.ratio() < 1.0);
+        blender.blend(&[0, 0, 0, 255]);
+        assert_eq!(blender.blend(&[255, 255, 255, 255]), &[1, 1, 1, 255]);
+        for _ in 0..2000 {
+            blender.blend(&[255, 255, 255, 255]);
+        }
+        assert_eq!(blender.blend(&[255, 255, 255, 255]), &[255, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,506 @@
+//! A stub for the [GDB remote serial protocol](https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html)
+//! so GDB (or anything else speaking the protocol) can debug the emulator over TCP with
+//! `target remote localhost:PORT`.
+//!
+//! GDB has no description of the SM83 so the registers are sent as six 16 bit little
+//! endian values: AF, BC, DE, HL, SP and PC. Software and hardware breakpoints (`Z0`
+//! and `Z1`) both become `CPU` breakpoints and write, read and access watchpoints (`Z2`
+//! to `Z4`) become `MemoryBus` watchpoints.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::CPU;
+use crate::debugger::{Breakpoint, Stop, WatchKind, Watchpoint};
+
+// How often a running CPU checks whether the client has asked it to stop, in steps
+// (about a frame). Steps are counted rather than clock cycles since the clock doesn't
+// move while the CPU is stopped.
+const POLL_STEPS: u32 = 70224 / 4;
+
+// The byte a client sends to interrupt a running target (Ctrl-C)
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const ERROR: &str = "E01";
+
+/// Waits for a client to connect to `address` and then serves it
+pub fn listen<A: ToSocketAddrs>(cpu: &mut CPU, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    serve(cpu, stream)
+}
+
+/// Serves the client connected over `stream` until it detaches, kills the target or
+/// disconnects. Breakpoints and watchpoints the client set are removed at the end.
+pub fn serve(cpu: &mut CPU, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut session = Session {
+        cpu,
+        stream,
+        breakpoints: HashMap::new(),
+        watchpoints: HashMap::new(),
+    };
+    let result = session.run();
+    for id in session.breakpoints.values() {
+        session.cpu.breakpoints.remove(*id);
+    }
+    for id in session.watchpoints.values() {
+        session.cpu.bus.watchpoints.remove(*id);
+    }
+    result
+}
+
+struct Session<'a> {
+    cpu: &'a mut CPU,
+    stream: TcpStream,
+    // The numbers of the breakpoints and watchpoints the client set, keyed by the
+    // arguments of the packet that set them so the matching `z` packet can remove them
+    breakpoints: HashMap<u16, usize>,
+    watchpoints: HashMap<(u8, u16, u16), usize>,
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.chars().next() {
+                Some('k') => return Ok(()),
+                Some('D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some('c') => self.resume()?,
+                Some('s') => {
+                    let mut stepped = false;
+                    let stop = self
+                        .cpu
+                        .run_until(|_| std::mem::replace(&mut stepped, true));
+                    self.stop_reply(stop)
+                }
+                _ => self.reply(&packet).unwrap_or_else(|| ERROR.to_string()),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// The reply to every packet which doesn't run the CPU or end the session, or
+    /// `None` when the packet is malformed or empty
+    fn reply(&mut self, packet: &str) -> Option<String> {
+        let mut chars = packet.chars();
+        let command = chars.next()?;
+        let arguments = chars.as_str();
+        let reply = match command {
+            '?' => format!("S{:02x}", SIGTRAP),
+            'g' => (0..6).map(|n| little_endian(self.register(n))).collect(),
+            'G' => {
+                let bytes = hex_bytes(arguments)?;
+                if bytes.len() != 12 {
+                    return None;
+                }
+                for (n, pair) in bytes.chunks(2).enumerate() {
+                    self.set_register(n, pair[0] as u16 | (pair[1] as u16) << 8);
+                }
+                "OK".to_string()
+            }
+            'p' => {
+                let n = usize::from_str_radix(arguments, 16)
+                    .ok()
+                    .filter(|n| *n < 6)?;
+                little_endian(self.register(n))
+            }
+            'P' => {
+                let (n, value) = split(arguments, '=')?;
+                let n = usize::from_str_radix(n, 16).ok().filter(|n| *n < 6)?;
+                let bytes = hex_bytes(value)?;
+                if bytes.len() != 2 {
+                    return None;
+                }
+                self.set_register(n, bytes[0] as u16 | (bytes[1] as u16) << 8);
+                "OK".to_string()
+            }
+            'm' => {
+                let (address, length) = split(arguments, ',')?;
+                let (address, length) = (hex(address)?, hex(length)?);
+                (0..length)
+                    .map(|offset| self.cpu.bus.peek_byte(address.wrapping_add(offset)))
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            }
+            'M' => {
+                let (location, data) = split(arguments, ':')?;
+                let (address, length) = split(location, ',')?;
+                let (address, length) = (hex(address)?, hex(length)?);
+                let bytes = hex_bytes(data)?;
+                if bytes.len() != length as usize {
+                    return None;
+                }
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    self.cpu
+                        .bus
+                        .poke_byte(address.wrapping_add(offset as u16), byte);
+                }
+                "OK".to_string()
+            }
+            'Z' | 'z' => {
+                let mut parts = arguments.split(',');
+                let kind = parts.next()?.parse().ok()?;
+                let address = hex(parts.next()?)?;
+                let length = hex(parts.next()?)?;
+                if command == 'Z' {
+                    self.insert(kind, address, length)?;
+                } else {
+                    self.remove(kind, address, length)?;
+                }
+                "OK".to_string()
+            }
+            'H' => "OK".to_string(),
+            'q' if arguments.starts_with("Supported") => "PacketSize=1000".to_string(),
+            'q' if arguments == "Attached" => "1".to_string(),
+            // An empty reply tells the client the packet isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn insert(&mut self, kind: u8, address: u16, length: u16) -> Option<()> {
+        match kind {
+            0 | 1 => {
+                if !self.breakpoints.contains_key(&address) {
+                    let id = self.cpu.breakpoints.add(Breakpoint::new(address));
+                    self.breakpoints.insert(address, id);
+                }
+            }
+            2..=4 => {
+                let watchpoint = Watchpoint {
+                    addresses: address..=address.saturating_add(length.max(1) - 1),
+                    kind: match kind {
+                        2 => WatchKind::Write,
+                        3 => WatchKind::Read,
+                        _ => WatchKind::Access,
+                    },
+                };
+                let id = self.cpu.bus.watchpoints.add(watchpoint);
+                if let Some(old) = self.watchpoints.insert((kind, address, length), id) {
+                    self.cpu.bus.watchpoints.remove(old);
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn remove(&mut self, kind: u8, address: u16, length: u16) -> Option<()> {
+        match kind {
+            0 | 1 => {
+                let id = self.breakpoints.remove(&address)?;
+                self.cpu.breakpoints.remove(id);
+            }
+            2..=4 => {
+                let id = self.watchpoints.remove(&(kind, address, length))?;
+                self.cpu.bus.watchpoints.remove(id);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // Runs until something stops the CPU or the client interrupts it
+    fn resume(&mut self) -> io::Result<String> {
+        let stream = &mut self.stream;
+        let mut steps = 0;
+        let mut interrupted = Ok(false);
+        let stop = self.cpu.run_until(|_| {
+            steps += 1;
+            if steps < POLL_STEPS {
+                return false;
+            }
+            steps = 0;
+            interrupted = poll_interrupt(stream);
+            !matches!(interrupted, Ok(false))
+        });
+        if interrupted? {
+            return Ok(format!("S{:02x}", SIGINT));
+        }
+        Ok(self.stop_reply(stop))
+    }
+
+    fn stop_reply(&self, stop: Option<Stop>) -> String {
+        if let Some(Stop::Watchpoint(hit)) = stop {
+            let kind = self
+                .watchpoints
+                .iter()
+                .find(|(_, id)| **id == hit.id)
+                .map(|((kind, _, _), _)| *kind);
+            let name = match kind {
+                Some(3) => "rwatch",
+                Some(4) => "awatch",
+                _ => "watch",
+            };
+            return format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.address);
+        }
+        format!("S{:02x}", SIGTRAP)
+    }
+
+    fn register(&self, n: usize) -> u16 {
+        let r = &self.cpu.registers;
+        match n {
+            0 => r.get_af(),
+            1 => r.get_bc(),
+            2 => r.get_de(),
+            3 => r.get_hl(),
+            4 => self.cpu.sp,
+            _ => self.cpu.pc,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u16) {
+        let r = &mut self.cpu.registers;
+        match n {
+            0 => r.set_af(value),
+            1 => r.set_bc(value),
+            2 => r.set_de(value),
+            3 => r.set_hl(value),
+            4 => self.cpu.sp = value,
+            _ => self.cpu.pc = value,
+        }
+    }
+
+    /// Reads the next packet, acknowledging it, or `None` once the client disconnects.
+    /// Acknowledgements from the client and interrupts while stopped are skipped.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let checksum = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if checksum == Some(sum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            return match self.stream.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, sum(data.as_bytes()))
+    }
+}
+
+// Checks, without waiting, whether the client has sent an interrupt
+fn poll_interrupt(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(0) => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "the client disconnected",
+        )),
+        Ok(_) => Ok(byte[0] == INTERRUPT),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn split(s: &str, separator: char) -> Option<(&str, &str)> {
+    let index = s.find(separator)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+fn hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// A string of odd length fails on its last byte, which has no pair
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn little_endian(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use std::thread;
+    use std::time::Duration;
+
+    // Speaks just enough of the protocol to drive the stub
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, packet: &str) {
+            write!(self.stream, "${}#{:02x}", packet, sum(packet.as_bytes())).unwrap();
+        }
+
+        fn receive(&mut self) -> String {
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            let data = String::from_utf8(data).unwrap();
+            assert_eq!(
+                std::str::from_utf8(&checksum).unwrap(),
+                format!("{:02x}", sum(data.as_bytes()))
+            );
+            data
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.receive()
+        }
+    }
+
+    #[test]
+    fn serves_a_client() {
+        let rom = assemble(
+            "
+            org $0100
+                ld hl, $C000
+            loop:
+                inc a
+                nop
+                nop
+                ld [hl], a
+                jr loop
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(None, rom);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(address).unwrap(),
+            };
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("qSupported:swbreak+"), "PacketSize=1000");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+            assert_eq!(client.request("g"), "80011300d8004d01feff0001");
+            assert_eq!(client.request("m100,4"), "2100c03c");
+            assert_eq!(client.request("mzz"), "E01");
+            assert_eq!(client.request(""), "E01");
+            assert_eq!(client.request("\u{e9}"), "");
+            assert_eq!(client.request("Mc000,2:abcd"), "OK");
+            assert_eq!(client.request("mc000,2"), "abcd");
+
+            assert_eq!(client.request("Z0,106,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "0601");
+            assert_eq!(client.request("p0"), "0002");
+            assert_eq!(client.request("z0,106,1"), "OK");
+            assert_eq!(client.request("z0,106,1"), "E01");
+
+            assert_eq!(client.request("Z2,c000,1"), "OK");
+            assert_eq!(client.request("c"), "T05watch:c000;");
+            assert_eq!(client.request("p5"), "0701");
+            assert_eq!(client.request("z2,c000,1"), "OK");
+            assert_eq!(client.request("Z4,bfff,2"), "OK");
+            assert_eq!(client.request("c"), "T05awatch:c000;");
+            assert_eq!(client.request("z4,bfff,2"), "OK");
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p5"), "0301");
+            assert_eq!(client.request("G0011130000004d01feff0301"), "OK");
+            assert_eq!(client.request("P3=00c0"), "OK");
+            assert_eq!(client.request("g"), "00111300000000c0feff0301");
+
+            // The loop never ends so only an interrupt stops it
+            client.send("c");
+            thread::sleep(Duration::from_millis(50));
+            client.stream.write_all(&[INTERRUPT]).unwrap();
+            assert_eq!(client.receive(), "S02");
+
+            assert_eq!(client.request("Z0,103,1"), "OK");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut cpu, stream).unwrap();
+        client.join().unwrap();
+        assert!(cpu.breakpoints.is_empty());
+        assert!(cpu.bus.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn interrupts_a_stopped_target() {
+        let rom = assemble("org $0100\n stop\n nop\n").unwrap();
+        let mut cpu = CPU::new(None, rom);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(address).unwrap(),
+            };
+            // No button is ever pressed so the CPU stays stopped
+            client.send("c");
+            thread::sleep(Duration::from_millis(50));
+            client.stream.write_all(&[INTERRUPT]).unwrap();
+            assert_eq!(client.receive(), "S02");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut cpu, stream).unwrap();
+        client.join().unwrap();
+        assert_eq!(cpu.pc, 0x0102);
+    }
+}
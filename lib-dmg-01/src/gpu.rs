@@ -1,6 +1,7 @@
 use std;
 
 use crate::memory_bus::{OAM_SIZE, VRAM_BEGIN, VRAM_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
 
 const NUMBER_OF_OBJECTS: usize = 40;
 #[cfg_attr(feature = "serialize", derive(Serialize))]
@@ -24,6 +25,18 @@ impl std::convert::From<u8> for Color {
     }
 }
 
+// The shade a color is written to the palette registers as
+impl std::convert::From<Color> for u8 {
+    fn from(value: Color) -> Self {
+        match value {
+            Color::White => 0,
+            Color::LightGray => 1,
+            Color::DarkGray => 2,
+            Color::Black => 3,
+        }
+    }
+}
+
 #[cfg_attr(feature = "serialize", derive(Serialize))]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct BackgroundColors(Color, Color, Color, Color);
@@ -50,6 +63,13 @@ impl std::convert::From<u8> for BackgroundColors {
     }
 }
 
+impl std::convert::From<BackgroundColors> for u8 {
+    fn from(value: BackgroundColors) -> Self {
+        let BackgroundColors(color_0, color_1, color_2, color_3) = value;
+        u8::from(color_3) << 6 | u8::from(color_2) << 4 | u8::from(color_1) << 2 | u8::from(color_0)
+    }
+}
+
 #[cfg_attr(feature = "serialize", derive(Serialize))]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TileMap {
@@ -257,6 +277,122 @@ impl GPU {
         }
     }
 
+    // The tile set and object data are decoded from VRAM and OAM so they're rebuilt
+    // rather than saved
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.canvas_buffer);
+        writer.bytes(&self.vram);
+        writer.bytes(&self.oam);
+        let BackgroundColors(color_0, color_1, color_2, color_3) = self.background_colors;
+        for color in &[color_0, color_1, color_2, color_3] {
+            writer.u8((*color).into());
+        }
+        writer.u8(self.viewport_x_offset);
+        writer.u8(self.viewport_y_offset);
+        writer.bool(self.lcd_display_enabled);
+        writer.bool(self.window_display_enabled);
+        writer.bool(self.background_display_enabled);
+        writer.bool(self.object_display_enabled);
+        writer.bool(self.line_equals_line_check_interrupt_enabled);
+        writer.bool(self.oam_interrupt_enabled);
+        writer.bool(self.vblank_interrupt_enabled);
+        writer.bool(self.hblank_interrupt_enabled);
+        writer.u8(self.line_check);
+        writer.bool(self.line_equals_line_check);
+        writer.bool(self.window_tile_map == TileMap::X9C00);
+        writer.bool(self.background_tile_map == TileMap::X9C00);
+        writer.bool(self.background_and_window_data_select == BackgroundAndWindowDataSelect::X8000);
+        writer.bool(self.object_size == ObjectSize::OS8X16);
+        for color in &[
+            self.obj_0_color_1,
+            self.obj_0_color_2,
+            self.obj_0_color_3,
+            self.obj_1_color_1,
+            self.obj_1_color_2,
+            self.obj_1_color_3,
+        ] {
+            writer.u8((*color).into());
+        }
+        writer.u8(self.window.x);
+        writer.u8(self.window.y);
+        writer.u8(self.line);
+        writer.u8(self.mode.into());
+        writer.u16(self.cycles);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let color = |reader: &mut StateReader| -> Result<Color, StateError> {
+            match reader.u8()? {
+                shade @ 0..=3 => Ok(shade.into()),
+                _ => Err(StateError::Mismatch("unknown color")),
+            }
+        };
+        let tile_map = |x9c00| {
+            if x9c00 {
+                TileMap::X9C00
+            } else {
+                TileMap::X9800
+            }
+        };
+
+        self.canvas_buffer
+            .copy_from_slice(reader.bytes(SCREEN_WIDTH * SCREEN_HEIGHT * 4)?);
+        for (index, value) in reader.bytes(VRAM_SIZE)?.iter().enumerate() {
+            self.write_vram(index, *value);
+        }
+        for (index, value) in reader.bytes(OAM_SIZE)?.iter().enumerate() {
+            self.write_oam(index, *value);
+        }
+        self.background_colors = BackgroundColors(
+            color(reader)?,
+            color(reader)?,
+            color(reader)?,
+            color(reader)?,
+        );
+        self.viewport_x_offset = reader.u8()?;
+        self.viewport_y_offset = reader.u8()?;
+        self.lcd_display_enabled = reader.bool()?;
+        self.window_display_enabled = reader.bool()?;
+        self.background_display_enabled = reader.bool()?;
+        self.object_display_enabled = reader.bool()?;
+        self.line_equals_line_check_interrupt_enabled = reader.bool()?;
+        self.oam_interrupt_enabled = reader.bool()?;
+        self.vblank_interrupt_enabled = reader.bool()?;
+        self.hblank_interrupt_enabled = reader.bool()?;
+        self.line_check = reader.u8()?;
+        self.line_equals_line_check = reader.bool()?;
+        self.window_tile_map = tile_map(reader.bool()?);
+        self.background_tile_map = tile_map(reader.bool()?);
+        self.background_and_window_data_select = if reader.bool()? {
+            BackgroundAndWindowDataSelect::X8000
+        } else {
+            BackgroundAndWindowDataSelect::X8800
+        };
+        self.object_size = if reader.bool()? {
+            ObjectSize::OS8X16
+        } else {
+            ObjectSize::OS8X8
+        };
+        self.obj_0_color_1 = color(reader)?;
+        self.obj_0_color_2 = color(reader)?;
+        self.obj_0_color_3 = color(reader)?;
+        self.obj_1_color_1 = color(reader)?;
+        self.obj_1_color_2 = color(reader)?;
+        self.obj_1_color_3 = color(reader)?;
+        self.window.x = reader.u8()?;
+        self.window.y = reader.u8()?;
+        self.line = reader.u8()?;
+        self.mode = match reader.u8()? {
+            0 => Mode::HorizontalBlank,
+            1 => Mode::VerticalBlank,
+            2 => Mode::OAMAccess,
+            3 => Mode::VRAMAccess,
+            _ => return Err(StateError::Mismatch("unknown GPU mode")),
+        };
+        self.cycles = reader.u16()?;
+        Ok(())
+    }
+
     pub fn write_vram(&mut self, index: usize, value: u8) {
         self.vram[index] = value;
         if index >= 0x1800 {
@@ -1,3 +1,4 @@
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::utils::bit;
 
 #[cfg_attr(feature = "serialize", derive(Serialize))]
@@ -59,6 +60,53 @@ impl Joypad {
         column_bit | row_bits
     }
 
+    /// The buttons held down, one bit each from A (bit 0) through B, Select, Start,
+    /// Right, Left, Up to Down (bit 7)
+    pub fn buttons(&self) -> u8 {
+        let held = [
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+        ];
+        held.iter()
+            .enumerate()
+            .fold(0, |buttons, (index, held)| buttons | (*held as u8) << index)
+    }
+
+    /// Holds down the buttons set in `buttons`, as returned by `buttons`, and lets go
+    /// of the rest
+    pub fn set_buttons(&mut self, buttons: u8) {
+        let held = |index: u8| buttons & (1 << index) != 0;
+        self.a = held(0);
+        self.b = held(1);
+        self.select = held(2);
+        self.start = held(3);
+        self.right = held(4);
+        self.left = held(5);
+        self.up = held(6);
+        self.down = held(7);
+    }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.column == Column::One);
+        writer.u8(self.buttons());
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.column = if reader.bool()? {
+            Column::One
+        } else {
+            Column::Zero
+        };
+        self.set_buttons(reader.u8()?);
+        Ok(())
+    }
+
     fn reading_column_0(&self) -> bool {
         self.column == Column::Zero
     }
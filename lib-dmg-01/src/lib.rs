@@ -2,13 +2,34 @@
 #[macro_use]
 extern crate serde_derive;
 
+mod assembler;
+mod bus;
+mod bus_observer;
+mod code_data_log;
 pub mod cpu;
+pub mod debugger;
+mod frame_blender;
+#[cfg(feature = "gdb")]
+pub mod gdb;
 mod gpu;
 mod interrupt_flags;
 mod joypad;
 mod memory_bus;
+mod model;
+pub mod rewind;
+mod state;
+mod symbols;
 mod timer;
 mod utils;
 
+pub use assembler::{assemble, AssemblerError};
+pub use bus::{Bus, FlatBus};
+pub use bus_observer::{AccessKind, BusAccess, BusObserver, BusObservers};
+pub use code_data_log::CodeDataLog;
 pub use cpu::CPU;
+pub use frame_blender::FrameBlender;
 pub use joypad::Joypad;
+pub use memory_bus::MemoryBus;
+pub use model::Model;
+pub use state::StateError;
+pub use symbols::{SymbolError, Symbols};
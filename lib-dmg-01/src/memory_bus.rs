@@ -1,7 +1,13 @@
 use crate::{
+    bus::Bus,
+    bus_observer::{AccessKind, BusAccess, BusObservers},
+    code_data_log::CodeDataLog,
+    debugger::{WatchKind, WatchpointHit, Watchpoints},
     gpu::{BackgroundAndWindowDataSelect, InterruptRequest, ObjectSize, TileMap, GPU},
     interrupt_flags::InterruptFlags,
     joypad::{self, Joypad},
+    model::Model,
+    state::{StateError, StateReader, StateWriter},
     timer::{Frequency, Timer},
     utils::bit,
 };
@@ -49,14 +55,25 @@ pub const ZERO_PAGE_SIZE: usize = ZERO_PAGE_END - ZERO_PAGE_BEGIN + 1;
 
 pub const INTERRUPT_ENABLE_REGISTER: usize = 0xFFFF;
 
+pub const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+
 pub const VBLANK_VECTOR: u16 = 0x40;
 pub const LCDSTAT_VECTOR: u16 = 0x48;
 pub const TIMER_VECTOR: u16 = 0x50;
+pub const SERIAL_VECTOR: u16 = 0x58;
+pub const JOYPAD_VECTOR: u16 = 0x60;
+
+// With the internal clock a byte is shifted out at 8192 bits per second
+const SERIAL_TRANSFER_CYCLES: u16 = 8 * 512;
 
 #[cfg_attr(feature = "serialize", derive(Serialize))]
 pub struct MemoryBus {
     #[cfg_attr(feature = "serialize", serde(skip_serializing))]
     boot_rom: Option<[u8; BOOT_ROM_SIZE]>,
+    // Whether the boot ROM is still mapped over the start of the cartridge ROM. It's
+    // kept after being unmapped so that earlier states can be loaded.
+    #[cfg_attr(feature = "serialize", serde(skip_serializing))]
+    boot_rom_mapped: bool,
     #[cfg_attr(feature = "serialize", serde(skip_serializing))]
     rom_bank_0: [u8; ROM_BANK_0_SIZE],
     #[cfg_attr(feature = "serialize", serde(skip_serializing))]
@@ -73,6 +90,24 @@ pub struct MemoryBus {
     timer: Timer,
     divider: Timer,
     pub joypad: Joypad,
+    serial_transfer_data: u8,
+    serial_transfer_control: u8,
+    // The clock cycles left until the transfer in progress completes
+    serial_transfer_cycles: Option<u16>,
+    /// Every byte the game has sent over the serial port. Test ROMs use this
+    /// to report their results.
+    #[cfg_attr(feature = "serialize", serde(skip_serializing))]
+    pub serial_output: Vec<u8>,
+    // The address the next byte of an in progress OAM DMA transfer is copied from
+    dma_source: Option<u16>,
+    cycles: u64,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub watchpoints: Watchpoints,
+    /// Records how every byte of ROM read is used when set
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub code_data_log: Option<CodeDataLog>,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub observers: BusObservers,
 }
 
 impl MemoryBus {
@@ -90,19 +125,23 @@ impl MemoryBus {
             boot_rom
         });
 
+        // A ROM shorter than 32 KiB, such as a test stub, is padded with zeros
         let mut rom_bank_0 = [0; ROM_BANK_0_SIZE];
-        for i in 0..ROM_BANK_0_SIZE {
-            rom_bank_0[i] = game_rom[i];
-        }
         let mut rom_bank_n = [0; ROM_BANK_N_SIZE];
-        for i in 0..ROM_BANK_N_SIZE {
-            rom_bank_n[i] = game_rom[ROM_BANK_0_SIZE + i];
+        let length = game_rom.len().min(ROM_BANK_N_END + 1);
+        for (i, &byte) in game_rom[..length].iter().enumerate() {
+            if i < ROM_BANK_N_BEGIN {
+                rom_bank_0[i] = byte;
+            } else {
+                rom_bank_n[i - ROM_BANK_N_BEGIN] = byte;
+            }
         }
         let mut divider = Timer::new(Frequency::F16384);
         divider.on = true;
         MemoryBus {
             // Note: instead of modeling memory as one array of length 0xFFFF, we'll
             // break memory up into it's logical parts.
+            boot_rom_mapped: boot_rom.is_some(),
             boot_rom,
             rom_bank_0,
             rom_bank_n,
@@ -115,10 +154,41 @@ impl MemoryBus {
             timer: Timer::new(Frequency::F4096),
             divider,
             joypad: Joypad::new(),
+            serial_transfer_data: 0,
+            serial_transfer_control: 0,
+            serial_transfer_cycles: None,
+            serial_output: Vec::new(),
+            dma_source: None,
+            cycles: 0,
+            watchpoints: Watchpoints::default(),
+            code_data_log: None,
+            observers: BusObservers::default(),
         }
     }
 
+    /// Sets the I/O registers to the values `model`'s boot ROM leaves them in. Used
+    /// when running without a boot ROM.
+    ///
+    /// Only the registers the boot ROM leaves non-zero are written; the others already
+    /// start at zero. The sound registers aren't emulated so they're skipped, as are the
+    /// object palettes, which the boot ROM never sets.
+    pub fn set_post_boot_state(&mut self, model: Model) {
+        // TODO: the boot ROM hands over part way through a frame. We still start the
+        // GPU from the top of the screen so LY and the STAT mode don't match hardware.
+        self.divider.value = match model {
+            Model::DMG0 => 0x18,
+            Model::DMG | Model::MGB => 0xAB,
+        };
+        self.write_io_register(0xFF07, 0xF8); // TAC
+        self.write_io_register(0xFF0F, 0xE1); // IF
+        self.write_io_register(0xFF40, 0x91); // LCDC
+        self.write_io_register(0xFF47, 0xFC); // BGP
+    }
+
     pub fn step(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.step_dma(cycles);
+        self.step_serial(cycles);
         if self.timer.step(cycles) {
             self.interrupt_flag.timer = true;
         }
@@ -138,24 +208,137 @@ impl MemoryBus {
         }
     }
 
-    pub fn has_interrupt(&self) -> bool {
-        (self.interrupt_enable.vblank && self.interrupt_flag.vblank)
-            || (self.interrupt_enable.lcdstat && self.interrupt_flag.lcdstat)
-            || (self.interrupt_enable.timer && self.interrupt_flag.timer)
-            || (self.interrupt_enable.serial && self.interrupt_flag.serial)
-            || (self.interrupt_enable.joypad && self.interrupt_flag.joypad)
+    fn step_dma(&mut self, cycles: u8) {
+        // One byte is copied per machine cycle
+        for _ in 0..(cycles / 4) {
+            let source = match self.dma_source {
+                Some(source) => source,
+                None => return,
+            };
+            let destination = OAM_BEGIN as u16 + (source & 0xFF);
+            let value = self.read_logged(source, CodeDataLog::DMA);
+            self.observe(AccessKind::Dma, source, value);
+            self.write_watched(destination, value);
+            let source = source + 1;
+            self.dma_source = if (source & 0xFF) as usize == OAM_SIZE {
+                None
+            } else {
+                Some(source)
+            };
+        }
+    }
+
+    fn step_serial(&mut self, cycles: u8) {
+        let remaining = match self.serial_transfer_cycles {
+            Some(remaining) => remaining,
+            None => return,
+        };
+        if remaining > cycles as u16 {
+            self.serial_transfer_cycles = Some(remaining - cycles as u16);
+            return;
+        }
+        // Nothing is ever plugged into the link port so the bits shifted in are all 1s
+        self.serial_transfer_cycles = None;
+        self.serial_transfer_data = 0xFF;
+        self.serial_transfer_control &= 0x7F;
+        self.interrupt_flag.serial = true;
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
+        let value = self.read_logged(address, CodeDataLog::DATA);
+        self.observe(AccessKind::Read, address, value);
+        value
+    }
+
+    // Reads a byte, logging it with `flags` in the code/data log when it is ROM
+    fn read_logged(&self, address: u16, flags: u8) -> u8 {
+        let value = self.read_memory(address);
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .check(WatchKind::Read, address, value, value);
+        }
+        if let Some(ref log) = self.code_data_log {
+            if let Some(offset) = self.rom_offset(address) {
+                log.log(offset, flags);
+            }
+        }
+        value
+    }
+
+    // Where `address` is in the cartridge's ROM, if it's mapped to ROM at all
+    fn rom_offset(&self, address: u16) -> Option<usize> {
         let address = address as usize;
         match address {
-            BOOT_ROM_BEGIN...BOOT_ROM_END => {
-                if let Some(boot_rom) = self.boot_rom {
-                    boot_rom[address]
-                } else {
-                    self.rom_bank_0[address]
-                }
-            }
+            BOOT_ROM_BEGIN..=BOOT_ROM_END if self.boot_rom_mapped => None,
+            ROM_BANK_0_BEGIN..=ROM_BANK_N_END => Some(address),
+            _ => None,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.write_watched(address, value);
+        self.observe(AccessKind::Write, address, value);
+    }
+
+    fn write_watched(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            // Only addresses being watched for changes are read beforehand. Some I/O
+            // registers can't be read at all.
+            let old = if self.watchpoints.watches_changes_to(address) {
+                self.try_peek_byte(address).unwrap_or(value)
+            } else {
+                value
+            };
+            self.watchpoints
+                .check(WatchKind::Write, address, old, value);
+        }
+        self.poke_byte(address, value);
+    }
+
+    // Tells the observers of an access. Reads and writes of I/O registers are told as
+    // such.
+    fn observe(&self, kind: AccessKind, address: u16, value: u8) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let io = matches!(
+            address as usize,
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END | INTERRUPT_ENABLE_REGISTER
+        );
+        let kind = match kind {
+            AccessKind::Read if io => AccessKind::IoRead,
+            AccessKind::Write if io => AccessKind::IoWrite,
+            kind => kind,
+        };
+        self.observers.notify(BusAccess {
+            address,
+            value,
+            cycle: self.cycles,
+            kind,
+        });
+    }
+
+    /// Reads a byte without triggering watchpoints. I/O registers that can't be read
+    /// yet read as 0xFF.
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        self.try_peek_byte(address).unwrap_or(0xFF)
+    }
+
+    /// Like `peek_byte` but `None` for the I/O registers that can't be read yet
+    pub fn try_peek_byte(&self, address: u16) -> Option<u8> {
+        match address as usize {
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.io_register(address as usize),
+            _ => Some(self.read_memory(address)),
+        }
+    }
+
+    fn read_memory(&self, address: u16) -> u8 {
+        let address = address as usize;
+        match address {
+            BOOT_ROM_BEGIN...BOOT_ROM_END => match self.boot_rom {
+                Some(ref boot_rom) if self.boot_rom_mapped => boot_rom[address],
+                _ => self.rom_bank_0[address],
+            },
             ROM_BANK_0_BEGIN...ROM_BANK_0_END => self.rom_bank_0[address],
             ROM_BANK_N_BEGIN...ROM_BANK_N_END => self.rom_bank_n[address - ROM_BANK_N_BEGIN],
             VRAM_BEGIN...VRAM_END => self.gpu.vram[address - VRAM_BEGIN],
@@ -181,7 +364,8 @@ impl MemoryBus {
         }
     }
 
-    pub fn write_byte(&mut self, address: u16, value: u8) {
+    /// Writes a byte without triggering watchpoints
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
         let address = address as usize;
         match address {
             ROM_BANK_0_BEGIN...ROM_BANK_0_END => {
@@ -219,11 +403,30 @@ impl MemoryBus {
     }
 
     fn read_io_register(&self, address: usize) -> u8 {
-        match address {
+        self.io_register(address)
+            .unwrap_or_else(|| panic!("Reading from an unknown I/O register {:x}", address))
+    }
+
+    // `None` for the registers that aren't emulated yet
+    fn io_register(&self, address: usize) -> Option<u8> {
+        let value = match address {
             0xFF00 => self.joypad.to_byte(),
-            0xFF01 => 0, // TODO: serial
-            0xFF02 => 0, // TODO: serial
+            0xFF01 => self.serial_transfer_data,
+            // Only the start and clock select bits are used. The others read as 1
+            0xFF02 => self.serial_transfer_control | 0x7E,
             0xFF04 => self.divider.value,
+            0xFF05 => self.timer.value,
+            0xFF06 => self.timer.modulo,
+            0xFF07 => {
+                // Timer Control. The unused bits read as 1
+                let frequency = match self.timer.frequency {
+                    Frequency::F4096 => 0b00,
+                    Frequency::F262144 => 0b01,
+                    Frequency::F65536 => 0b10,
+                    Frequency::F16384 => 0b11,
+                };
+                0b11111000 | bit(self.timer.on) << 2 | frequency
+            }
             0xFF0F => self.interrupt_flag.to_byte(),
             0xFF40 => {
                 // LCD Control
@@ -255,12 +458,35 @@ impl MemoryBus {
                 // Scroll Y Position
                 self.gpu.viewport_y_offset
             }
+            0xFF43 => {
+                // Scroll X Position
+                self.gpu.viewport_x_offset
+            }
             0xFF44 => {
                 // Current Line
                 self.gpu.line
             }
-            _ => panic!("Reading from an unknown I/O register {:x}", address),
-        }
+            0xFF45 => self.gpu.line_check,
+            0xFF47 => {
+                // Background Colors Setting
+                self.gpu.background_colors.into()
+            }
+            // Color 0 of the object palettes is transparent and isn't kept
+            0xFF48 => {
+                u8::from(self.gpu.obj_0_color_3) << 6
+                    | u8::from(self.gpu.obj_0_color_2) << 4
+                    | u8::from(self.gpu.obj_0_color_1) << 2
+            }
+            0xFF49 => {
+                u8::from(self.gpu.obj_1_color_3) << 6
+                    | u8::from(self.gpu.obj_1_color_2) << 4
+                    | u8::from(self.gpu.obj_1_color_1) << 2
+            }
+            0xFF4A => self.gpu.window.y,
+            0xFF4B => self.gpu.window.x,
+            _ => return None,
+        };
+        Some(value)
     }
 
     fn write_io_register(&mut self, address: usize, value: u8) {
@@ -272,8 +498,22 @@ impl MemoryBus {
                     joypad::Column::Zero
                 };
             }
-            0xFF01 => { /* Serial Transfer */ }
-            0xFF02 => { /* Serial Transfer Control */ }
+            0xFF01 => {
+                // Serial Transfer
+                self.serial_transfer_data = value;
+            }
+            0xFF02 => {
+                // Serial Transfer Control
+                self.serial_transfer_control = value & 0x81;
+                if (value & 0x80) == 0x80 {
+                    self.serial_output.push(self.serial_transfer_data);
+                    // Using the external clock the transfer waits for another Game Boy
+                    // to drive it, which never happens
+                    if (value & 0x01) == 0x01 {
+                        self.serial_transfer_cycles = Some(SERIAL_TRANSFER_CYCLES);
+                    }
+                }
+            }
             0xFF04 => self.divider.value = 0,
             0xFF05 => {
                 self.timer.value = value;
@@ -315,7 +555,6 @@ impl MemoryBus {
             0xff30 | 0xff31 | 0xff32 | 0xff33 | 0xff34 | 0xff35 | 0xff36 | 0xff37 | 0xff38
             | 0xff39 | 0xff3a | 0xff3b | 0xff3c | 0xff3d | 0xff3e | 0xff3f => {
                 //Wave Pattern RAM
-
             }
             0xFF40 => {
                 // LCD Control
@@ -364,15 +603,10 @@ impl MemoryBus {
                 self.gpu.line_check = value;
             }
             0xFF46 => {
-                // TODO: account for the fact this takes 160 microseconds
-                let dma_source = (value as u16) << 8;
-                let dma_destination = 0xFE00;
-                for offset in 0..150 {
-                    self.write_byte(
-                        dma_destination + offset,
-                        self.read_byte(dma_source + offset),
-                    )
-                }
+                // OAM DMA: copy 160 bytes from 0xXX00 into OAM over the next 160
+                // machine cycles.
+                // TODO: the CPU should only be able to reach HRAM while this runs
+                self.dma_source = Some((value as u16) << 8);
             }
             0xFF47 => {
                 // Background Colors Setting
@@ -396,7 +630,7 @@ impl MemoryBus {
             }
             0xFF50 => {
                 // Unmap boot ROM
-                self.boot_rom = None;
+                self.boot_rom_mapped = false;
             }
             0xFF7f => {
                 // Writing to here does nothing
@@ -411,8 +645,120 @@ impl MemoryBus {
     pub fn slice(&self, start: u16, end: u16) -> Vec<u8> {
         let mut result = Vec::with_capacity((end - start) as usize);
         for i in start..end {
-            result.push(self.read_byte(i));
+            result.push(self.peek_byte(i));
         }
         result
     }
+
+    // The ROM and boot ROM never change so they aren't part of the state. Only the
+    // length of the serial output is, so loading an earlier state can drop what was
+    // sent after it.
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.boot_rom_mapped);
+        writer.bytes(&self.external_ram);
+        writer.bytes(&self.working_ram);
+        writer.bytes(&self.zero_page);
+        self.gpu.write_state(writer);
+        writer.u8(self.interrupt_enable.to_byte());
+        writer.u8(self.interrupt_flag.to_byte());
+        self.timer.write_state(writer);
+        self.divider.write_state(writer);
+        self.joypad.write_state(writer);
+        writer.u8(self.serial_transfer_data);
+        writer.u8(self.serial_transfer_control);
+        writer.bool(self.serial_transfer_cycles.is_some());
+        writer.u16(self.serial_transfer_cycles.unwrap_or(0));
+        writer.u32(self.serial_output.len() as u32);
+        writer.bool(self.dma_source.is_some());
+        writer.u16(self.dma_source.unwrap_or(0));
+        writer.u64(self.cycles);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let boot_rom_mapped = reader.bool()?;
+        if boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::Mismatch(
+                "the state was saved while running the boot ROM",
+            ));
+        }
+        self.boot_rom_mapped = boot_rom_mapped;
+        self.external_ram
+            .copy_from_slice(reader.bytes(EXTERNAL_RAM_SIZE)?);
+        self.working_ram
+            .copy_from_slice(reader.bytes(WORKING_RAM_SIZE)?);
+        self.zero_page
+            .copy_from_slice(reader.bytes(ZERO_PAGE_SIZE)?);
+        self.gpu.read_state(reader)?;
+        self.interrupt_enable.from_byte(reader.u8()?);
+        self.interrupt_flag.from_byte(reader.u8()?);
+        self.timer.read_state(reader)?;
+        self.divider.read_state(reader)?;
+        self.joypad.read_state(reader)?;
+        self.serial_transfer_data = reader.u8()?;
+        self.serial_transfer_control = reader.u8()?;
+        let transferring = reader.bool()?;
+        let transfer_cycles = reader.u16()?;
+        self.serial_transfer_cycles = if transferring {
+            Some(transfer_cycles)
+        } else {
+            None
+        };
+        let serial_output = reader.u32()? as usize;
+        self.serial_output.truncate(serial_output);
+        let copying = reader.bool()?;
+        let dma_source = reader.u16()?;
+        self.dma_source = if copying { Some(dma_source) } else { None };
+        self.cycles = reader.u64()?;
+        Ok(())
+    }
+}
+
+impl Bus for MemoryBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        MemoryBus::read_byte(self, address)
+    }
+
+    fn fetch_cycle(&mut self, address: u16, opcode: bool) -> u8 {
+        self.tick();
+        let flags = if opcode {
+            CodeDataLog::OPCODE
+        } else {
+            CodeDataLog::OPERAND
+        };
+        let value = self.read_logged(address, flags);
+        self.observe(AccessKind::Fetch, address, value);
+        value
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        MemoryBus::peek_byte(self, address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        MemoryBus::write_byte(self, address, value)
+    }
+
+    fn poke_byte(&mut self, address: u16, value: u8) {
+        MemoryBus::poke_byte(self, address, value)
+    }
+
+    fn tick(&mut self) {
+        self.step(4);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn has_interrupt(&self) -> bool {
+        (self.interrupt_enable.vblank && self.interrupt_flag.vblank)
+            || (self.interrupt_enable.lcdstat && self.interrupt_flag.lcdstat)
+            || (self.interrupt_enable.timer && self.interrupt_flag.timer)
+            || (self.interrupt_enable.serial && self.interrupt_flag.serial)
+            || (self.interrupt_enable.joypad && self.interrupt_flag.joypad)
+    }
+
+    fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.watchpoints.take_hit()
+    }
 }
@@ -0,0 +1,29 @@
+/// The hardware revision being emulated.
+///
+/// The models run the same code but their boot ROMs leave the CPU and I/O
+/// registers in slightly different states. Games and test ROMs sometimes
+/// look at these values to work out what they are running on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub enum Model {
+    /// The original Game Boy with the early revision boot ROM
+    DMG0,
+    /// The original Game Boy
+    #[default]
+    DMG,
+    /// The Game Boy Pocket
+    MGB,
+}
+
+impl std::str::FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_lowercase().as_str() {
+            "dmg0" => Ok(Model::DMG0),
+            "dmg" => Ok(Model::DMG),
+            "mgb" => Ok(Model::MGB),
+            _ => Err(format!("Unknown model '{}'. Expected dmg0, dmg or mgb", s)),
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::VecDeque;
+
+use crate::bus::Bus;
+use crate::cpu::CPU;
+
+/// The number of clock cycles the GPU takes to draw a frame
+pub const FRAME_CYCLES: u64 = 70224;
+
+// A saved state along with the cycle it was taken on
+struct Snapshot {
+    cycle: u64,
+    state: Vec<u8>,
+}
+
+// A snapshot stored as the difference from the snapshot after it
+struct Delta {
+    cycle: u64,
+    length: usize,
+    changes: Vec<u8>,
+}
+
+/// A history of the machine that can be rewound to any recorded frame or instruction.
+///
+/// Every `interval` frames a snapshot of the machine is taken. The newest is kept
+/// whole and the older ones as the XOR of their bytes with the next snapshot, which is
+/// mostly zeros and so is run length encoded. At most `capacity` snapshots are kept,
+/// dropping the oldest first, along with the joypad input since the oldest. Rewinding
+/// loads the nearest snapshot at or before the point being rewound to and runs the
+/// machine forward from there, feeding in the recorded input.
+///
+/// Rewinding throws away the history after the point rewound to, so recording carries
+/// on from there as if the rewound frames never happened.
+///
+/// ```
+/// use lib_dmg_01::cpu::CPU;
+/// use lib_dmg_01::rewind::{Rewind, FRAME_CYCLES};
+/// use lib_dmg_01::Bus;
+///
+/// let mut rom = vec![0; 0x8000];
+/// rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // jr $0100
+/// let mut cpu = CPU::new(None, rom);
+/// let mut rewind = Rewind::new(4, 8);
+/// for _ in 0..10 {
+///     rewind.record_frame(&cpu);
+///     cpu.run(FRAME_CYCLES);
+/// }
+/// let cycles = cpu.bus.cycles();
+/// assert!(rewind.rewind_frames(&mut cpu, 3));
+/// assert!(cpu.bus.cycles() < cycles);
+/// ```
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    newest: Option<Snapshot>,
+    older: VecDeque<Delta>,
+    frames_since_snapshot: usize,
+    // The cycle each recorded frame started on
+    frames: VecDeque<u64>,
+    // The buttons held from each cycle on, recorded when they change
+    inputs: VecDeque<(u64, u8)>,
+}
+
+impl Rewind {
+    /// Keeps a snapshot every `interval` frames and up to `capacity` snapshots, so a
+    /// little under `interval * capacity` frames can be rewound. Rewinding has to run
+    /// as many as `interval - 1` frames to get back to a frame so a smaller interval
+    /// rewinds faster but takes more memory.
+    pub fn new(interval: usize, capacity: usize) -> Rewind {
+        assert!(
+            interval > 0 && capacity > 0,
+            "a rewind buffer can't be empty"
+        );
+        Rewind {
+            interval,
+            capacity,
+            newest: None,
+            older: VecDeque::new(),
+            frames_since_snapshot: 0,
+            frames: VecDeque::new(),
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// Records the start of a frame. Call this before running each frame, after the
+    /// joypad has been set up for it.
+    pub fn record_frame(&mut self, cpu: &CPU) {
+        let cycle = cpu.bus.cycles();
+        let buttons = cpu.bus.joypad.buttons();
+        if self.inputs.back().map(|&(_, held)| held) != Some(buttons) {
+            self.inputs.push_back((cycle, buttons));
+        }
+        self.frames.push_back(cycle);
+        if self.frames_since_snapshot == 0 {
+            self.snapshot(cycle, cpu.save_state());
+        }
+        self.frames_since_snapshot = (self.frames_since_snapshot + 1) % self.interval;
+    }
+
+    /// Records a frame once a frame's worth of cycles has passed since the last one.
+    /// For callers that step the CPU rather than running it a frame at a time.
+    pub fn record(&mut self, cpu: &CPU) {
+        let due = match self.frames.back() {
+            Some(start) => cpu.bus.cycles() >= start + FRAME_CYCLES,
+            None => true,
+        };
+        if due {
+            self.record_frame(cpu);
+        }
+    }
+
+    /// The number of recorded frames that can be rewound
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Rewinds to the start of the `count`th most recent frame, so a count of 1 goes
+    /// back to the start of the frame that is running. Returns false, leaving `cpu`
+    /// as it is, when fewer frames were recorded.
+    pub fn rewind_frames(&mut self, cpu: &mut CPU, count: usize) -> bool {
+        if count == 0 || count > self.frames.len() {
+            return false;
+        }
+        let cycle = self.frames[self.frames.len() - count];
+        self.rewind_to_cycle(cpu, cycle)
+    }
+
+    /// Rewinds to the first instruction boundary at or after `cycle`. Returns false,
+    /// leaving `cpu` as it is, when `cycle` is from before the oldest snapshot.
+    pub fn rewind_to_cycle(&mut self, cpu: &mut CPU, cycle: u64) -> bool {
+        if !self.restore(cpu, cycle) {
+            return false;
+        }
+        self.replay(cpu, |cpu| cpu.bus.cycles() >= cycle);
+        self.forget_after(cpu.bus.cycles());
+        true
+    }
+
+    /// Rewinds to before the last instruction ran. Returns false, leaving `cpu` as it
+    /// is, when that was before the oldest snapshot.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let now = cpu.bus.cycles();
+        if now == 0 || !self.restore(cpu, now - 1) {
+            return false;
+        }
+        // Run forward once to find where the last instruction started and again to
+        // stop there
+        let mut previous = cpu.bus.cycles();
+        self.replay(cpu, |cpu| {
+            if cpu.bus.cycles() >= now {
+                return true;
+            }
+            previous = cpu.bus.cycles();
+            false
+        });
+        self.rewind_to_cycle(cpu, previous)
+    }
+
+    fn snapshot(&mut self, cycle: u64, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            self.older.push_back(Delta {
+                cycle: previous.cycle,
+                length: previous.state.len(),
+                changes: compress(&previous.state, &state),
+            });
+        }
+        self.newest = Some(Snapshot { cycle, state });
+        if self.older.len() >= self.capacity {
+            self.older.pop_front();
+            let oldest = self.older.front().map_or(cycle, |delta| delta.cycle);
+            // The buttons held at the oldest snapshot are part of it
+            while self.frames.front().is_some_and(|&start| start < oldest) {
+                self.frames.pop_front();
+            }
+            while self.inputs.front().is_some_and(|&(at, _)| at < oldest) {
+                self.inputs.pop_front();
+            }
+        }
+    }
+
+    // Loads the newest snapshot taken at or before `cycle`
+    fn restore(&self, cpu: &mut CPU, cycle: u64) -> bool {
+        let newest = match self.newest {
+            Some(ref newest) => newest,
+            None => return false,
+        };
+        let mut state = newest.state.clone();
+        if newest.cycle > cycle {
+            let mut found = false;
+            for delta in self.older.iter().rev() {
+                decompress(&delta.changes, &mut state);
+                state.resize(delta.length, 0);
+                if delta.cycle <= cycle {
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                return false;
+            }
+        }
+        cpu.load_state(&state)
+            .expect("a rewind snapshot could not be loaded");
+        true
+    }
+
+    // Runs `cpu` until `done` with the recorded input. The tracer and profiler are left
+    // out and watchpoint hits are dropped since the instructions already ran once.
+    fn replay<F: FnMut(&CPU) -> bool>(&self, cpu: &mut CPU, mut done: F) {
+        let tracer = cpu.take_tracer();
+        let profiler = cpu.take_profiler();
+        // The observers have already seen everything being replayed
+        let observers = std::mem::take(&mut cpu.bus.observers);
+        let mut inputs = self.inputs.iter().peekable();
+        loop {
+            while let Some(&&(at, buttons)) = inputs.peek() {
+                if at > cpu.bus.cycles() {
+                    break;
+                }
+                cpu.bus.joypad.set_buttons(buttons);
+                inputs.next();
+            }
+            if done(cpu) {
+                break;
+            }
+            cpu.step();
+        }
+        cpu.bus.take_watchpoint_hit();
+        cpu.bus.observers = observers;
+        if let Some(tracer) = tracer {
+            cpu.set_tracer(tracer);
+        }
+        if let Some(profiler) = profiler {
+            cpu.set_profiler(profiler);
+        }
+    }
+
+    fn forget_after(&mut self, cycle: u64) {
+        while self.frames.back().is_some_and(|&start| start >= cycle) {
+            self.frames.pop_back();
+        }
+        while self.inputs.back().is_some_and(|&(at, _)| at >= cycle) {
+            self.inputs.pop_back();
+        }
+        let newest_cycle = match self.newest {
+            Some(ref newest) => newest.cycle,
+            None => return,
+        };
+        if newest_cycle >= cycle {
+            let mut state = self.newest.take().unwrap().state;
+            while let Some(delta) = self.older.pop_back() {
+                decompress(&delta.changes, &mut state);
+                state.resize(delta.length, 0);
+                if delta.cycle < cycle {
+                    self.newest = Some(Snapshot {
+                        cycle: delta.cycle,
+                        state,
+                    });
+                    break;
+                }
+            }
+        }
+        self.frames_since_snapshot = match self.newest {
+            Some(ref newest) => {
+                let frames = self.frames.iter().filter(|&&start| start >= newest.cycle);
+                frames.count() % self.interval
+            }
+            None => 0,
+        };
+    }
+}
+
+// Encodes `old` XOR `new` as runs of unchanged bytes followed by runs of changed ones:
+// the length of each unchanged run, the length of the changed run after it and then
+// the XORed bytes of the changed run. Lengths are LEB128.
+fn compress(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let length = old.len().max(new.len());
+    let byte =
+        |index: usize| old.get(index).cloned().unwrap_or(0) ^ new.get(index).cloned().unwrap_or(0);
+    let mut changes = Vec::new();
+    let mut index = 0;
+    while index < length {
+        let start = index;
+        while index < length && byte(index) == 0 {
+            index += 1;
+        }
+        if index == length {
+            break;
+        }
+        let changed = index;
+        while index < length && byte(index) != 0 {
+            index += 1;
+        }
+        write_length(&mut changes, changed - start);
+        write_length(&mut changes, index - changed);
+        changes.extend((changed..index).map(byte));
+    }
+    changes
+}
+
+// XORs changes made by `compress` into `state`, growing it if needed
+fn decompress(mut changes: &[u8], state: &mut Vec<u8>) {
+    let mut index = 0;
+    while !changes.is_empty() {
+        index += read_length(&mut changes);
+        let length = read_length(&mut changes);
+        if state.len() < index + length {
+            state.resize(index + length, 0);
+        }
+        for (byte, change) in state[index..index + length].iter_mut().zip(changes) {
+            *byte ^= change;
+        }
+        changes = &changes[length..];
+        index += length;
+    }
+}
+
+fn write_length(bytes: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        bytes.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    bytes.push(length as u8);
+}
+
+fn read_length(bytes: &mut &[u8]) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[0];
+        *bytes = &bytes[1..];
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // Counts frames in $C000 and copies the joypad to $C001 and a stack of calls
+    // deepening and unwinding so snapshots differ in length
+    fn cpu() -> CPU {
+        cpu_booting(None)
+    }
+
+    fn cpu_booting(boot_rom: Option<Vec<u8>>) -> CPU {
+        let rom = assemble(
+            "
+            org $0100
+                ld sp, $DFFE
+                ld a, $10
+                ldh [$FF00 + $00], a
+            loop:
+                ld hl, $C000
+                inc [hl]
+                ldh a, [$FF00 + $00]
+                ld [$C001], a
+                ld a, [$C000]
+                and $07
+                ld b, a
+                call nest
+                jr loop
+            nest:
+                dec b
+                ret z
+                call nest
+                ret
+            ",
+        )
+        .unwrap();
+        CPU::new(boot_rom, rom)
+    }
+
+    #[test]
+    fn compresses_differences() {
+        let old = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let mut new = old.clone();
+        new[2] = 0xFF;
+        new.extend(vec![9; 300]);
+        let changes = compress(&old, &new);
+        assert!(changes.len() < 310);
+        let mut state = new.clone();
+        decompress(&changes, &mut state);
+        state.truncate(old.len());
+        assert_eq!(state, old);
+        let mut state = old.clone();
+        decompress(&changes, &mut state);
+        assert_eq!(state, new);
+        assert!(compress(&old, &old).is_empty());
+    }
+
+    // Records a frame and the state at its start for each of `frames` frames
+    fn record(cpu: &mut CPU, rewind: &mut Rewind, frames: u8) -> Vec<Vec<u8>> {
+        let mut states = Vec::new();
+        for frame in 0..frames {
+            cpu.bus.joypad.set_buttons(frame / 4);
+            rewind.record_frame(cpu);
+            states.push(cpu.save_state());
+            cpu.run(FRAME_CYCLES);
+        }
+        states
+    }
+
+    #[test]
+    fn rewinds_frames_with_recorded_input() {
+        let mut cpu = cpu();
+        let mut rewind = Rewind::new(3, 100);
+        let states = record(&mut cpu, &mut rewind, 20);
+        assert_eq!(rewind.frames(), 20);
+
+        assert!(rewind.rewind_frames(&mut cpu, 4));
+        assert!(cpu.save_state() == states[16]);
+        assert!(rewind.rewind_frames(&mut cpu, 1));
+        assert!(cpu.save_state() == states[15]);
+        assert!(rewind.rewind_frames(&mut cpu, 13));
+        assert!(cpu.save_state() == states[2]);
+        assert!(!rewind.rewind_frames(&mut cpu, 3));
+        assert!(rewind.rewind_frames(&mut cpu, 2));
+        assert!(cpu.save_state() == states[0]);
+        assert_eq!(rewind.frames(), 0);
+    }
+
+    #[test]
+    fn rewinds_to_before_the_boot_rom_was_unmapped() {
+        let boot_rom = assemble(
+            "
+            org $0000
+                ld a, $01
+                ldh [$FF00 + $50], a
+            ",
+        )
+        .unwrap();
+        let mut cpu = cpu_booting(Some(boot_rom[..0x100].to_vec()));
+        let mut rewind = Rewind::new(1, 10);
+        let states = record(&mut cpu, &mut rewind, 3);
+        assert_eq!(cpu.bus.peek_byte(0x0000), 0x00);
+
+        assert!(rewind.rewind_frames(&mut cpu, 3));
+        assert!(cpu.save_state() == states[0]);
+        assert_eq!(cpu.bus.peek_byte(0x0000), 0x3E);
+        cpu.run(FRAME_CYCLES);
+        assert!(cpu.save_state() == states[1]);
+        assert_eq!(cpu.bus.peek_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn records_over_rewound_frames() {
+        let mut cpu = cpu();
+        let mut rewind = Rewind::new(3, 100);
+        record(&mut cpu, &mut rewind, 10);
+        assert!(rewind.rewind_frames(&mut cpu, 5));
+        cpu.bus.joypad.set_buttons(0xFF);
+        rewind.record_frame(&cpu);
+        let state = cpu.save_state();
+        cpu.run(FRAME_CYCLES);
+        rewind.record_frame(&cpu);
+        cpu.run(FRAME_CYCLES);
+        assert_eq!(rewind.frames(), 7);
+        assert!(rewind.rewind_frames(&mut cpu, 2));
+        assert!(cpu.save_state() == state);
+    }
+
+    #[test]
+    fn drops_the_oldest_snapshots() {
+        let mut cpu = cpu();
+        let mut rewind = Rewind::new(2, 3);
+        let states = record(&mut cpu, &mut rewind, 10);
+        assert_eq!(rewind.frames(), 6);
+        assert!(!rewind.rewind_frames(&mut cpu, 7));
+        assert!(rewind.rewind_frames(&mut cpu, 6));
+        assert!(cpu.save_state() == states[4]);
+    }
+
+    #[test]
+    fn steps_back_instructions() {
+        let mut cpu = cpu();
+        let mut rewind = Rewind::new(1, 10);
+        let mut history = Vec::new();
+        let mut steps_into_frame = 0;
+        while rewind.frames() < 3 || steps_into_frame < 5 {
+            let frames = rewind.frames();
+            rewind.record(&cpu);
+            if rewind.frames() != frames {
+                steps_into_frame = 0;
+            }
+            history.push((cpu.pc, cpu.bus.cycles()));
+            cpu.step();
+            steps_into_frame += 1;
+        }
+        let last = cpu.save_state();
+        assert!(rewind.step_back(&mut cpu));
+        let before_last = cpu.save_state();
+        cpu.step();
+        assert!(cpu.save_state() == last);
+
+        cpu.load_state(&before_last).unwrap();
+        for _ in 0..20 {
+            assert_eq!(Some((cpu.pc, cpu.bus.cycles())), history.pop());
+            assert!(rewind.step_back(&mut cpu));
+        }
+        assert_eq!(Some((cpu.pc, cpu.bus.cycles())), history.pop());
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+
+const MAGIC: &[u8; 4] = b"DMG1";
+const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    /// The bytes don't start the way a saved state does
+    NotAState,
+    /// The state was saved by a version of the emulator that saves it differently
+    UnsupportedVersion(u8),
+    /// The state ended before everything was read from it
+    Truncated,
+    /// The state doesn't fit the machine it's being loaded into
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a saved state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "saved states of version {} aren't supported", version)
+            }
+            StateError::Truncated => write!(f, "the saved state is cut short"),
+            StateError::Mismatch(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Where each part of the machine writes its state, in the same order it reads it back
+/// from a `StateReader`
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < length {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+}
+
+impl CPU {
+    /// The state of the whole machine (the CPU, memory and the hardware on the bus) as
+    /// bytes that `load_state` can restore.
+    ///
+    /// ROM isn't included so a state can only be loaded into a CPU running the same
+    /// game. Neither are breakpoints, watchpoints, symbols or the tracer.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes(MAGIC);
+        writer.u8(VERSION);
+        self.write_state(&mut writer);
+        self.bus.write_state(&mut writer);
+        writer.bytes
+    }
+
+    /// Restores the machine to a state returned by `save_state`. The machine is left
+    /// partly restored if an error is returned.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader { bytes: state };
+        if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::NotAState);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        self.read_state(&mut reader)?;
+        self.bus.read_state(&mut reader)?;
+        if !reader.bytes.is_empty() {
+            return Err(StateError::Mismatch(
+                "the saved state is longer than expected",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+
+    fn cpu() -> CPU {
+        let rom = assemble(
+            "
+            org $0100
+                ld sp, $DFFE
+                ld a, $91
+                ldh [$FF00 + $40], a
+            loop:
+                inc a
+                ld [$C000], a
+                ld [$8000], a
+                call function
+                jr loop
+            function:
+                ret
+            ",
+        )
+        .unwrap();
+        CPU::new(None, rom)
+    }
+
+    fn run(cpu: &mut CPU, cycles: u64) {
+        let end = cpu.bus.cycles() + cycles;
+        while cpu.bus.cycles() < end {
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn restores_the_machine() {
+        let mut cpu = cpu();
+        run(&mut cpu, 100_000);
+        let state = cpu.save_state();
+        let cycles = cpu.bus.cycles();
+        run(&mut cpu, 100_000);
+        let expected = (
+            cpu.registers.a,
+            cpu.bus.gpu.line,
+            cpu.bus.gpu.canvas_buffer.to_vec(),
+        );
+
+        let mut restored = self::cpu();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.bus.cycles(), cycles);
+        assert_eq!(restored.save_state(), state);
+        run(&mut restored, 100_000);
+        assert_eq!(
+            (
+                restored.registers.a,
+                restored.bus.gpu.line,
+                restored.bus.gpu.canvas_buffer.to_vec()
+            ),
+            expected
+        );
+        assert_eq!(restored.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let mut cpu = cpu();
+        let state = cpu.save_state();
+        assert_eq!(cpu.load_state(b"nope"), Err(StateError::NotAState));
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        let mut future = state.clone();
+        future[4] = 99;
+        assert_eq!(
+            cpu.load_state(&future),
+            Err(StateError::UnsupportedVersion(99))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::bus::Bus;
+
+#[derive(Debug, PartialEq)]
+pub struct SymbolError {
+    /// The line the error was found on, starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+/// Names for addresses, such as those in the `.sym` files written by RGBDS (`rgblink
+/// -n`) and no$gmb.
+///
+/// The same address can hold different code or data depending on which ROM or RAM
+/// bank is mapped in, so every label belongs to a bank. `Bus::bank` says which bank
+/// is mapped at an address and the `_on` lookups use it to find the label for what is
+/// there right now.
+///
+/// ```
+/// use lib_dmg_01::{FlatBus, Symbols};
+///
+/// let symbols = Symbols::parse("00:0150 main\n00:0158 main.loop\n01:4000 data").unwrap();
+/// let bus = FlatBus::new();
+/// assert_eq!(symbols.address("main.loop"), Some((0x00, 0x0158)));
+/// assert_eq!(symbols.label_on(&bus, 0x0150), Some("main"));
+/// assert_eq!(symbols.describe_on(&bus, 0x015A), "main.loop+$2");
+/// assert_eq!(symbols.describe_on(&bus, 0x4000), "data");
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    labels: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Parses a symbol file. Each line is `BB:AAAA label` with the bank and address
+    /// in hex. Blank lines and `;` comments are skipped.
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: &str| SymbolError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let line = match line.find(';') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let mut words = line.split_whitespace();
+            let location = match words.next() {
+                Some(location) => location,
+                None => continue,
+            };
+            let label = words.next().ok_or_else(|| error("missing label"))?;
+            if words.next().is_some() {
+                return Err(error("expected `BB:AAAA label`"));
+            }
+            let mut parts = location.splitn(2, ':');
+            let bank = parts
+                .next()
+                .and_then(|bank| u16::from_str_radix(bank, 16).ok());
+            let address = parts
+                .next()
+                .and_then(|address| u16::from_str_radix(address, 16).ok());
+            match (bank, address) {
+                (Some(bank), Some(address)) => symbols.insert(bank, address, label),
+                _ => return Err(error("expected `BB:AAAA label`")),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Names `address` in `bank`. An address with several labels is shown using the
+    /// first but every label can be looked up.
+    pub fn insert(&mut self, bank: u16, address: u16, label: &str) {
+        self.labels
+            .entry((bank, address))
+            .or_insert_with(|| label.to_string());
+        self.addresses.insert(label.to_string(), (bank, address));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The bank and address of `label`
+    pub fn address(&self, label: &str) -> Option<(u16, u16)> {
+        self.addresses.get(label).cloned()
+    }
+
+    /// The label for `address` in `bank`
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        self.labels
+            .get(&(bank, address))
+            .map(|label| label.as_str())
+    }
+
+    /// The label for `address` in whichever bank `bus` has mapped there
+    pub fn label_on<B: Bus>(&self, bus: &B, address: u16) -> Option<&str> {
+        self.label(bus.bank(address), address)
+    }
+
+    /// The closest label at or before `address` in `bank` along with how far past it
+    /// `address` is. Labels from a different area of memory (say ROM when `address` is
+    /// in WRAM) don't count.
+    pub fn nearest(&self, bank: u16, address: u16) -> Option<(&str, u16)> {
+        let area_start = area_start(address);
+        self.labels
+            .range((bank, area_start)..=(bank, address))
+            .next_back()
+            .map(|((_, label_address), label)| (label.as_str(), address - label_address))
+    }
+
+    /// `address` written as `label` or `label+$N` when there is a nearby label and as
+    /// `$AAAA` otherwise
+    pub fn describe_on<B: Bus>(&self, bus: &B, address: u16) -> String {
+        match self.nearest(bus.bank(address), address) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+${:X}", label, offset),
+            None => format!("${:04X}", address),
+        }
+    }
+}
+
+// The start of the area of memory (ROM bank, VRAM, WRAM bank...) containing `address`
+fn area_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFDFF => 0xE000,
+        0xFE00..=0xFEFF => 0xFE00,
+        0xFF00..=0xFF7F => 0xFF00,
+        _ => 0xFF80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rgbds_symbol_files() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0100 Start\n\
+             00:0100 EntryPoint\n\
+             \n\
+             01:4000 Banked ; a comment\n\
+             02:4000 OtherBank\n\
+             00:c000 wCounter\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.label(0, 0x0100), Some("Start"));
+        assert_eq!(symbols.address("EntryPoint"), Some((0, 0x0100)));
+        assert_eq!(symbols.label(1, 0x4000), Some("Banked"));
+        assert_eq!(symbols.label(2, 0x4000), Some("OtherBank"));
+        assert_eq!(symbols.label(3, 0x4000), None);
+        assert_eq!(symbols.nearest(0, 0xC004), Some(("wCounter", 4)));
+        assert_eq!(symbols.nearest(0, 0xBFFF), None);
+        assert_eq!(symbols.nearest(1, 0x4010), Some(("Banked", 0x10)));
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        assert_eq!(
+            Symbols::parse("00:0100 Start\n0100 Oops").unwrap_err(),
+            SymbolError {
+                line: 2,
+                message: "expected `BB:AAAA label`".to_string()
+            }
+        );
+        assert_eq!(
+            Symbols::parse("00:0100").unwrap_err().message,
+            "missing label"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 #[cfg_attr(feature = "serialize", derive(Serialize))]
 pub enum Frequency {
     F4096,
@@ -40,6 +42,34 @@ impl Timer {
         }
     }
 
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.u8(match self.frequency {
+            Frequency::F4096 => 0,
+            Frequency::F16384 => 1,
+            Frequency::F262144 => 2,
+            Frequency::F65536 => 3,
+        });
+        writer.u32(self.cycles as u32);
+        writer.u8(self.value);
+        writer.u8(self.modulo);
+        writer.bool(self.on);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.frequency = match reader.u8()? {
+            0 => Frequency::F4096,
+            1 => Frequency::F16384,
+            2 => Frequency::F262144,
+            3 => Frequency::F65536,
+            _ => return Err(StateError::Mismatch("unknown timer frequency")),
+        };
+        self.cycles = reader.u32()? as usize;
+        self.value = reader.u8()?;
+        self.modulo = reader.u8()?;
+        self.on = reader.bool()?;
+        Ok(())
+    }
+
     pub fn step(&mut self, cycles: u8) -> bool {
         if !self.on {
             return false;
@@ -0,0 +1,104 @@
+mod common;
+
+use common::{catch_panic, load_rom, run_until_serial_result, Outcome, CLOCK_SPEED};
+use lib_dmg_01::assemble;
+
+const CPU_INSTRS: [&str; 11] = [
+    "01-special.gb",
+    "02-interrupts.gb",
+    "03-op sp,hl.gb",
+    "04-op r,imm.gb",
+    "05-op rp.gb",
+    "06-ld r,r.gb",
+    "07-jr,jp,call,ret,rst.gb",
+    "08-misc instrs.gb",
+    "09-op r,r.gb",
+    "10-bit ops.gb",
+    "11-op a,(hl).gb",
+];
+
+// The slowest of the ROMs takes around 10 seconds to finish on hardware
+const TIMEOUT: u64 = 30 * CLOCK_SPEED;
+
+/// Runs each ROM and fails with all of the ROMs that didn't pass and their output
+fn run_roms(paths: &[String]) {
+    let mut failures = Vec::new();
+    for path in paths {
+        let rom = match load_rom(path) {
+            Some(rom) => rom,
+            None => continue,
+        };
+        match catch_panic(move || run_until_serial_result(rom, TIMEOUT)) {
+            Ok(ref report) if report.outcome == Outcome::Passed => {}
+            Ok(report) => {
+                failures.push(format!("{} {:?}:\n{}", path, report.outcome, report.serial));
+            }
+            Err(message) => failures.push(format!("{} panicked: {}", path, message)),
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn cpu_instrs() {
+    let paths: Vec<String> = CPU_INSTRS
+        .iter()
+        .map(|rom| format!("blargg/cpu_instrs/individual/{}", rom))
+        .collect();
+    run_roms(&paths);
+}
+
+#[test]
+fn instr_timing() {
+    run_roms(&["blargg/instr_timing/instr_timing.gb".to_string()]);
+}
+
+// Checks the harness itself with programs that print results the way Blargg's do
+fn serial_program(text: &str) -> Vec<u8> {
+    assemble(&format!(
+        "
+        org $0100
+            ld hl, message
+        .next:
+            ld a, [hl+]
+            and a
+            jr z, .done
+            ldh [$01], a
+            ld a, $81
+            ldh [$02], a
+        .wait:
+            ldh a, [$02]
+            bit 7, a
+            jr nz, .wait
+            jr .next
+        .done:
+            jr .done
+        message:
+            db \"{}\", 0
+        ",
+        text.replace('\n', "\\n")
+    ))
+    .unwrap()
+}
+
+#[test]
+fn harness_reports_serial_results() {
+    let report = run_until_serial_result(serial_program("01-special\n\nPassed"), TIMEOUT);
+    assert_eq!(report.outcome, Outcome::Passed);
+    assert_eq!(report.serial, "01-special\n\nPassed");
+
+    let report = run_until_serial_result(serial_program("Failed #3"), TIMEOUT);
+    assert_eq!(report.outcome, Outcome::Failed);
+
+    let report = run_until_serial_result(serial_program("Still going"), CLOCK_SPEED);
+    assert_eq!(report.outcome, Outcome::TimedOut);
+    assert_eq!(report.serial, "Still going");
+}
+
+#[test]
+fn harness_reports_panics() {
+    // Sound isn't emulated so reading NR10 panics
+    let rom = assemble("org $0100\n ldh a, [$10]\n").unwrap();
+    let message = catch_panic(move || run_until_serial_result(rom, TIMEOUT)).unwrap_err();
+    assert!(message.contains("unknown I/O register"), "{}", message);
+}
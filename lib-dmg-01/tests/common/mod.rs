@@ -0,0 +1,126 @@
+//! Helpers for running test ROMs. The ROMs themselves aren't redistributable so they
+//! are read from a local directory (see `tests/roms/README.md`) and tests that need one
+//! are skipped when it's missing.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::panic::{self, UnwindSafe};
+use std::path::PathBuf;
+
+use lib_dmg_01::cpu::registers::Registers;
+use lib_dmg_01::CPU;
+
+/// The clock speed of the Game Boy in cycles per second
+pub const CLOCK_SPEED: u64 = 4_194_304;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub outcome: Outcome,
+    /// Everything the ROM sent over the serial port
+    pub serial: String,
+    pub cycles: u64,
+    /// The registers when the run stopped
+    pub registers: Registers,
+}
+
+/// The directory test ROMs are read from: `$DMG_TEST_ROMS` if it's set and
+/// `tests/roms` otherwise.
+pub fn roms_directory() -> PathBuf {
+    match env::var_os("DMG_TEST_ROMS") {
+        Some(directory) => PathBuf::from(directory),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"),
+    }
+}
+
+/// Reads the ROM at `path` inside the ROM directory. Returns `None`, after saying that
+/// the test is being skipped, when the ROM isn't there.
+pub fn load_rom(path: &str) -> Option<Vec<u8>> {
+    let full_path = roms_directory().join(path);
+    match fs::read(&full_path) {
+        Ok(rom) => Some(rom),
+        Err(_) => {
+            eprintln!("skipping {}: {} not found", path, full_path.display());
+            None
+        }
+    }
+}
+
+/// Runs `rom` until its serial output contains "Passed" or "Failed" or it has run for
+/// `timeout_cycles` clock cycles. This is how Blargg's test ROMs report their results.
+pub fn run_until_serial_result(rom: Vec<u8>, timeout_cycles: u64) -> Report {
+    let mut cpu = CPU::new(None, rom);
+    let mut cycles = 0;
+    let mut checked_length = 0;
+    let mut outcome = Outcome::TimedOut;
+    while cycles < timeout_cycles {
+        cycles += cpu.step() as u64;
+        let output = &cpu.bus.serial_output;
+        if output.len() != checked_length {
+            checked_length = output.len();
+            let text = String::from_utf8_lossy(output);
+            if text.contains("Passed") {
+                outcome = Outcome::Passed;
+                break;
+            }
+            if text.contains("Failed") {
+                outcome = Outcome::Failed;
+                break;
+            }
+        }
+    }
+    report(&cpu, outcome, cycles)
+}
+
+/// Runs `rom` until it executes an `LD B,B` software breakpoint or it has run for
+/// `timeout_cycles` clock cycles. Mooneye's test ROMs do this when they finish, with
+/// the Fibonacci numbers 3, 5, 8, 13, 21 and 34 in B, C, D, E, H and L if they passed.
+pub fn run_until_software_breakpoint(rom: Vec<u8>, timeout_cycles: u64) -> Report {
+    let mut cpu = CPU::new(None, rom);
+    cpu.set_software_breakpoints(true);
+    let mut cycles = 0;
+    let mut outcome = Outcome::TimedOut;
+    while cycles < timeout_cycles {
+        cycles += cpu.step() as u64;
+        if cpu.hit_software_breakpoint() {
+            let r = &cpu.registers;
+            outcome = if [r.b, r.c, r.d, r.e, r.h, r.l] == [3, 5, 8, 13, 21, 34] {
+                Outcome::Passed
+            } else {
+                Outcome::Failed
+            };
+            break;
+        }
+    }
+    report(&cpu, outcome, cycles)
+}
+
+/// Calls `run`, turning a panic into an error holding its message so that one ROM
+/// hitting something that isn't emulated yet doesn't stop the rest from running.
+pub fn catch_panic<F: FnOnce() -> Report + UnwindSafe>(run: F) -> Result<Report, String> {
+    panic::catch_unwind(run).map_err(|cause| {
+        if let Some(message) = cause.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = cause.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown cause".to_string()
+        }
+    })
+}
+
+fn report(cpu: &CPU, outcome: Outcome, cycles: u64) -> Report {
+    Report {
+        outcome,
+        serial: String::from_utf8_lossy(&cpu.bus.serial_output).into_owned(),
+        cycles,
+        registers: cpu.registers,
+    }
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{catch_panic, roms_directory, run_until_software_breakpoint, Outcome, CLOCK_SPEED};
+use lib_dmg_01::assemble;
+
+// Every test finishes well within a second on hardware
+const TIMEOUT: u64 = 5 * CLOCK_SPEED;
+
+/// Whether a ROM is meant to pass on the DMG. ROMs written for specific models end
+/// their name with them, e.g. `boot_regs-dmgABC.gb` or `di_timing-GS.gb` where `G` is
+/// every DMG and MGB, `S` the SGBs, `C` the CGBs and `A` the AGBs.
+fn runs_on_dmg(name: &str) -> bool {
+    let stem = name.trim_end_matches(".gb");
+    match stem.rfind('-') {
+        None => true,
+        Some(index) => {
+            let models = &stem[index + 1..];
+            let groups = models.chars().all(|c| "GSCA".contains(c));
+            models.contains("dmgABC") || (groups && models.contains('G'))
+        }
+    }
+}
+
+/// Runs every DMG ROM directly inside `suite` (e.g. `acceptance/timer`) and fails
+/// with all of the ROMs that didn't pass.
+fn run_suite(suite: &str) {
+    let directory = roms_directory().join("mooneye").join(suite);
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            eprintln!("skipping {}: {} not found", suite, directory.display());
+            return;
+        }
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".gb") && runs_on_dmg(name))
+        .collect();
+    names.sort();
+
+    let mut failures = Vec::new();
+    for name in &names {
+        let rom = fs::read(Path::new(&directory).join(name)).unwrap();
+        match catch_panic(move || run_until_software_breakpoint(rom, TIMEOUT)) {
+            Ok(ref report) if report.outcome == Outcome::Passed => {}
+            Ok(report) => failures.push(format!(
+                "{} {:?} {:?}",
+                name, report.outcome, report.registers
+            )),
+            Err(message) => failures.push(format!("{} panicked: {}", name, message)),
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} failed:\n{}",
+        failures.len(),
+        names.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn acceptance() {
+    run_suite("acceptance");
+}
+
+#[test]
+fn acceptance_bits() {
+    run_suite("acceptance/bits");
+}
+
+#[test]
+fn acceptance_instr() {
+    run_suite("acceptance/instr");
+}
+
+#[test]
+fn acceptance_interrupts() {
+    run_suite("acceptance/interrupts");
+}
+
+#[test]
+fn acceptance_oam_dma() {
+    run_suite("acceptance/oam_dma");
+}
+
+#[test]
+fn acceptance_ppu() {
+    run_suite("acceptance/ppu");
+}
+
+#[test]
+fn acceptance_timer() {
+    run_suite("acceptance/timer");
+}
+
+#[test]
+fn model_suffixes() {
+    assert!(runs_on_dmg("ie_push.gb"));
+    assert!(runs_on_dmg("boot_regs-dmgABC.gb"));
+    assert!(runs_on_dmg("di_timing-GS.gb"));
+    assert!(!runs_on_dmg("boot_regs-dmg0.gb"));
+    assert!(!runs_on_dmg("boot_hwio-S.gb"));
+    assert!(!runs_on_dmg("boot_div-cgbABCDE.gb"));
+}
+
+// Checks the harness itself with programs that finish the way Mooneye's do
+#[test]
+fn harness_checks_the_register_signature() {
+    let program = |registers: [u8; 6]| {
+        assemble(&format!(
+            "
+            org $0100
+                ld b, {}
+                ld c, {}
+                ld d, {}
+                ld e, {}
+                ld h, {}
+                ld l, {}
+                ld b, b
+            .wait:
+                jr .wait
+            ",
+            registers[0], registers[1], registers[2], registers[3], registers[4], registers[5]
+        ))
+        .unwrap()
+    };
+
+    let report = run_until_software_breakpoint(program([3, 5, 8, 13, 21, 34]), TIMEOUT);
+    assert_eq!(report.outcome, Outcome::Passed);
+
+    let report = run_until_software_breakpoint(program([0x42; 6]), TIMEOUT);
+    assert_eq!(report.outcome, Outcome::Failed);
+
+    let rom = assemble("org $0100\n.wait:\n jr .wait").unwrap();
+    let report = run_until_software_breakpoint(rom, CLOCK_SPEED);
+    assert_eq!(report.outcome, Outcome::TimedOut);
+}
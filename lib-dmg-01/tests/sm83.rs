@@ -0,0 +1,291 @@
+//! Runs the per-opcode SM83 test vectors from
+//! [SingleStepTests](https://github.com/SingleStepTests/sm83). Each vector gives the
+//! state of the CPU and the memory it touches before and after a single instruction
+//! along with what happened on the bus during each machine cycle.
+mod common;
+
+use std::fs;
+
+use common::roms_directory;
+use lib_dmg_01::cpu::flags_register::FlagsRegister;
+use lib_dmg_01::{Bus, FlatBus, CPU};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+    Internal,
+}
+
+/// A `FlatBus` that records what happens on each cycle
+struct RecordingBus {
+    memory: FlatBus,
+    accesses: Vec<Access>,
+}
+
+impl RecordingBus {
+    fn new() -> RecordingBus {
+        RecordingBus {
+            memory: FlatBus::new(),
+            accesses: Vec::new(),
+        }
+    }
+}
+
+impl Bus for RecordingBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory.read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory.write_byte(address, value);
+    }
+
+    fn tick(&mut self) {
+        self.memory.tick();
+        self.accesses.push(Access::Internal);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.memory.cycles()
+    }
+
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        let value = self.memory.read_cycle(address);
+        self.accesses.push(Access::Read(address, value));
+        value
+    }
+
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.memory.write_cycle(address, value);
+        self.accesses.push(Access::Write(address, value));
+    }
+}
+
+const REGISTERS: [&str; 8] = ["a", "b", "c", "d", "e", "f", "h", "l"];
+
+fn number(state: &Value, name: &str) -> u64 {
+    state[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("missing {} in {}", name, state))
+}
+
+fn load(state: &Value) -> CPU<RecordingBus> {
+    let mut cpu = CPU::with_bus(RecordingBus::new());
+    let r = &mut cpu.registers;
+    r.a = number(state, "a") as u8;
+    r.b = number(state, "b") as u8;
+    r.c = number(state, "c") as u8;
+    r.d = number(state, "d") as u8;
+    r.e = number(state, "e") as u8;
+    r.f = FlagsRegister::from(number(state, "f") as u8);
+    r.h = number(state, "h") as u8;
+    r.l = number(state, "l") as u8;
+    cpu.pc = number(state, "pc") as u16;
+    cpu.sp = number(state, "sp") as u16;
+    cpu.set_interrupts_enabled(number(state, "ime") == 1);
+    if let Some(ie) = state["ie"].as_u64() {
+        cpu.bus.write_byte(0xFFFF, ie as u8);
+    }
+    for entry in state["ram"].as_array().into_iter().flatten() {
+        cpu.bus.write_byte(
+            entry[0].as_u64().unwrap() as u16,
+            entry[1].as_u64().unwrap() as u8,
+        );
+    }
+    cpu
+}
+
+fn register(cpu: &CPU<RecordingBus>, name: &str) -> u64 {
+    let r = &cpu.registers;
+    match name {
+        "a" => r.a as u64,
+        "b" => r.b as u64,
+        "c" => r.c as u64,
+        "d" => r.d as u64,
+        "e" => r.e as u64,
+        "f" => u8::from(r.f) as u64,
+        "h" => r.h as u64,
+        "l" => r.l as u64,
+        _ => unreachable!(),
+    }
+}
+
+/// An expected cycle is `[address, value, activity]` where the activity contains an
+/// `r` for reads and a `w` for writes (e.g. `"r-m"`), or `null` for internal cycles
+fn expected_access(cycle: &Value) -> Access {
+    let activity = cycle[2].as_str().unwrap_or("");
+    let address = cycle[0].as_u64().unwrap_or(0) as u16;
+    let value = cycle[1].as_u64().unwrap_or(0) as u8;
+    if activity.contains('r') {
+        Access::Read(address, value)
+    } else if activity.contains('w') {
+        Access::Write(address, value)
+    } else {
+        Access::Internal
+    }
+}
+
+/// Runs one vector, describing the first difference from the expected result
+fn run_vector(test: &Value) -> Result<(), String> {
+    let mut cpu = load(&test["initial"]);
+    cpu.step();
+
+    let expected = &test["final"];
+    let mut differences = Vec::new();
+    for name in REGISTERS.iter() {
+        let (actual, wanted) = (register(&cpu, name), number(expected, name));
+        if actual != wanted {
+            differences.push(format!("{} is {:02X} not {:02X}", name, actual, wanted));
+        }
+    }
+    if cpu.pc as u64 != number(expected, "pc") {
+        differences.push(format!(
+            "pc is {:04X} not {:04X}",
+            cpu.pc,
+            number(expected, "pc")
+        ));
+    }
+    if cpu.sp as u64 != number(expected, "sp") {
+        differences.push(format!(
+            "sp is {:04X} not {:04X}",
+            cpu.sp,
+            number(expected, "sp")
+        ));
+    }
+    if let Some(ime) = expected["ime"].as_u64() {
+        if cpu.interrupts_enabled() != (ime == 1) {
+            differences.push(format!("ime is {} not {}", cpu.interrupts_enabled(), ime));
+        }
+    }
+    for entry in expected["ram"].as_array().into_iter().flatten() {
+        let address = entry[0].as_u64().unwrap() as u16;
+        let (actual, wanted) = (cpu.bus.read_byte(address), entry[1].as_u64().unwrap() as u8);
+        if actual != wanted {
+            differences.push(format!(
+                "[{:04X}] is {:02X} not {:02X}",
+                address, actual, wanted
+            ));
+        }
+    }
+
+    let expected_accesses: Vec<Access> = test["cycles"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(expected_access)
+        .collect();
+    let accesses = &cpu.bus.accesses;
+    if accesses.len() != expected_accesses.len() {
+        differences.push(format!(
+            "took {} cycles not {}",
+            accesses.len(),
+            expected_accesses.len()
+        ));
+    } else {
+        for (cycle, (actual, wanted)) in accesses.iter().zip(&expected_accesses).enumerate() {
+            // Hardware puts some address on the bus during internal cycles too but the
+            // emulator doesn't model which
+            if *wanted != Access::Internal && actual != wanted {
+                differences.push(format!("cycle {} was {:?} not {:?}", cycle, actual, wanted));
+            }
+        }
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", test["name"], differences.join(", ")))
+    }
+}
+
+#[test]
+fn sm83_vectors() {
+    let directory = roms_directory().join("sm83");
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            eprintln!("skipping sm83 vectors: {} not found", directory.display());
+            return;
+        }
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+
+    // Only the first failure for each opcode is reported to keep the output readable
+    let mut failures = Vec::new();
+    for path in &paths {
+        let vectors: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        let results: Vec<_> = vectors.as_array().unwrap().iter().map(run_vector).collect();
+        let failed: Vec<&String> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+        if let Some(first) = failed.first() {
+            failures.push(format!(
+                "{} of {} failed, e.g. {}",
+                failed.len(),
+                results.len(),
+                first
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} opcodes failed:\n{}",
+        failures.len(),
+        paths.len(),
+        failures.join("\n")
+    );
+}
+
+// Checks the runner itself against hand written vectors
+#[test]
+fn runner_compares_state_and_cycles() {
+    let mut vectors: Value = serde_json::from_str(
+        r#"[
+        {
+            "name": "3e 0000",
+            "initial": {
+                "pc": 49152, "sp": 53248, "a": 0, "b": 1, "c": 2, "d": 3, "e": 4,
+                "f": 0, "h": 5, "l": 6, "ime": 0, "ram": [[49152, 62], [49153, 66]]
+            },
+            "final": {
+                "pc": 49154, "sp": 53248, "a": 66, "b": 1, "c": 2, "d": 3, "e": 4,
+                "f": 0, "h": 5, "l": 6, "ime": 0, "ram": [[49152, 62], [49153, 66]]
+            },
+            "cycles": [[49152, 62, "r-m"], [49153, 66, "r-m"]]
+        },
+        {
+            "name": "c5 0000",
+            "initial": {
+                "pc": 49152, "sp": 53248, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0,
+                "f": 176, "h": 0, "l": 0, "ime": 0, "ram": [[49152, 197]]
+            },
+            "final": {
+                "pc": 49153, "sp": 53246, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0,
+                "f": 176, "h": 0, "l": 0, "ime": 0,
+                "ram": [[49152, 197], [53247, 18], [53246, 52]]
+            },
+            "cycles": [[49152, 197, "r-m"], null, [53247, 18, "-wm"], [53246, 52, "-wm"]]
+        }
+    ]"#,
+    )
+    .unwrap();
+    for vector in vectors.as_array().unwrap() {
+        assert_eq!(run_vector(vector), Ok(()));
+    }
+
+    vectors[0]["final"]["a"] = Value::from(67);
+    vectors[1]["cycles"][2][1] = Value::from(19);
+    assert_eq!(
+        run_vector(&vectors[0]),
+        Err(r#""3e 0000": a is 42 not 43"#.to_string())
+    );
+    assert_eq!(
+        run_vector(&vectors[1]),
+        Err(r#""c5 0000": cycle 2 was Write(53247, 18) not Write(53247, 19)"#.to_string())
+    );
+}
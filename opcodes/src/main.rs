@@ -1,95 +1,113 @@
-#![feature(proc_macro_non_items)]
-#![feature(use_extern_macros)]
+use std::env;
+use std::fs;
+use std::path::Path;
 
-extern crate maud;
-use maud::{html, Markup};
+use lib_dmg_01::cpu::instruction::{metadata, metadata_table, FlagEffect, OpcodeMetadata};
+use maud::{html, Markup, DOCTYPE};
+use serde_json::{json, Map, Value};
 
+const TABLE_LABELS: [&str; 16] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F",
+];
+
+/// Writes the instruction guide into the directory given as the first argument (or
+/// the current directory):
+///
+/// * `opcodes.html` with the unprefixed and 0xCB prefixed opcode tables
+/// * `opcodes/XX.html` and `opcodes/CBXX.html` with the details of each instruction
+/// * `opcodes.json` with the same data for the guide's scripts
 fn main() {
-    let markup = html! {
-        head {
-            meta charset="utf-8" {}
-            title { "DMG-01: Instruction Guide" }
-            link rel="stylesheet" href="index.css" {}
-        }
-        body {
-            div {
-                div class="header" {
-                    div class="title"{
-                        "Instruction Guide"
-                    }
-                    (instruction_table())
-                    (instruction_list())
-                }
+    let out = env::args().nth(1).unwrap_or_else(|| ".".to_string());
+    let out = Path::new(&out);
+    fs::create_dir_all(out.join("opcodes")).expect("Could not create the output directory");
+
+    write(&out.join("opcodes.html"), tables_page().into_string());
+    for instruction in metadata_table() {
+        let path = out.join("opcodes").join(page_name(&instruction));
+        write(&path, detail_page(&instruction).into_string());
+    }
+    let json = serde_json::to_string_pretty(&json_table()).expect("Could not serialize opcodes");
+    write(&out.join("opcodes.json"), json);
+}
+
+fn write(path: &Path, contents: String) {
+    fs::write(path, contents).unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e));
+}
+
+fn page_name(instruction: &OpcodeMetadata) -> String {
+    format!(
+        "{}.html",
+        opcode_name(instruction.opcode, instruction.prefixed)
+    )
+}
+
+fn opcode_name(opcode: u8, prefixed: bool) -> String {
+    if prefixed {
+        format!("CB{:02X}", opcode)
+    } else {
+        format!("{:02X}", opcode)
+    }
+}
+
+fn cycles(instruction: &OpcodeMetadata) -> String {
+    match instruction.cycles_not_taken {
+        Some(not_taken) => format!("{}/{}", instruction.cycles, not_taken),
+        None => instruction.cycles.to_string(),
+    }
+}
+
+fn flags(instruction: &OpcodeMetadata) -> [(char, FlagEffect); 4] {
+    let flags = instruction.flags;
+    [
+        ('Z', flags.zero),
+        ('N', flags.subtract),
+        ('H', flags.half_carry),
+        ('C', flags.carry),
+    ]
+}
+
+fn page(title: &str, stylesheet: &str, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                title { "DMG-01: " (title) }
+                link rel="stylesheet" href=(stylesheet);
             }
+            body { (body) }
         }
-    };
-    println!("{}", markup.into_string());
+    }
 }
 
-struct Instruction {
-    name: &'static str,
-    code: u8,
+fn tables_page() -> Markup {
+    page(
+        "Instruction Guide",
+        "index.css",
+        html! {
+            div class="title" { "Instruction Guide" }
+            h2 { "Unprefixed" }
+            (table(false))
+            h2 id="cb-prefixed" { "0xCB prefixed" }
+            (table(true))
+        },
+    )
 }
-const INSTRUCTIONS: [Instruction; 6] = [
-    Instruction {
-        name: "NOP",
-        code: 0x00,
-        pc: 1,
-        cycles: 4,
-    },
-    Instruction {
-        name: "LD BC,d16",
-        code: 0x01,
-        pc: 3,
-        cycles: 12,
-    },
-    // Instruction { name: "LD (BC), A", code: 0x02 },
-    Instruction {
-        name: "STOP 0",
-        code: 0x10,
-        pc: 2,
-        cycles: 12,
-    },
-    Instruction {
-        name: "LD DE,d16",
-        code: 0x11,
-    },
-    // Instruction { name: "LD (DE),A", code: 0x12 },
-    Instruction {
-        name: "JR NZ,r8",
-        code: 0x20,
-    },
-    Instruction {
-        name: "LD HL,d16",
-        code: 0x21,
-    },
-    // Instruction { name: "LD (HL+),A", code: 0x22 },
-];
-const INSTRUCTION_TABLE_LABELS: [&'static str; 2] = [
-    "0x0",
-    "0x1", // "0x2", "0x3", "0x4", "0x5", "0x6", "0x7",
-           //"0x8", "0x9", "0xA", "0xB", "0xC", "0xD", "0xE", "0xF",
-];
-fn instruction_table() -> Markup {
-    let instruction_table = form_instruction_table();
+
+fn table(prefixed: bool) -> Markup {
     html! {
-        div {
-            div class="table-header" style="display: flex;" {
-                @for label in &INSTRUCTION_TABLE_LABELS {
-                    div {
-                        (label)
-                    }
+        table id="instruction-table" {
+            tr {
+                th {}
+                @for label in TABLE_LABELS.iter() {
+                    th { "x" (label) }
                 }
             }
-            @for (row_index, row) in instruction_table.iter().enumerate() {
-                div class=(format!("row{}", row_index)) style="display:flex;"{
-                    div {
-                        (INSTRUCTION_TABLE_LABELS[row_index])
-                    }
-                    @for (column_index, instruction) in row.iter().enumerate() {
-                        div class=(format!("column{}", column_index)) style="width: 100px;height: 100px; text-align: center;"{
-                            (instruction.name)
-                        }
+            @for (row, row_label) in TABLE_LABELS.iter().enumerate() {
+                tr {
+                    th { (row_label) "x" }
+                    @for column in 0..16 {
+                        (cell(metadata((row << 4 | column) as u8, prefixed)))
                     }
                 }
             }
@@ -97,56 +115,105 @@ fn instruction_table() -> Markup {
     }
 }
 
-fn form_instruction_table<'a>() -> Vec<Vec<&'a Instruction>> {
-    let mut rows = Vec::with_capacity(16);
-    for row_index in 0..(INSTRUCTION_TABLE_LABELS.len() as u8) {
-        let mut row = Vec::with_capacity(16);
-        for column_index in 0..(INSTRUCTION_TABLE_LABELS.len() as u8) {
-            let code = column_index << 4 | row_index;
-            let instruction = INSTRUCTIONS
-                .iter()
-                .find(|i| i.code == code)
-                .expect(&format!("Instruction for code: {}", code));
-            row.push(instruction);
-        }
-        rows.push(row);
+fn cell(instruction: Option<OpcodeMetadata>) -> Markup {
+    match instruction {
+        Some(instruction) => html! {
+            td {
+                a href={ "opcodes/" (page_name(&instruction)) } {
+                    div class="mnemonic" { (instruction.mnemonic) }
+                    div class="timing" { (instruction.length) " " (cycles(&instruction)) }
+                    div class="flags" {
+                        @for (_, effect) in flags(&instruction).iter() {
+                            (effect.symbol())
+                        }
+                    }
+                }
+            }
+        },
+        // The unprefixed 0xCB only selects the prefixed table
+        None => html! {
+            td { a href="#cb-prefixed" { "prefix cb" } }
+        },
     }
-
-    rows
 }
 
-fn instruction_list() -> Markup {
-    html! {
-        div id="instruction-list" {
-            @for instruction in INSTRUCTIONS.iter() {
-                div class="instruction" {
-                    div { (instruction.name) }
-                    (instruction_details(instruction))
+fn detail_page(instruction: &OpcodeMetadata) -> Markup {
+    let opcode = if instruction.prefixed {
+        format!("0xCB 0x{:02X}", instruction.opcode)
+    } else {
+        format!("0x{:02X}", instruction.opcode)
+    };
+    page(
+        &instruction.mnemonic,
+        "../index.css",
+        html! {
+            div id="instructions-list" {
+                div class="instruction-entry" {
+                    div class="heading" { (instruction.mnemonic) }
+                    div class="instruction-info" {
+                        div class="quick-view" {
+                            div class="row" {
+                                div { (instruction.length) }
+                                div { (cycles(instruction)) }
+                            }
+                            div class="row" {
+                                @for (name, _) in flags(instruction).iter() {
+                                    div { (name) }
+                                }
+                            }
+                            div class="row" {
+                                @for (_, effect) in flags(instruction).iter() {
+                                    div { (effect.symbol()) }
+                                }
+                            }
+                        }
+                        div class="description" {
+                            p { "Opcode: " code { (opcode) } }
+                            p { (instruction.description) }
+                            p {
+                                (instruction.length) " bytes, " (cycles(instruction)) " cycles"
+                                @if instruction.cycles_not_taken.is_some() {
+                                    " (when the condition holds / when it does not)"
+                                }
+                            }
+                        }
+                    }
                 }
+                a href="../opcodes.html" { "Back to the opcode tables" }
             }
+        },
+    )
+}
+
+/// The opcodes keyed by their hex value (e.g. `"0x20"`) in one object per table.
+fn json_table() -> Value {
+    let mut unprefixed = Map::new();
+    let mut prefixed = Map::new();
+    for instruction in metadata_table() {
+        let key = format!("0x{:02X}", instruction.opcode);
+        let value = serde_json::to_value(&instruction).expect("Could not serialize opcode");
+        if instruction.prefixed {
+            prefixed.insert(key, value);
+        } else {
+            unprefixed.insert(key, value);
         }
     }
+    json!({ "unprefixed": unprefixed, "cbprefixed": prefixed })
 }
 
-fn instruction_details(instruction: &Instruction) -> Markup {
-    html! {
-        div class="instruction-info" {
-            div class="quick-info" {
-                div class="pc-cycles" {
-                    div { (instruction.pc) }
-                    // div { (instruction.cycles) }
-                }
-                div class="flags" {
-                    // div { (instruction.flags.zero) }
-                    // div { (instruction.flags.negative) }
-                    // div { (instruction.flags.half_carry) }
-                    // div { (instruction.flags.carry) }
-                }
-            }
-            div class="description" {
-                // (instruction.description)
-            }
-            div class="playground" { }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_cover_every_opcode() {
+        let html = tables_page().into_string();
+        assert_eq!(html.matches("<td>").count(), 512);
+        assert!(html.contains(r#"<a href="opcodes/CB7E.html">"#));
+
+        let json = json_table();
+        assert_eq!(json["unprefixed"].as_object().unwrap().len(), 255);
+        assert_eq!(json["cbprefixed"].as_object().unwrap().len(), 256);
+        assert_eq!(json["unprefixed"]["0x20"]["cycles_not_taken"], 8);
     }
 }